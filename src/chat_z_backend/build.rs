@@ -0,0 +1,43 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Embeds every file under `static/` into the canister so the frontend can be
+// served from `http_request`. Paths are relative to `static/` with a leading
+// slash, e.g. `/index.html` or `/assets/index-BMtKkftz.js`.
+fn main() {
+    let static_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("static");
+    println!("cargo:rerun-if-changed={}", static_dir.display());
+
+    let mut files = Vec::new();
+    if static_dir.is_dir() {
+        collect_files(&static_dir, &mut files);
+    }
+    files.sort();
+
+    let mut out = String::from("pub static STATIC_ASSETS: &[(&str, &[u8])] = &[\n");
+    for file in files {
+        let relative = file.strip_prefix(&static_dir).unwrap();
+        let url_path = format!("/{}", relative.to_string_lossy().replace('\\', "/"));
+        out.push_str(&format!(
+            "    ({:?}, include_bytes!({:?})),\n",
+            url_path,
+            file.display().to_string()
+        ));
+    }
+    out.push_str("];\n");
+
+    let out_path = PathBuf::from(std::env::var("OUT_DIR").unwrap()).join("static_assets.rs");
+    fs::write(out_path, out).unwrap();
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        println!("cargo:rerun-if-changed={}", path.display());
+        if path.is_dir() {
+            collect_files(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
@@ -0,0 +1,295 @@
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use candid::Principal;
+use ic_certified_map::{fork, labeled, labeled_hash, AsHashTree, Hash, HashTree};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::http;
+use crate::state::{self, AttachmentRecord, EncryptedMessage};
+
+// Certified HTTP responses (response verification v1). Every certified path
+// maps to the SHA-256 of its response body in a tree labeled "http_assets",
+// whose root hash is set as the canister's certified data. Responses carry an
+// IC-Certificate header with the subnet certificate and a witness for the path.
+
+const ASSETS_LABEL: &[u8] = b"http_assets";
+
+/// Number of most recent messages included in a public channel feed
+pub const CHANNEL_FEED_LENGTH: usize = 50;
+
+#[derive(Serialize)]
+struct ChannelFeed {
+    channel_id: u64,
+    name: String,
+    messages: Vec<FeedMessage>,
+}
+
+#[derive(Serialize)]
+struct FeedMessage {
+    id: u64,
+    author: String,
+    content: String,
+    timestamp: u64,
+    reply_to: Option<u64>,
+    attachment_ids: Vec<u64>,
+}
+
+pub fn channel_feed_path(channel_id: u64) -> String {
+    format!("/feeds/channels/{}", channel_id)
+}
+
+pub fn attachment_path(attachment_id: u64) -> String {
+    format!("/attachments/{}", attachment_id)
+}
+
+pub fn avatar_path(user: &Principal) -> String {
+    format!("/avatars/{}", user)
+}
+
+/// Certified for the `get_certified_encrypted_message` query; not served over
+/// HTTP
+pub fn encrypted_message_path(message_id: u64) -> String {
+    format!("/encrypted_messages/{}", message_id)
+}
+
+/// Bytes whose hash is certified for an encrypted message
+pub fn encode_encrypted_message(message: &EncryptedMessage) -> Vec<u8> {
+    candid::encode_one(message).expect("encrypted messages are always Candid-encodable")
+}
+
+/// JSON feed of the latest messages in a public channel. Usernames are left
+/// out so the body only changes when a message is posted.
+pub fn channel_feed(channel_id: u64) -> Option<Vec<u8>> {
+    let channel = state::with_channels(|channels| channels.get(&channel_id))?;
+    if !channel.is_public() {
+        return None;
+    }
+
+    let message_ids = state::newest_channel_messages(channel_id, CHANNEL_FEED_LENGTH);
+    let mut messages: Vec<FeedMessage> = state::with_messages(|messages| {
+        message_ids
+            .iter()
+            .filter_map(|id| messages.get(id))
+            .map(|message| FeedMessage {
+                id: message.id,
+                author: message.author.to_text(),
+                content: message.content,
+                timestamp: message.timestamp,
+                reply_to: message.reply_to,
+                attachment_ids: message.attachments.iter().map(|a| a.id).collect(),
+            })
+            .collect()
+    });
+    messages.reverse();
+
+    let feed = ChannelFeed {
+        channel_id,
+        name: channel.name,
+        messages,
+    };
+    serde_json::to_vec(&feed).ok()
+}
+
+fn insert_hash(path: &str, hash: Hash) {
+    state::with_asset_hashes_mut(|tree| tree.insert(path.as_bytes().to_vec(), hash));
+}
+
+/// Certify a path whose content lives in stable memory, storing the hash so
+/// it survives upgrades
+fn insert_stored_hash(path: &str, hash: Hash) {
+    state::set_certified_hash(path, hash);
+    insert_hash(path, hash);
+}
+
+fn remove_hash(path: &str) {
+    state::remove_certified_hash(path);
+    state::with_asset_hashes_mut(|tree| tree.delete(path.as_bytes()));
+}
+
+/// Root hash to publish as certified data
+pub fn root_hash() -> Hash {
+    state::with_asset_hashes(|tree| labeled_hash(ASSETS_LABEL, &tree.root_hash()))
+}
+
+fn publish() {
+    // Certified data only exists inside a canister; native unit tests check
+    // the tree itself
+    if cfg!(target_arch = "wasm32") {
+        ic_cdk::api::certified_data_set(root_hash());
+    }
+}
+
+/// Fill the tree from scratch. Used after init and upgrades, when the heap
+/// tree is empty.
+pub fn rebuild_tree() {
+    state::with_asset_hashes_mut(|tree| *tree = Default::default());
+
+    for (path, body) in http::STATIC_ASSETS {
+        insert_hash(path, Sha256::digest(body).into());
+    }
+    if let Some(index) = http::static_asset("/index.html") {
+        insert_hash("/", Sha256::digest(index).into());
+    }
+
+    // Everything else was hashed when it was certified
+    for (path, hash) in state::certified_hashes() {
+        insert_hash(&path, hash);
+    }
+}
+
+/// Rebuild the tree and publish its root hash
+pub fn init() {
+    rebuild_tree();
+    publish();
+}
+
+/// Re-certify a channel feed after a message is posted or the channel changes.
+/// Feeds of deleted or non-public channels are removed.
+pub fn certify_channel_feed(channel_id: u64) {
+    match channel_feed(channel_id) {
+        Some(feed) => insert_stored_hash(&channel_feed_path(channel_id), Sha256::digest(feed).into()),
+        None => remove_hash(&channel_feed_path(channel_id)),
+    }
+    publish();
+}
+
+pub fn certify_attachments(records: &[AttachmentRecord]) {
+    for record in records {
+        if let Ok(hash) = record.sha256.as_slice().try_into() {
+            insert_stored_hash(&attachment_path(record.id), hash);
+        }
+    }
+    publish();
+}
+
+pub fn uncertify_attachments(records: &[AttachmentRecord]) {
+    for record in records {
+        remove_hash(&attachment_path(record.id));
+    }
+    publish();
+}
+
+/// Certify an encrypted message after it is created or changed
+pub fn certify_encrypted_message(message: &EncryptedMessage) {
+    insert_stored_hash(&encrypted_message_path(message.id), Sha256::digest(encode_encrypted_message(message)).into());
+    publish();
+}
+
+pub fn uncertify_encrypted_message(message_id: u64) {
+    let path = encrypted_message_path(message_id);
+    if is_certified(&path) {
+        remove_hash(&path);
+        publish();
+    }
+}
+
+/// Store the hashes of encrypted messages created before they were stored
+/// at write time and certify them, a batch at a time in ID order
+pub fn certify_stored_encrypted_messages(cursor: Option<&[u8]>, budget: usize) -> Option<Vec<u8>> {
+    let next: u64 = state::resume(cursor);
+    let messages: Vec<EncryptedMessage> = state::with_encrypted_messages(|messages| {
+        messages.range(next..).take(budget).map(|(_, message)| message).collect()
+    });
+    for message in &messages {
+        insert_stored_hash(&encrypted_message_path(message.id), Sha256::digest(encode_encrypted_message(message)).into());
+    }
+    publish();
+    
+    match messages.last() {
+        Some(last) if messages.len() == budget => state::suspend(last.id + 1),
+        _ => None,
+    }
+}
+
+/// Certify the feeds of public channels created before feed hashes were
+/// stored, a batch of channels at a time in ID order
+pub fn certify_stored_channel_feeds(cursor: Option<&[u8]>, budget: usize) -> Option<Vec<u8>> {
+    let next: u64 = state::resume(cursor);
+    let channel_ids: Vec<u64> = state::with_channels(|channels| {
+        channels.range(next..).take(budget).map(|(id, _)| id).collect()
+    });
+    for channel_id in &channel_ids {
+        if let Some(feed) = channel_feed(*channel_id) {
+            insert_stored_hash(&channel_feed_path(*channel_id), Sha256::digest(feed).into());
+        }
+    }
+    publish();
+    
+    match channel_ids.last() {
+        Some(last) if channel_ids.len() == budget => state::suspend(last + 1),
+        _ => None,
+    }
+}
+
+pub fn certify_avatar(user: &Principal, data: &[u8]) {
+    insert_stored_hash(&avatar_path(user), Sha256::digest(data).into());
+    publish();
+}
+
+pub fn uncertify_avatar(user: &Principal) {
+    remove_hash(&avatar_path(user));
+    publish();
+}
+
+/// Combine two witnesses of the same tree into one revealing both
+pub fn merge_witnesses<'a>(a: HashTree<'a>, b: HashTree<'a>) -> HashTree<'a> {
+    match (a, b) {
+        (HashTree::Pruned(_), b) => b,
+        (a, HashTree::Pruned(_)) => a,
+        (HashTree::Fork(a), HashTree::Fork(b)) => {
+            let (a_left, a_right) = *a;
+            let (b_left, b_right) = *b;
+            fork(merge_witnesses(a_left, b_left), merge_witnesses(a_right, b_right))
+        }
+        (HashTree::Labeled(label, a), HashTree::Labeled(_, b)) => HashTree::Labeled(label, Box::new(merge_witnesses(*a, *b))),
+        (a, _) => a,
+    }
+}
+
+/// CBOR-encoded witness proving the hash certified for `path`. Client-side
+/// routes are answered with index.html, so their witness proves that the
+/// path itself is absent and reveals index.html instead, which verifiers of
+/// response certification v1 fall back to.
+pub fn witness(path: &str) -> Vec<u8> {
+    state::with_asset_hashes(|tree| {
+        let mut witness = tree.witness(path.as_bytes());
+        if tree.get(path.as_bytes()).is_none() && http::is_app_route(path) {
+            witness = merge_witnesses(witness, tree.witness(b"/index.html"));
+        }
+        let witness = labeled(ASSETS_LABEL, witness);
+        let mut serializer = serde_cbor::ser::Serializer::new(vec![]);
+        serializer.self_describe().unwrap();
+        witness.serialize(&mut serializer).unwrap();
+        serializer.into_inner()
+    })
+}
+
+pub fn is_certified(path: &str) -> bool {
+    state::with_asset_hashes(|tree| tree.get(path.as_bytes()).is_some())
+}
+
+/// Whether the 200 response for `path` is covered by a certified hash,
+/// either its own or that of the index.html it falls back to
+pub fn is_response_certified(path: &str) -> bool {
+    is_certified(path) || (http::is_app_route(path) && is_certified("/index.html"))
+}
+
+/// Add the IC-Certificate header to a full 200 response for a certified path.
+/// Partial and error responses are left uncertified.
+pub fn add_certificate_header(response: &mut http::HttpResponse, path: &str) {
+    if response.status_code != 200 || response.body.is_empty() || !is_response_certified(path) {
+        return;
+    }
+    let Some(certificate) = ic_cdk::api::data_certificate() else {
+        return;
+    };
+
+    response.headers.push((
+        "IC-Certificate".to_string(),
+        format!(
+            "certificate=:{}:, tree=:{}:",
+            BASE64.encode(certificate),
+            BASE64.encode(witness(path))
+        ),
+    ));
+}
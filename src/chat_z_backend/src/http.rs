@@ -0,0 +1,474 @@
+use candid::{define_function, CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::certification;
+use crate::state::{self, AttachmentReaders, AttachmentRecord};
+
+// Frontend files embedded by build.rs
+include!(concat!(env!("OUT_DIR"), "/static_assets.rs"));
+
+/// Largest body returned in a single HTTP response; bigger attachments are
+/// streamed or fetched with range requests
+pub const HTTP_CHUNK_BYTES: usize = 1024 * 1024; // 1 MiB
+
+/// How long a signed attachment URL stays valid
+pub const ATTACHMENT_URL_TTL_NS: u64 = 60 * 60 * 1_000_000_000; // 1 hour
+
+// HTTP gateway interface types
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    #[serde(with = "serde_bytes")]
+    pub body: Vec<u8>,
+    pub certificate_version: Option<u16>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    #[serde(with = "serde_bytes")]
+    pub body: Vec<u8>,
+    pub streaming_strategy: Option<StreamingStrategy>,
+}
+
+/// Identifies the next chunk of a streamed attachment, carrying the same
+/// access proof as the original request
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct StreamingCallbackToken {
+    pub attachment_id: u64,
+    pub index: u32,
+    pub expires: u64,
+    pub signature: String,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct StreamingCallbackHttpResponse {
+    #[serde(with = "serde_bytes")]
+    pub body: Vec<u8>,
+    pub token: Option<StreamingCallbackToken>,
+}
+
+define_function!(pub StreamingCallback : (StreamingCallbackToken) -> (StreamingCallbackHttpResponse) query);
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum StreamingStrategy {
+    Callback {
+        callback: StreamingCallback,
+        token: StreamingCallbackToken,
+    },
+}
+
+impl HttpResponse {
+    pub fn not_found() -> Self {
+        Self::text(404, "Not found")
+    }
+
+    pub fn text(status_code: u16, body: &str) -> Self {
+        HttpResponse {
+            status_code,
+            headers: vec![("Content-Type".to_string(), "text/plain; charset=utf-8".to_string())],
+            body: body.as_bytes().to_vec(),
+            streaming_strategy: None,
+        }
+    }
+
+    pub fn ok(content_type: &str, cache_control: &str, body: Vec<u8>) -> Self {
+        HttpResponse {
+            status_code: 200,
+            headers: vec![
+                ("Content-Type".to_string(), content_type.to_string()),
+                ("Content-Length".to_string(), body.len().to_string()),
+                ("Cache-Control".to_string(), cache_control.to_string()),
+                ("X-Content-Type-Options".to_string(), "nosniff".to_string()),
+            ],
+            body,
+            streaming_strategy: None,
+        }
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+}
+
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+/// Path of the canister-served avatar for a user. The version suffix changes
+/// whenever a new avatar is uploaded so it can be cached aggressively.
+pub fn avatar_path(user: &Principal, content_hash: &[u8]) -> String {
+    format!("/avatars/{}?v={}", user, hex::encode(&content_hash[..8]))
+}
+
+/// Split a request URL into its path and query string
+pub fn split_url(url: &str) -> (&str, &str) {
+    match url.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (url, ""),
+    }
+}
+
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+pub fn handle_request(request: HttpRequest, current_time: u64) -> HttpResponse {
+    let is_head = request.method == "HEAD";
+    if request.method != "GET" && !is_head {
+        return HttpResponse::text(405, "Method not allowed");
+    }
+
+    let (path, query) = split_url(&request.url);
+
+    let mut response = if let Some(principal) = path.strip_prefix("/avatars/") {
+        serve_avatar(principal)
+    } else if let Some(attachment_id) = path.strip_prefix("/attachments/") {
+        serve_attachment(attachment_id, query, &request.headers, current_time)
+    } else if let Some(channel_id) = path.strip_prefix("/feeds/channels/") {
+        serve_channel_feed(channel_id)
+    } else {
+        serve_static(path)
+    };
+
+    if is_head {
+        response.body.clear();
+        response.streaming_strategy = None;
+    }
+    response
+}
+
+fn serve_avatar(principal: &str) -> HttpResponse {
+    let Ok(user) = Principal::from_text(principal) else {
+        return HttpResponse::not_found();
+    };
+
+    match state::with_avatars(|avatars| avatars.get(&user)) {
+        Some(avatar) => HttpResponse::ok(&avatar.content_type, "public, max-age=86400", avatar.data),
+        None => HttpResponse::not_found(),
+    }
+}
+
+fn serve_channel_feed(channel_id: &str) -> HttpResponse {
+    match channel_id.parse::<u64>().ok().and_then(certification::channel_feed) {
+        Some(body) => HttpResponse::ok("application/json", "no-cache", body),
+        None => HttpResponse::not_found(),
+    }
+}
+
+// === Static frontend ===
+
+pub fn static_asset(path: &str) -> Option<&'static [u8]> {
+    STATIC_ASSETS
+        .iter()
+        .find(|(asset_path, _)| *asset_path == path)
+        .map(|(_, body)| *body)
+}
+
+/// Path prefixes served from canister state rather than the bundled frontend
+const DYNAMIC_PREFIXES: [&str; 3] = ["/avatars/", "/attachments/", "/feeds/channels/"];
+
+/// Client-side routes: frontend paths that are not bundled files and have no
+/// file extension. They are answered with the app shell.
+pub fn is_app_route(path: &str) -> bool {
+    !DYNAMIC_PREFIXES.iter().any(|prefix| path.starts_with(prefix))
+        && static_asset(path).is_none()
+        && !path.rsplit('/').next().unwrap_or("").contains('.')
+}
+
+fn serve_static(path: &str) -> HttpResponse {
+    let path = if path == "/" { "/index.html" } else { path };
+
+    let (path, body) = match static_asset(path) {
+        Some(body) => (path, body),
+        None if is_app_route(path) => match static_asset("/index.html") {
+            Some(body) => ("/index.html", body),
+            None => return HttpResponse::not_found(),
+        },
+        None => return HttpResponse::not_found(),
+    };
+
+    HttpResponse::ok(content_type_for_path(path), static_cache_control(path), body.to_vec())
+}
+
+/// Bundled assets have content hashes in their names and never change
+pub fn static_cache_control(path: &str) -> &'static str {
+    if path.starts_with("/assets/") {
+        "public, max-age=31536000, immutable"
+    } else {
+        "no-cache"
+    }
+}
+
+pub fn content_type_for_path(path: &str) -> &'static str {
+    match path.rsplit('.').next().unwrap_or("") {
+        "html" => "text/html; charset=utf-8",
+        "js" => "text/javascript; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "json" => "application/json",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "ico" => "image/x-icon",
+        "woff2" => "font/woff2",
+        "txt" => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+// === Attachments ===
+
+/// Attachments that anyone may read can be fetched without a token. Uses the
+/// same rule as the Candid endpoints, so HTTP is never more permissive.
+pub fn is_public_attachment(record: &AttachmentRecord) -> bool {
+    record.readers() == AttachmentReaders::Anyone
+}
+
+fn hmac_sha256(key: &[u8; 32], message: &[u8]) -> [u8; 32] {
+    let mut inner_pad = [0x36u8; 64];
+    let mut outer_pad = [0x5cu8; 64];
+    for (i, byte) in key.iter().enumerate() {
+        inner_pad[i] ^= byte;
+        outer_pad[i] ^= byte;
+    }
+
+    let inner = Sha256::new().chain_update(inner_pad).chain_update(message).finalize();
+    Sha256::new().chain_update(outer_pad).chain_update(inner).finalize().into()
+}
+
+fn attachment_signature(key: &[u8; 32], attachment_id: u64, expires: u64) -> String {
+    let message = format!("attachment:{}:{}", attachment_id, expires);
+    hex::encode(&hmac_sha256(key, message.as_bytes())[..16])
+}
+
+/// Check a signed attachment token without leaking timing information
+pub fn verify_attachment_signature(attachment_id: u64, expires: u64, signature: &str, current_time: u64) -> bool {
+    let Some(key) = state::url_signing_key() else {
+        return false;
+    };
+    if current_time > expires {
+        return false;
+    }
+
+    let expected = attachment_signature(&key, attachment_id, expires);
+    expected.len() == signature.len()
+        && expected
+            .bytes()
+            .zip(signature.bytes())
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// URL an authorized reader can use to fetch an attachment over HTTP. Returns
+/// None if a signed URL is needed but the signing key is not initialized yet.
+pub fn attachment_url(record: &AttachmentRecord, current_time: u64) -> Option<String> {
+    if is_public_attachment(record) {
+        return Some(format!("/attachments/{}", record.id));
+    }
+
+    let key = state::url_signing_key()?;
+    let expires = current_time + ATTACHMENT_URL_TTL_NS;
+    Some(format!(
+        "/attachments/{}?expires={}&token={}",
+        record.id,
+        expires,
+        attachment_signature(&key, record.id, expires)
+    ))
+}
+
+/// Parse a single `bytes=` range against a body of `total` bytes. Returns the
+/// inclusive start and end offsets, or Err for unsatisfiable ranges.
+pub fn parse_range(header: &str, total: u64) -> Result<(u64, u64), ()> {
+    let spec = header.trim().strip_prefix("bytes=").ok_or(())?;
+    if spec.contains(',') || total == 0 {
+        return Err(());
+    }
+    let (start, end) = spec.split_once('-').ok_or(())?;
+
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().map_err(|_| ())?;
+            if suffix == 0 {
+                return Err(());
+            }
+            (total.saturating_sub(suffix), total - 1)
+        }
+        (start, "") => (start.parse().map_err(|_| ())?, total - 1),
+        (start, end) => {
+            let end: u64 = end.parse().map_err(|_| ())?;
+            (start.parse().map_err(|_| ())?, end.min(total - 1))
+        }
+    };
+
+    if start > end || start >= total {
+        return Err(());
+    }
+    Ok((start, end))
+}
+
+/// Headers of an attachment response. Conditional and range requests are
+/// only advertised when `partial` responses may be served.
+fn attachment_headers(record: &AttachmentRecord, public: bool, partial: bool) -> Vec<(String, String)> {
+    let content_type = if record.file_type == "text/plain" {
+        "text/plain; charset=utf-8".to_string()
+    } else {
+        record.file_type.clone()
+    };
+    let disposition = if ["image/", "audio/", "video/"].iter().any(|prefix| record.file_type.starts_with(prefix)) {
+        "inline"
+    } else {
+        "attachment"
+    };
+    let cache_control = if public {
+        "public, max-age=31536000, immutable"
+    } else {
+        "private, max-age=3600"
+    };
+
+    let mut headers = vec![
+        ("Content-Type".to_string(), content_type),
+        (
+            "Content-Disposition".to_string(),
+            format!("{}; filename*=UTF-8''{}", disposition, percent_encode(&record.filename)),
+        ),
+        ("Cache-Control".to_string(), cache_control.to_string()),
+        ("X-Content-Type-Options".to_string(), "nosniff".to_string()),
+    ];
+    if partial {
+        headers.push(("ETag".to_string(), format!("\"{}\"", hex::encode(&record.sha256))));
+        headers.push(("Accept-Ranges".to_string(), "bytes".to_string()));
+    }
+    headers
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn load_blob(record: &AttachmentRecord) -> Option<Vec<u8>> {
+    let content_hash: [u8; 32] = record.sha256.as_slice().try_into().ok()?;
+    state::with_blobs(|blobs| blobs.get(&content_hash))
+}
+
+fn serve_attachment(attachment_id: &str, query: &str, headers: &[(String, String)], current_time: u64) -> HttpResponse {
+    let Ok(attachment_id) = attachment_id.parse::<u64>() else {
+        return HttpResponse::not_found();
+    };
+    let Some(record) = state::with_attachments(|attachments| attachments.get(&attachment_id)) else {
+        return HttpResponse::not_found();
+    };
+
+    let public = is_public_attachment(&record);
+    let expires = query_param(query, "expires").and_then(|e| e.parse::<u64>().ok()).unwrap_or(0);
+    let signature = query_param(query, "token").unwrap_or("");
+    if !public && !verify_attachment_signature(attachment_id, expires, signature, current_time) {
+        return HttpResponse::text(403, "Forbidden");
+    }
+
+    let Some(blob) = load_blob(&record) else {
+        return HttpResponse::not_found();
+    };
+
+    // Only full 200 responses can be certified, so certified attachments are
+    // always served whole and 304 and 206 are left to uncertified ones
+    let partial = !certification::is_certified(&certification::attachment_path(attachment_id));
+    let mut response_headers = attachment_headers(&record, public, partial);
+    let etag = format!("\"{}\"", hex::encode(&record.sha256));
+    if partial && find_header(headers, "If-None-Match") == Some(etag.as_str()) {
+        return HttpResponse {
+            status_code: 304,
+            headers: response_headers,
+            body: vec![],
+            streaming_strategy: None,
+        };
+    }
+
+    let total = blob.len() as u64;
+    if let Some(range) = find_header(headers, "Range").filter(|_| partial) {
+        let Ok((start, end)) = parse_range(range, total) else {
+            response_headers.push(("Content-Range".to_string(), format!("bytes */{}", total)));
+            return HttpResponse {
+                status_code: 416,
+                headers: response_headers,
+                body: vec![],
+                streaming_strategy: None,
+            };
+        };
+        let end = end.min(start + HTTP_CHUNK_BYTES as u64 - 1);
+        let body = blob[start as usize..=end as usize].to_vec();
+        response_headers.push(("Content-Range".to_string(), format!("bytes {}-{}/{}", start, end, total)));
+        response_headers.push(("Content-Length".to_string(), body.len().to_string()));
+        return HttpResponse {
+            status_code: 206,
+            headers: response_headers,
+            body,
+            streaming_strategy: None,
+        };
+    }
+
+    response_headers.push(("Content-Length".to_string(), total.to_string()));
+    let first_chunk_end = blob.len().min(HTTP_CHUNK_BYTES);
+    let streaming_strategy = (blob.len() > HTTP_CHUNK_BYTES).then(|| StreamingStrategy::Callback {
+        callback: StreamingCallback::new(ic_cdk::api::canister_self(), "http_request_streaming_callback".to_string()),
+        token: StreamingCallbackToken {
+            attachment_id,
+            index: 1,
+            expires,
+            signature: signature.to_string(),
+        },
+    });
+
+    HttpResponse {
+        status_code: 200,
+        headers: response_headers,
+        body: blob[..first_chunk_end].to_vec(),
+        streaming_strategy,
+    }
+}
+
+/// Return the next chunk of a streamed attachment
+pub fn handle_streaming_callback(token: StreamingCallbackToken, current_time: u64) -> StreamingCallbackHttpResponse {
+    let empty = StreamingCallbackHttpResponse { body: vec![], token: None };
+
+    let Some(record) = state::with_attachments(|attachments| attachments.get(&token.attachment_id)) else {
+        return empty;
+    };
+    if !is_public_attachment(&record)
+        && !verify_attachment_signature(token.attachment_id, token.expires, &token.signature, current_time)
+    {
+        return empty;
+    }
+    let Some(blob) = load_blob(&record) else {
+        return empty;
+    };
+
+    let start = token.index as usize * HTTP_CHUNK_BYTES;
+    if start >= blob.len() {
+        return empty;
+    }
+    let end = (start + HTTP_CHUNK_BYTES).min(blob.len());
+
+    StreamingCallbackHttpResponse {
+        body: blob[start..end].to_vec(),
+        token: (end < blob.len()).then(|| StreamingCallbackToken {
+            index: token.index + 1,
+            ..token
+        }),
+    }
+}
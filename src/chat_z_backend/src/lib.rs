@@ -1,27 +1,31 @@
 
+mod certification;
+mod http;
+mod media;
+mod schema;
 mod state;
+mod vetkd;
 
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests;
 
-use candid::{CandidType, Principal};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use candid::{CandidType, Principal, Reserved};
 use sha2::{Digest, Sha256};
 use ic_cdk::{init, post_upgrade};
-use ic_cdk_timers::set_timer_interval;
+use ic_cdk_timers::{set_timer, set_timer_interval};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-pub use state::{Attachment, Channel, Message, MessageType, User, EncryptedMessage, MessageIds};
+pub use http::{HttpRequest, HttpResponse, StreamingCallbackHttpResponse, StreamingCallbackToken};
+pub use state::{Attachment, AttachmentReaders, AttachmentRecord, Avatar, ImagePreview, Channel, Message, MessageExpiry, MessageType, RetentionPolicy, User, EncryptedMessage, MessageIds, PrincipalList, UpgradeRecord};
 
-// VetKeys imports
-use ic_cdk::api::{caller, time, msg_caller};
-use ic_cdk::call;
-use ic_management_canister_types::{
-    VetKDCurve, VetKDDeriveKeyArgs, VetKDDeriveKeyResult, VetKDKeyId, VetKDPublicKeyArgs,
-    VetKDPublicKeyResult,
-};
+use ic_cdk::api::{msg_caller, time};
+use ic_management_canister_types::VetKDPublicKeyArgs;
+use vetkd::{IcManagementCanister, KeyContext, ManagementCanister};
 
-#[derive(CandidType, Serialize, Deserialize, Debug)]
+#[derive(CandidType, Serialize, Deserialize, Debug, PartialEq)]
 pub enum ChatError {
     NotFound,
     NotAuthorized,
@@ -33,13 +37,124 @@ pub enum ChatError {
     InvalidPassword,
 }
 
+/// Principal allowed to call admin-only endpoints
+const ADMIN_PRINCIPAL: &str = "ouuvn-c7hpi-46km4-ywlnr-j2ten-wldfi-xu53v-vth6u-3qtqr-cmbxu-gqe";
+
+/// Maximum number of principals a user can block or mute
+const MAX_BLOCKED_USERS: usize = 500;
+
+/// Avatar upload limits
+const MAX_AVATAR_BYTES: usize = 256 * 1024; // 256 KiB
+const MAX_AVATAR_DIMENSION: u32 = 1024;
+
+/// Attachment upload limits
+const MAX_CHUNK_BYTES: usize = 1024 * 1024; // 1 MiB, well under the ingress limit
+const MAX_ATTACHMENT_BYTES: u64 = 10_000_000; // 10MB
+const MAX_MESSAGE_ATTACHMENT_BYTES: u64 = 10_000_000; // 10MB
+const MAX_ATTACHMENTS_PER_MESSAGE: usize = 10;
+const UPLOAD_SESSION_TTL_NS: u64 = 60 * 60 * 1_000_000_000; // 1 hour
+const UNATTACHED_UPLOAD_TTL_NS: u64 = 24 * 60 * 60 * 1_000_000_000; // 24 hours
+const MAX_OPEN_UPLOADS_PER_USER: usize = 8;
+const MAX_ENCRYPTED_METADATA_BYTES: usize = 1024;
+const ENCRYPTED_ATTACHMENT_TYPE: &str = "application/octet-stream";
+const MAX_THUMBNAIL_BYTES: usize = 8 * 1024; // 8 KiB
+const MAX_THUMBNAIL_DIMENSION: u32 = 128;
+
+/// Key vault limits. Wrapped keys are stored as text, typically base64.
+const MAX_VAULT_KEYS: usize = 64;
+const MAX_WRAPPED_KEY_BYTES: usize = 1024;
+
+/// Client-side ciphertext of an IBE direct message, as base64 text
+const MAX_IBE_CIPHERTEXT_BYTES: usize = 8 * 1024;
+
+/// Users seen within this window are online, within the away window are away
+const ONLINE_WINDOW_NS: u64 = 2 * 60 * 1_000_000_000; // 2 minutes
+const AWAY_WINDOW_NS: u64 = 15 * 60 * 1_000_000_000; // 15 minutes
+
+/// How long a typing indicator lasts without being refreshed
+const TYPING_TTL_NS: u64 = 6 * 1_000_000_000; // 6 seconds
+
+// Bounds for the cleanup timer interval
+const MIN_CLEANUP_INTERVAL_SECS: u64 = 60;
+const MAX_CLEANUP_INTERVAL_SECS: u64 = 24 * 60 * 60;
+const DEFAULT_VETKD_KEY_NAME: &str = "chat_z_symmetric_key";
+
+// Maximum number of expired messages deleted per cleanup run. Runs that hit
+// the budget schedule another run straight away.
+const CLEANUP_BATCH_SIZE: usize = 200;
+
+// Records decoded from each end of every stable map after an upgrade. Records
+// outside the sample are decoded when read.
+const UPGRADE_VERIFY_SAMPLE: usize = 20;
+
+// Records handled per migration batch
+const MIGRATION_BATCH_SIZE: usize = 500;
+
+// Bounds for channel retention policies
+const MAX_RETENTION_DAYS: u32 = 3650;
+const MAX_RETENTION_MESSAGES: u64 = 1_000_000;
+const DAY_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum PresenceStatus {
+    Online,
+    Away,
+    Offline,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct UserPresence {
+    pub user_principal: Principal,
+    pub status: PresenceStatus,
+    pub last_seen: u64,
+}
+
 #[derive(CandidType, Serialize, Deserialize)]
 pub struct CreateMessageRequest {
     pub content: String,
     pub channel_id: Option<u64>,
     pub reply_to: Option<u64>,
     pub message_type: MessageType,
-    pub attachments: Vec<Attachment>,
+    /// IDs of committed uploads to attach, see `commit_upload`. Optional so
+    /// that requests from clients built before uploads still decode.
+    pub attachment_ids: Option<Vec<u64>>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct StorageUsage {
+    pub used_bytes: u64,
+    pub quota_bytes: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+pub struct CanisterInfo {
+    pub upgrade: UpgradeRecord,
+    pub vetkd_key_name: String,
+    pub cleanup_interval_secs: u64,
+}
+
+/// Arguments accepted on install and on upgrade. Fields left unset keep their
+/// current (or default) values.
+#[derive(CandidType, Serialize, Deserialize, Default)]
+pub struct CanisterArgs {
+    pub vetkd_key_name: Option<String>,
+    pub cleanup_interval_secs: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+pub struct BeginUploadRequest {
+    pub filename: String,
+    pub file_type: String,
+    pub total_size: u64,
+}
+
+/// An upload of ciphertext for an encrypted message. The client encrypts the
+/// file and its metadata (filename and type) with the message's key.
+#[derive(CandidType, Serialize, Deserialize)]
+pub struct BeginEncryptedUploadRequest {
+    pub message_id: u64,
+    pub total_size: u64,
+    pub encrypted_metadata: Vec<u8>,
 }
 
 #[derive(CandidType, Serialize, Deserialize)]
@@ -74,10 +189,61 @@ pub struct PaginatedMessages {
     pub has_more: bool,
 }
 
+/// One page of encrypted messages, newest first
+#[derive(CandidType, Serialize, Deserialize, Debug)]
+pub struct EncryptedMessagePage {
+    pub messages: Vec<EncryptedMessage>,
+    /// Pass as `before` to fetch the next page. Unset on the last page.
+    pub next_before: Option<u64>,
+}
+
+/// An encrypted message in the shape the original `get_encrypted_messages`
+/// endpoints returned. Messages that never expire report `u64::MAX`.
+#[derive(CandidType, Serialize, Deserialize, Debug)]
+pub struct LegacyEncryptedMessage {
+    pub id: u64,
+    pub encrypted_content: String,
+    pub author: Principal,
+    pub timestamp: u64,
+    pub expires_at: u64,
+    pub channel_id: Option<u64>,
+    pub reply_to: Option<u64>,
+    pub message_type: MessageType,
+    pub shared_with: Vec<Principal>,
+    pub attachments: Vec<Attachment>,
+}
+
+impl From<EncryptedMessage> for LegacyEncryptedMessage {
+    fn from(message: EncryptedMessage) -> Self {
+        LegacyEncryptedMessage {
+            id: message.id,
+            encrypted_content: message.encrypted_content,
+            author: message.author,
+            timestamp: message.timestamp,
+            expires_at: message.expires_at.unwrap_or(u64::MAX),
+            channel_id: message.channel_id,
+            reply_to: message.reply_to,
+            message_type: message.message_type,
+            shared_with: message.shared_with,
+            attachments: message.attachments,
+        }
+    }
+}
+
+/// An encrypted message with proof that the canister certified it.
+/// `encoded_message` is the Candid encoding whose SHA-256 is certified at
+/// `/encrypted_messages/<id>` in the tree covered by `witness`.
+#[derive(CandidType, Serialize, Deserialize)]
+pub struct CertifiedEncryptedMessage {
+    pub encoded_message: Vec<u8>,
+    pub certificate: Vec<u8>,
+    pub witness: Vec<u8>,
+}
+
 // User management
 #[ic_cdk::update]
 pub fn register_user(username: String, bio: Option<String>) -> Result<User, ChatError> {
-let caller = caller();
+    let caller = msg_caller();
     
     if username.trim().is_empty() || username.len() > 50 {
         return Err(ChatError::InvalidInput);
@@ -85,7 +251,7 @@ let caller = caller();
     
     // Check if user already exists
     let user_exists = state::with_users(|users| {
-        users.get(&caller).is_some()
+        users.contains_key(&caller)
     });
     
     if user_exists {
@@ -123,7 +289,7 @@ let caller = caller();
 
 #[ic_cdk::update]
 pub fn update_user(request: UpdateUserRequest) -> Result<User, ChatError> {
-let caller = caller();
+    let caller = msg_caller();
     
     state::with_users_mut(|users| {
         match users.get(&caller) {
@@ -137,8 +303,16 @@ let caller = caller();
                 if let Some(bio) = request.bio {
                     user.bio = if bio.trim().is_empty() { None } else { Some(bio.trim().to_string()) };
                 }
+                // Avatars are uploaded through upload_avatar; the URL can only be
+                // cleared here, or left as the canister-local reference it already is
                 if let Some(avatar_url) = request.avatar_url {
-                    user.avatar_url = if avatar_url.trim().is_empty() { None } else { Some(avatar_url.trim().to_string()) };
+                    if avatar_url.trim().is_empty() {
+                        user.avatar_url = None;
+                        state::with_avatars_mut(|avatars| avatars.remove(&caller));
+                        certification::uncertify_avatar(&caller);
+                    } else if user.avatar_url.as_deref() != Some(avatar_url.trim()) {
+                        return Err(ChatError::InvalidInput);
+                    }
                 }
                 user.last_active = time();
                 users.insert(caller, user.clone());
@@ -149,6 +323,46 @@ let caller = caller();
     })
 }
 
+/// Upload a PNG, JPEG, GIF or WebP avatar. The image is stored in the canister
+/// and `avatar_url` is set to its canister-local path.
+#[ic_cdk::update]
+pub fn upload_avatar(data: Vec<u8>) -> Result<User, ChatError> {
+    let caller = msg_caller();
+    
+    if data.len() > MAX_AVATAR_BYTES {
+        return Err(ChatError::AttachmentTooLarge);
+    }
+    
+    let info = media::image_info(&data).ok_or(ChatError::InvalidInput)?;
+    if info.width == 0 || info.height == 0
+        || info.width > MAX_AVATAR_DIMENSION || info.height > MAX_AVATAR_DIMENSION {
+        return Err(ChatError::InvalidInput);
+    }
+    
+    let mut user = state::with_users(|users| users.get(&caller)).ok_or(ChatError::NotFound)?;
+    let current_time = time();
+    let content_hash = Sha256::digest(&data);
+    
+    certification::certify_avatar(&caller, &data);
+    state::with_avatars_mut(|avatars| {
+        avatars.insert(caller, Avatar {
+            content_type: info.mime_type.to_string(),
+            data,
+            width: info.width,
+            height: info.height,
+            uploaded_at: current_time,
+        });
+    });
+    
+    user.avatar_url = Some(http::avatar_path(&caller, &content_hash));
+    user.last_active = current_time;
+    state::with_users_mut(|users| {
+        users.insert(caller, user.clone());
+    });
+    
+    Ok(user)
+}
+
 #[ic_cdk::query]
 pub fn get_user(principal: Principal) -> Option<User> {
     state::with_users(|users| {
@@ -158,7 +372,7 @@ pub fn get_user(principal: Principal) -> Option<User> {
 
 #[ic_cdk::query]
 pub fn get_current_user() -> Option<User> {
-let caller = caller();
+    let caller = msg_caller();
     state::with_users(|users| {
         users.get(&caller)
     })
@@ -171,10 +385,277 @@ pub fn get_all_users() -> Vec<User> {
     })
 }
 
+// Key vault: wrapped keys a user stores for themselves, keyed by context
+
+/// Store a wrapped key under a context, replacing any key already stored there
+#[ic_cdk::update]
+pub fn store_encrypted_key(context: String, wrapped_key: String) -> Result<(), ChatError> {
+    put_vault_key(msg_caller(), context, wrapped_key)
+}
+
+/// Contexts the caller has stored keys under, in sorted order
+#[ic_cdk::query]
+pub fn list_encrypted_keys() -> Result<Vec<String>, ChatError> {
+    let caller = msg_caller();
+    require_registered(&caller)?;
+    Ok(state::vault_contexts(caller))
+}
+
+#[ic_cdk::query]
+pub fn get_encrypted_key(context: String) -> Result<String, ChatError> {
+    let caller = msg_caller();
+    require_registered(&caller)?;
+    state::vault_key(caller, context).ok_or(ChatError::NotFound)
+}
+
+#[ic_cdk::update]
+pub fn delete_encrypted_key(context: String) -> Result<(), ChatError> {
+    delete_vault_key(msg_caller(), context)
+}
+
+fn require_registered(caller: &Principal) -> Result<(), ChatError> {
+    if state::with_users(|users| users.contains_key(caller)) {
+        Ok(())
+    } else {
+        Err(ChatError::NotAuthorized)
+    }
+}
+
+/// Validate and store a vault entry
+fn put_vault_key(caller: Principal, context: String, wrapped_key: String) -> Result<(), ChatError> {
+    require_registered(&caller)?;
+    if context.trim().is_empty() || context.len() > state::MAX_KEY_CONTEXT_BYTES {
+        return Err(ChatError::InvalidInput);
+    }
+    if wrapped_key.is_empty() || wrapped_key.len() > MAX_WRAPPED_KEY_BYTES {
+        return Err(ChatError::InvalidInput);
+    }
+    let contexts = state::vault_contexts(caller);
+    if !contexts.contains(&context) && contexts.len() >= MAX_VAULT_KEYS {
+        return Err(ChatError::InvalidInput);
+    }
+    
+    state::insert_vault_key(caller, context, wrapped_key);
+    Ok(())
+}
+
+fn delete_vault_key(caller: Principal, context: String) -> Result<(), ChatError> {
+    require_registered(&caller)?;
+    if state::remove_vault_key(caller, context) {
+        Ok(())
+    } else {
+        Err(ChatError::NotFound)
+    }
+}
+
+// Presence and typing indicators
+
+/// Record that the caller is active. Only touches heap memory.
+#[ic_cdk::update]
+pub fn heartbeat() -> Result<(), ChatError> {
+    let caller = msg_caller();
+    
+    let user_exists = state::with_users(|users| users.contains_key(&caller));
+    if !user_exists {
+        return Err(ChatError::NotAuthorized);
+    }
+    
+    state::record_heartbeat(caller, time());
+    Ok(())
+}
+
+#[ic_cdk::query]
+pub fn get_presence(user_principals: Vec<Principal>) -> Vec<UserPresence> {
+    let current_time = time();
+    
+    user_principals
+        .into_iter()
+        .take(100)
+        .filter_map(|principal| {
+            let last_active = state::with_users(|users| users.get(&principal).map(|u| u.last_active))?;
+            let last_seen = state::last_seen(&principal).unwrap_or(0).max(last_active);
+            Some(UserPresence {
+                user_principal: principal,
+                status: presence_status(last_seen, current_time),
+                last_seen,
+            })
+        })
+        .collect()
+}
+
+/// Derive a presence status from the last time a user was seen
+pub fn presence_status(last_seen: u64, current_time: u64) -> PresenceStatus {
+    let idle = current_time.saturating_sub(last_seen);
+    if idle <= ONLINE_WINDOW_NS {
+        PresenceStatus::Online
+    } else if idle <= AWAY_WINDOW_NS {
+        PresenceStatus::Away
+    } else {
+        PresenceStatus::Offline
+    }
+}
+
+/// Mark the caller as typing (or no longer typing) in a channel
+#[ic_cdk::update]
+pub fn set_typing(channel_id: u64, is_typing: bool) -> Result<(), ChatError> {
+    let caller = msg_caller();
+    let current_time = time();
+    
+    if !is_channel_member(channel_id, &caller)? {
+        return Err(ChatError::NotAuthorized);
+    }
+    
+    let expires_at = is_typing.then_some(current_time + TYPING_TTL_NS);
+    state::set_typing(channel_id, caller, expires_at, current_time);
+    state::record_heartbeat(caller, current_time);
+    Ok(())
+}
+
+/// Users currently typing in a channel, visible to channel members only.
+/// Users the caller has blocked are left out.
+#[ic_cdk::query]
+pub fn get_typing_users(channel_id: u64) -> Result<Vec<Principal>, ChatError> {
+    typing_users_for(&msg_caller(), channel_id, time())
+}
+
+fn typing_users_for(caller: &Principal, channel_id: u64, current_time: u64) -> Result<Vec<Principal>, ChatError> {
+    if !is_channel_member(channel_id, caller)? {
+        return Err(ChatError::NotAuthorized);
+    }
+    
+    let blocked = state::with_blocked_users(|blocked| blocked.get(caller).unwrap_or_default());
+    Ok(state::typing_users(channel_id, current_time)
+        .into_iter()
+        .filter(|user| user != caller && !blocked.contains(user))
+        .collect())
+}
+
+fn is_channel_member(channel_id: u64, user: &Principal) -> Result<bool, ChatError> {
+    state::with_channels(|channels| {
+        channels.get(&channel_id)
+            .map(|channel| channel.members.contains(user))
+            .ok_or(ChatError::ChannelNotFound)
+    })
+}
+
+// Block and mute management
+
+/// Block a user: their messages and typing indicators are hidden from the
+/// caller, and they can no longer send or share encrypted messages to the
+/// caller. Encrypted messages they shared before the block are hidden too.
+#[ic_cdk::update]
+pub fn block_user(user_principal: Principal) -> Result<(), ChatError> {
+    block(msg_caller(), user_principal)
+}
+
+fn block(caller: Principal, user_principal: Principal) -> Result<(), ChatError> {
+    validate_block_target(&caller, &user_principal)?;
+    state::with_blocked_users_mut(|blocked| add_to_list(blocked, caller, user_principal))
+}
+
+#[ic_cdk::update]
+pub fn unblock_user(user_principal: Principal) -> Result<(), ChatError> {
+    unblock(msg_caller(), &user_principal);
+    Ok(())
+}
+
+fn unblock(caller: Principal, user_principal: &Principal) {
+    state::with_blocked_users_mut(|blocked| remove_from_list(blocked, caller, user_principal));
+}
+
+#[ic_cdk::query]
+pub fn get_blocked_users() -> Vec<Principal> {
+    let caller = msg_caller();
+    state::with_blocked_users(|blocked| {
+        blocked.get(&caller).unwrap_or_default().principals
+    })
+}
+
+/// Mute a user. Muted users can still reach the caller and show up as
+/// typing; clients read the mute list to suppress notifications and
+/// mentions from them
+#[ic_cdk::update]
+pub fn mute_user(user_principal: Principal) -> Result<(), ChatError> {
+    mute(msg_caller(), user_principal)
+}
+
+fn mute(caller: Principal, user_principal: Principal) -> Result<(), ChatError> {
+    validate_block_target(&caller, &user_principal)?;
+    state::with_muted_users_mut(|muted| add_to_list(muted, caller, user_principal))
+}
+
+#[ic_cdk::update]
+pub fn unmute_user(user_principal: Principal) -> Result<(), ChatError> {
+    unmute(msg_caller(), &user_principal);
+    Ok(())
+}
+
+fn unmute(caller: Principal, user_principal: &Principal) {
+    state::with_muted_users_mut(|muted| remove_from_list(muted, caller, user_principal));
+}
+
+#[ic_cdk::query]
+pub fn get_muted_users() -> Vec<Principal> {
+    let caller = msg_caller();
+    state::with_muted_users(|muted| {
+        muted.get(&caller).unwrap_or_default().principals
+    })
+}
+
+fn add_to_list(
+    lists: &mut state::Records<Principal, PrincipalList>,
+    owner: Principal,
+    user: Principal,
+) -> Result<(), ChatError> {
+    let mut list = lists.get(&owner).unwrap_or_default();
+    if list.contains(&user) {
+        return Ok(());
+    }
+    if list.principals.len() >= MAX_BLOCKED_USERS {
+        return Err(ChatError::InvalidInput);
+    }
+    list.principals.push(user);
+    lists.insert(owner, list);
+    Ok(())
+}
+
+fn remove_from_list(
+    lists: &mut state::Records<Principal, PrincipalList>,
+    owner: Principal,
+    user: &Principal,
+) {
+    if let Some(mut list) = lists.get(&owner) {
+        list.principals.retain(|p| p != user);
+        if list.principals.is_empty() {
+            lists.remove(&owner);
+        } else {
+            lists.insert(owner, list);
+        }
+    }
+}
+
+fn validate_block_target(caller: &Principal, target: &Principal) -> Result<(), ChatError> {
+    if caller == target {
+        return Err(ChatError::InvalidInput);
+    }
+    
+    let caller_exists = state::with_users(|users| users.contains_key(caller));
+    if !caller_exists {
+        return Err(ChatError::NotAuthorized);
+    }
+    
+    let target_exists = state::with_users(|users| users.contains_key(target));
+    if !target_exists {
+        return Err(ChatError::NotFound);
+    }
+    
+    Ok(())
+}
+
 // Channel management
 #[ic_cdk::update]
 pub fn create_channel(request: CreateChannelRequest) -> Result<Channel, ChatError> {
-let caller = caller();
+    let caller = msg_caller();
     
     if request.name.trim().is_empty() || request.name.len() > 100 {
         return Err(ChatError::InvalidInput);
@@ -182,7 +663,7 @@ let caller = caller();
     
     // Ensure user is registered
     let user_exists = state::with_users(|users| {
-        users.get(&caller).is_some()
+        users.contains_key(&caller)
     });
     
     if !user_exists {
@@ -203,11 +684,15 @@ let caller = caller();
         last_message_at: None,
         is_encrypted: false, // Default channels are not encrypted
         password_hash: None, // Regular channels have no password
+        message_expiry: None,
+        retention: None,
+        key_epoch: None,
     };
     
     state::with_channels_mut(|channels| {
         channels.insert(channel_id, channel.clone());
     });
+    certification::certify_channel_feed(channel_id);
     
     Ok(channel)
 }
@@ -228,11 +713,11 @@ pub fn get_all_channels() -> Vec<Channel> {
 
 #[ic_cdk::update]
 pub fn join_channel(channel_id: u64, password: Option<String>) -> Result<(), ChatError> {
-    let caller = caller();
+    let caller = msg_caller();
     
     // Ensure user is registered
     let user_exists = state::with_users(|users| {
-        users.get(&caller).is_some()
+        users.contains_key(&caller)
     });
     
     if !user_exists {
@@ -272,27 +757,23 @@ pub fn join_channel(channel_id: u64, password: Option<String>) -> Result<(), Cha
 // Message management
 #[ic_cdk::update]
 pub fn send_message(request: CreateMessageRequest) -> Result<Message, ChatError> {
-let caller = caller();
+    let caller = msg_caller();
     
     if request.content.trim().is_empty() || request.content.len() > 2000 {
         return Err(ChatError::InvalidInput);
     }
     
-    // Check total attachment size
-    let total_attachment_size: u64 = request.attachments.iter().map(|a| a.size).sum();
-    if total_attachment_size > 10_000_000 { // 10MB limit
-        return Err(ChatError::AttachmentTooLarge);
-    }
-    
     // Ensure user is registered
     let user_exists = state::with_users(|users| {
-        users.get(&caller).is_some()
+        users.contains_key(&caller)
     });
     
     if !user_exists {
         return Err(ChatError::NotAuthorized);
     }
     
+    let attachment_ids = request.attachment_ids.unwrap_or_default();
+    
     // If channel_id is provided, ensure user is member of channel
     if let Some(channel_id) = request.channel_id {
         let is_member = state::with_channels(|channels| {
@@ -304,10 +785,21 @@ let caller = caller();
         if !is_member {
             return Err(ChatError::NotAuthorized);
         }
+        
+        // Files in encrypted channels must be uploaded encrypted
+        let is_encrypted_channel = state::with_channels(|channels| {
+            channels.get(&channel_id).map(|channel| channel.is_encrypted).unwrap_or(false)
+        });
+        if is_encrypted_channel && !attachment_ids.is_empty() {
+            return Err(ChatError::InvalidInput);
+        }
     }
     
+    let attachments = resolve_attachments(&caller, &attachment_ids)?;
+    
     let message_id = state::next_message_id();
     let current_time = time();
+    bind_attachments(&attachments, message_id, request.channel_id);
     
     let message = Message {
         id: message_id,
@@ -317,13 +809,16 @@ let caller = caller();
         channel_id: request.channel_id,  // Store the channel_id with the message
         reply_to: request.reply_to,
         message_type: request.message_type,
-        attachments: request.attachments,
+        attachments: attachments.iter().map(AttachmentRecord::metadata).collect(),
     };
     
     // Store the message
     state::with_messages_mut(|messages| {
         messages.insert(message_id, message.clone());
     });
+    if let Some(channel_id) = request.channel_id {
+        state::index_channel_message(channel_id, message_id);
+    }
     
     // Update user message count and last active
     state::with_users_mut(|users| {
@@ -343,6 +838,7 @@ let caller = caller();
                 channels.insert(channel_id, channel);
             }
         });
+        certification::certify_channel_feed(channel_id);
     }
     
     Ok(message)
@@ -354,12 +850,17 @@ pub fn get_messages(
     limit: Option<u64>,
     offset: Option<u64>,
 ) -> PaginatedMessages {
+    let caller = msg_caller();
     let limit = limit.unwrap_or(50).min(100);
     let offset = offset.unwrap_or(0);
     
+    // Messages from users the caller has blocked are hidden
+    let blocked = state::with_blocked_users(|blocked| blocked.get(&caller).unwrap_or_default());
+    
     let all_messages: Vec<Message> = state::with_messages(|messages| {
         messages.iter()
             .map(|(_, message)| message)
+            .filter(|message| !blocked.contains(&message.author))
             .filter(|message| {
                 if let Some(cid) = channel_id {
                     // Filter messages by their actual channel_id
@@ -374,7 +875,7 @@ pub fn get_messages(
     
     // Sort by timestamp descending (newest first)
     let mut sorted_messages = all_messages;
-    sorted_messages.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+    sorted_messages.sort_by_key(|m| std::cmp::Reverse(m.timestamp));
     
     let total_count = sorted_messages.len() as u64;
     let has_more = (offset + limit) < total_count;
@@ -440,28 +941,638 @@ pub fn get_message(message_id: u64) -> Option<MessageWithAuthor> {
     })
 }
 
-// Stats and utility functions
-#[ic_cdk::query]
-pub fn get_stats() -> Vec<(String, u64)> {
-    let user_count = state::with_users(|users| users.len());
-    let message_count = state::with_messages(|messages| messages.len());
-    let channel_count = state::with_channels(|channels| channels.len());
+// Attachment uploads
+
+/// Start a chunked upload. Returns the upload ID to pass to `put_chunk`.
+#[ic_cdk::update]
+pub fn begin_upload(request: BeginUploadRequest) -> Result<u64, ChatError> {
+    start_upload(&msg_caller(), request, time())
+}
+
+fn start_upload(caller: &Principal, request: BeginUploadRequest, current_time: u64) -> Result<u64, ChatError> {
+    let caller = *caller;
+    let user_exists = state::with_users(|users| users.contains_key(&caller));
+    if !user_exists {
+        return Err(ChatError::NotAuthorized);
+    }
     
-    vec![
-        ("users".to_string(), user_count),
-        ("messages".to_string(), message_count),
-        ("channels".to_string(), channel_count),
-    ]
+    let filename = media::sanitize_filename(&request.filename).ok_or(ChatError::InvalidInput)?;
+    if request.total_size == 0 {
+        return Err(ChatError::InvalidInput);
+    }
+    
+    if request.total_size > MAX_ATTACHMENT_BYTES {
+        return Err(ChatError::AttachmentTooLarge);
+    }
+    
+    check_upload_capacity(&caller, request.total_size)?;
+    
+    // The declared type is only a hint; the stored type is sniffed on commit
+    if !media::ALLOWED_ATTACHMENT_TYPES.contains(&request.file_type.trim()) {
+        return Err(ChatError::InvalidInput);
+    }
+    
+    let upload_id = state::next_upload_id();
+    let session = state::UploadSession {
+        id: upload_id,
+        owner: caller,
+        file_type: request.file_type.trim().to_string(),
+        filename,
+        total_size: request.total_size,
+        received_size: 0,
+        created_at: current_time,
+        encrypted_message_id: None,
+        encrypted_metadata: None,
+    };
+    
+    state::insert_upload_session(session);
+    
+    Ok(upload_id)
 }
 
-// Initialize the canister with VetKeys support
+/// Start a chunked upload of an attachment for one of the caller's encrypted
+/// messages. The bytes are stored as-is and attached to the message on commit.
+#[ic_cdk::update]
+pub fn begin_encrypted_upload(request: BeginEncryptedUploadRequest) -> Result<u64, ChatError> {
+    let caller = msg_caller();
+    
+    if request.total_size == 0 || request.encrypted_metadata.is_empty() {
+        return Err(ChatError::InvalidInput);
+    }
+    
+    if request.encrypted_metadata.len() > MAX_ENCRYPTED_METADATA_BYTES {
+        return Err(ChatError::InvalidInput);
+    }
+    
+    check_encrypted_attachment(&caller, request.message_id, request.total_size, time())?;
+    check_upload_capacity(&caller, request.total_size)?;
+    
+    let upload_id = state::next_upload_id();
+    let session = state::UploadSession {
+        id: upload_id,
+        owner: caller,
+        file_type: ENCRYPTED_ATTACHMENT_TYPE.to_string(),
+        filename: String::new(),
+        total_size: request.total_size,
+        received_size: 0,
+        created_at: time(),
+        encrypted_message_id: Some(request.message_id),
+        encrypted_metadata: Some(request.encrypted_metadata),
+    };
+    
+    state::insert_upload_session(session);
+    
+    Ok(upload_id)
+}
+
+/// Store one chunk of an upload. Chunks may arrive in any order and can be
+/// re-sent; a re-sent chunk replaces the previous one.
+#[ic_cdk::update]
+pub fn put_chunk(upload_id: u64, index: u32, data: Vec<u8>) -> Result<(), ChatError> {
+    store_chunk(&msg_caller(), upload_id, index, data)
+}
+
+fn store_chunk(caller: &Principal, upload_id: u64, index: u32, data: Vec<u8>) -> Result<(), ChatError> {
+    if data.is_empty() || data.len() > MAX_CHUNK_BYTES {
+        return Err(ChatError::InvalidInput);
+    }
+    
+    let mut session = state::with_upload_sessions(|sessions| sessions.get(&upload_id))
+        .ok_or(ChatError::NotFound)?;
+    if &session.owner != caller {
+        return Err(ChatError::NotAuthorized);
+    }
+    
+    let previous_size = state::with_upload_chunks(|chunks| {
+        chunks.get(&(upload_id, index)).map(|chunk| chunk.len() as u64).unwrap_or(0)
+    });
+    let received_size = session.received_size - previous_size + data.len() as u64;
+    if received_size > session.total_size {
+        return Err(ChatError::AttachmentTooLarge);
+    }
+    
+    state::with_upload_chunks_mut(|chunks| {
+        chunks.insert((upload_id, index), data);
+    });
+    
+    session.received_size = received_size;
+    state::with_upload_sessions_mut(|sessions| {
+        sessions.insert(upload_id, session);
+    });
+    
+    Ok(())
+}
+
+/// Finish an upload. The assembled bytes must match `sha256` and sniff as an
+/// allowed type; they are moved into the blob store and an attachment is
+/// created that can be passed to `send_message`. Encrypted uploads are not
+/// sniffed and are attached to their message straight away.
+#[ic_cdk::update]
+pub fn commit_upload(upload_id: u64, sha256: Vec<u8>) -> Result<Attachment, ChatError> {
+    finish_upload(&msg_caller(), upload_id, sha256, time())
+}
+
+fn finish_upload(caller: &Principal, upload_id: u64, sha256: Vec<u8>, current_time: u64) -> Result<Attachment, ChatError> {
+    let caller = *caller;
+    let session = state::with_upload_sessions(|sessions| sessions.get(&upload_id))
+        .ok_or(ChatError::NotFound)?;
+    if session.owner != caller {
+        return Err(ChatError::NotAuthorized);
+    }
+    
+    if session.received_size != session.total_size {
+        return Err(ChatError::InvalidInput);
+    }
+    
+    let data = state::assemble_upload(upload_id).ok_or(ChatError::InvalidInput)?;
+    let content_hash: [u8; 32] = Sha256::digest(&data).into();
+    if data.len() as u64 != session.total_size || sha256 != content_hash {
+        return Err(ChatError::InvalidInput);
+    }
+    
+    let (file_type, channel_id, preview) = match session.encrypted_message_id {
+        Some(message_id) => {
+            // The message may have been deleted or filled up since the upload began
+            let channel_id = check_encrypted_attachment(&caller, message_id, session.total_size, current_time)?;
+            (ENCRYPTED_ATTACHMENT_TYPE, channel_id, None)
+        }
+        None => {
+            let file_type = media::sniff_mime_type(&data)
+                .filter(|mime_type| media::ALLOWED_ATTACHMENT_TYPES.contains(mime_type))
+                .ok_or(ChatError::InvalidInput)?;
+            (file_type, None, image_preview(&data))
+        }
+    };
+    
+    // The session's reservation held room for it in the quota since it began
+    let attachment_id = state::next_attachment_id();
+    state::store_blob(content_hash, data);
+    
+    let record = AttachmentRecord {
+        id: attachment_id,
+        owner: caller,
+        file_type: file_type.to_string(),
+        filename: session.filename,
+        size: session.total_size,
+        sha256: content_hash.to_vec(),
+        created_at: current_time,
+        message_id: session.encrypted_message_id,
+        channel_id,
+        encrypted: session.encrypted_message_id.is_some(),
+        encrypted_metadata: session.encrypted_metadata,
+        preview,
+    };
+    
+    state::insert_attachment(record.clone());
+    state::add_storage_usage(caller, record.size);
+    state::remove_upload(upload_id);
+    
+    if let Some(message_id) = record.message_id {
+        state::with_encrypted_messages_mut(|messages| {
+            if let Some(mut message) = messages.get(&message_id) {
+                message.attachments.push(record.metadata());
+                certification::certify_encrypted_message(&message);
+                messages.insert(message_id, message);
+            }
+        });
+        certification::certify_attachments(std::slice::from_ref(&record));
+    }
+    
+    Ok(record.metadata())
+}
+
+/// Reuse an attachment the caller can read in a new message without uploading
+/// it again. The copy shares the stored bytes but is charged to the caller.
+#[ic_cdk::update]
+pub fn forward_attachment(attachment_id: u64) -> Result<Attachment, ChatError> {
+    let caller = msg_caller();
+    let current_time = time();
+    
+    let user_exists = state::with_users(|users| users.contains_key(&caller));
+    if !user_exists {
+        return Err(ChatError::NotAuthorized);
+    }
+    
+    let source = state::with_attachments(|attachments| attachments.get(&attachment_id))
+        .ok_or(ChatError::NotFound)?;
+    if !can_read_attachment(&caller, &source, current_time) {
+        return Err(ChatError::NotAuthorized);
+    }
+    
+    // Ciphertext is bound to the key of the message it was uploaded for
+    if source.encrypted {
+        return Err(ChatError::InvalidInput);
+    }
+    
+    let content_hash: [u8; 32] = source.sha256.as_slice().try_into().map_err(|_| ChatError::NotFound)?;
+    
+    if !has_storage_for(&caller, source.size) {
+        return Err(ChatError::AttachmentTooLarge);
+    }
+    
+    let record = AttachmentRecord {
+        id: state::next_attachment_id(),
+        owner: caller,
+        created_at: current_time,
+        message_id: None,
+        channel_id: None,
+        ..source
+    };
+    
+    state::retain_blob(content_hash);
+    state::insert_attachment(record.clone());
+    state::add_storage_usage(caller, record.size);
+    
+    Ok(record.metadata())
+}
+
+/// Attach a client-generated thumbnail and BlurHash to a committed image
+/// upload. Must be called before the attachment is sent with a message.
+#[ic_cdk::update]
+pub fn set_attachment_preview(attachment_id: u64, thumbnail: Option<Vec<u8>>, blurhash: Option<String>) -> Result<Attachment, ChatError> {
+    let caller = msg_caller();
+    
+    let mut record = state::with_attachments(|attachments| attachments.get(&attachment_id))
+        .ok_or(ChatError::NotFound)?;
+    if record.owner != caller || record.message_id.is_some() {
+        return Err(ChatError::NotAuthorized);
+    }
+    
+    let preview = record.preview.as_mut().ok_or(ChatError::InvalidInput)?;
+    
+    if let Some(thumbnail) = &thumbnail {
+        if !is_valid_thumbnail(thumbnail) {
+            return Err(ChatError::InvalidInput);
+        }
+    }
+    
+    if let Some(blurhash) = &blurhash {
+        if !media::is_valid_blurhash(blurhash) {
+            return Err(ChatError::InvalidInput);
+        }
+    }
+    
+    preview.thumbnail = thumbnail;
+    preview.blurhash = blurhash;
+    
+    state::insert_attachment(record.clone());
+    
+    Ok(record.metadata())
+}
+
+/// Abandon an upload, dropping its chunks and the quota it reserved
+#[ic_cdk::update]
+pub fn cancel_upload(upload_id: u64) -> Result<(), ChatError> {
+    abort_upload(&msg_caller(), upload_id)
+}
+
+fn abort_upload(caller: &Principal, upload_id: u64) -> Result<(), ChatError> {
+    let session = state::with_upload_sessions(|sessions| sessions.get(&upload_id))
+        .ok_or(ChatError::NotFound)?;
+    if &session.owner != caller {
+        return Err(ChatError::NotAuthorized);
+    }
+    
+    state::remove_upload(upload_id);
+    Ok(())
+}
+
+/// Attachment metadata, if the caller can read the message it belongs to
+#[ic_cdk::query]
+pub fn get_attachment(attachment_id: u64) -> Option<Attachment> {
+    let caller = msg_caller();
+    state::with_attachments(|attachments| attachments.get(&attachment_id))
+        .filter(|record| can_read_attachment(&caller, record, time()))
+        .map(|record| record.metadata())
+}
+
+/// Download an attachment in chunks of at most 1 MiB
+#[ic_cdk::query]
+pub fn get_attachment_chunk(attachment_id: u64, index: u32) -> Result<Vec<u8>, ChatError> {
+    let caller = msg_caller();
+    
+    let record = state::with_attachments(|attachments| attachments.get(&attachment_id))
+        .ok_or(ChatError::NotFound)?;
+    if !can_read_attachment(&caller, &record, time()) {
+        return Err(ChatError::NotAuthorized);
+    }
+    
+    let start = index as usize * MAX_CHUNK_BYTES;
+    if start as u64 >= record.size {
+        return Err(ChatError::InvalidInput);
+    }
+    
+    let content_hash: [u8; 32] = record.sha256.as_slice().try_into().map_err(|_| ChatError::NotFound)?;
+    state::with_blobs(|blobs| {
+        let blob = blobs.get(&content_hash).ok_or(ChatError::NotFound)?;
+        let end = (start + MAX_CHUNK_BYTES).min(blob.len());
+        Ok(blob[start..end].to_vec())
+    })
+}
+
+/// Dimensions of an uploaded image. Images that already fit the thumbnail
+/// limits are their own thumbnail.
+fn image_preview(data: &[u8]) -> Option<ImagePreview> {
+    let info = media::image_info(data)?;
+    Some(ImagePreview {
+        width: info.width,
+        height: info.height,
+        blurhash: None,
+        thumbnail: is_valid_thumbnail(data).then(|| data.to_vec()),
+    })
+}
+
+fn is_valid_thumbnail(data: &[u8]) -> bool {
+    data.len() <= MAX_THUMBNAIL_BYTES
+        && media::image_info(data).is_some_and(|info| {
+            info.width > 0 && info.height > 0
+                && info.width <= MAX_THUMBNAIL_DIMENSION && info.height <= MAX_THUMBNAIL_DIMENSION
+        })
+}
+
+/// Look up the caller's committed uploads that are not attached to a message yet
+fn resolve_attachments(caller: &Principal, attachment_ids: &[u64]) -> Result<Vec<AttachmentRecord>, ChatError> {
+    if attachment_ids.len() > MAX_ATTACHMENTS_PER_MESSAGE {
+        return Err(ChatError::InvalidInput);
+    }
+    
+    let mut records: Vec<AttachmentRecord> = Vec::with_capacity(attachment_ids.len());
+    
+    for attachment_id in attachment_ids {
+        let record = state::with_attachments(|attachments| attachments.get(attachment_id))
+            .ok_or(ChatError::NotFound)?;
+        if &record.owner != caller || record.message_id.is_some() {
+            return Err(ChatError::NotAuthorized);
+        }
+        if records.iter().any(|r| r.id == record.id) {
+            return Err(ChatError::InvalidInput);
+        }
+        records.push(record);
+    }
+    
+    // Sizes come from the committed bytes, never from the client
+    let total_size: u64 = records.iter().map(|r| r.size).sum();
+    if records.iter().any(|r| r.size > MAX_ATTACHMENT_BYTES) || total_size > MAX_MESSAGE_ATTACHMENT_BYTES {
+        return Err(ChatError::AttachmentTooLarge);
+    }
+    
+    Ok(records)
+}
+
+/// Bind attachments to the message they were sent with
+fn bind_attachments(records: &[AttachmentRecord], message_id: u64, channel_id: Option<u64>) {
+    for record in records {
+        state::insert_attachment(AttachmentRecord {
+            message_id: Some(message_id),
+            channel_id,
+            ..record.clone()
+        });
+    }
+    certification::certify_attachments(records);
+}
+
+/// Check that the caller can add an attachment of `size` bytes to one of their
+/// encrypted messages. Returns the message's channel.
+fn check_encrypted_attachment(caller: &Principal, message_id: u64, size: u64, current_time: u64) -> Result<Option<u64>, ChatError> {
+    let message = state::with_encrypted_messages(|messages| messages.get(&message_id))
+        .ok_or(ChatError::NotFound)?;
+    if &message.author != caller || message.is_expired(current_time) {
+        return Err(ChatError::NotAuthorized);
+    }
+    
+    if message.attachments.len() >= MAX_ATTACHMENTS_PER_MESSAGE {
+        return Err(ChatError::InvalidInput);
+    }
+    
+    let total_size: u64 = message.attachments.iter().map(|a| a.size).sum::<u64>() + size;
+    if size > MAX_ATTACHMENT_BYTES || total_size > MAX_MESSAGE_ATTACHMENT_BYTES {
+        return Err(ChatError::AttachmentTooLarge);
+    }
+    
+    Ok(message.channel_id)
+}
+
+/// Attachments are readable by their uploader and by anyone who can read the
+/// message they are attached to
+fn can_read_attachment(user: &Principal, record: &AttachmentRecord, current_time: u64) -> bool {
+    if &record.owner == user {
+        return true;
+    }
+    
+    match record.readers() {
+        AttachmentReaders::UploaderOnly => false,
+        AttachmentReaders::Anyone => true,
+        AttachmentReaders::ChannelMembers(channel_id) => state::with_channels(|channels| {
+            channels.get(&channel_id)
+                .map(|channel| channel.members.contains(user))
+                .unwrap_or(false)
+        }),
+        AttachmentReaders::EncryptedMessage(message_id) => {
+            can_read_encrypted_message(user, message_id, current_time)
+        }
+    }
+}
+
+/// Remove up to `budget` upload sessions that were never committed and
+/// committed uploads that were never attached to a message, oldest first.
+/// Returns the number removed and whether none are left.
+fn cleanup_stale_uploads(current_time: u64, budget: usize) -> (u64, bool) {
+    let stale_uploads = state::upload_sessions_created_before(
+        current_time.saturating_sub(UPLOAD_SESSION_TTL_NS),
+        budget,
+    );
+    let stale_attachments = state::unattached_attachments_created_before(
+        current_time.saturating_sub(UNATTACHED_UPLOAD_TTL_NS),
+        budget - stale_uploads.len(),
+    );
+    
+    let cleaned = stale_uploads.len() + stale_attachments.len();
+    for upload_id in stale_uploads {
+        state::remove_upload(upload_id);
+    }
+    remove_attachments(&stale_attachments);
+    
+    (cleaned as u64, cleaned < budget)
+}
+
+/// Delete attachment records and release the storage charged for them
+fn remove_attachments(attachment_ids: &[u64]) {
+    let removed: Vec<AttachmentRecord> = attachment_ids.iter()
+        .filter_map(|id| state::remove_attachment(*id))
+        .collect();
+    
+    for record in &removed {
+        state::release_storage_usage(record.owner, record.size);
+        if let Ok(content_hash) = record.sha256.as_slice().try_into() {
+            state::release_blob(content_hash);
+        }
+    }
+    if removed.iter().any(|record| record.message_id.is_some()) {
+        certification::uncertify_attachments(&removed);
+    }
+}
+
+/// Uploads that don't fit the remaining quota fail with `AttachmentTooLarge`.
+/// Bytes reserved by open upload sessions count as used.
+fn has_storage_for(user: &Principal, bytes: u64) -> bool {
+    let (_, reserved) = state::open_uploads(user);
+    state::storage_usage(user)
+        .saturating_add(reserved)
+        .saturating_add(bytes) <= state::storage_quota(user)
+}
+
+/// Check that a user can open another upload session of `size` bytes
+fn check_upload_capacity(user: &Principal, size: u64) -> Result<(), ChatError> {
+    let (open, _) = state::open_uploads(user);
+    if open >= MAX_OPEN_UPLOADS_PER_USER {
+        return Err(ChatError::InvalidInput);
+    }
+    if !has_storage_for(user, size) {
+        return Err(ChatError::AttachmentTooLarge);
+    }
+    Ok(())
+}
+
+// Storage quotas
+
+/// Attachment storage used by the caller, or by another user for admins
+#[ic_cdk::query]
+pub fn get_storage_usage(user: Option<Principal>) -> Result<StorageUsage, ChatError> {
+    storage_usage_of(&msg_caller(), user)
+}
+
+fn storage_usage_of(caller: &Principal, user: Option<Principal>) -> Result<StorageUsage, ChatError> {
+    let user = user.unwrap_or(*caller);
+    
+    if &user != caller && !is_admin(caller) {
+        return Err(ChatError::NotAuthorized);
+    }
+    
+    Ok(StorageUsage {
+        used_bytes: state::storage_usage(&user),
+        quota_bytes: state::storage_quota(&user),
+    })
+}
+
+#[ic_cdk::update]
+pub fn set_default_storage_quota(bytes: u64) -> Result<(), ChatError> {
+    set_default_quota(&msg_caller(), bytes)
+}
+
+fn set_default_quota(caller: &Principal, bytes: u64) -> Result<(), ChatError> {
+    if !is_admin(caller) {
+        return Err(ChatError::NotAuthorized);
+    }
+    
+    state::set_default_storage_quota(bytes);
+    Ok(())
+}
+
+/// Override the quota for one user, or pass None to return them to the default
+#[ic_cdk::update]
+pub fn set_user_storage_quota(user: Principal, bytes: Option<u64>) -> Result<(), ChatError> {
+    set_user_quota(&msg_caller(), user, bytes)
+}
+
+fn set_user_quota(caller: &Principal, user: Principal, bytes: Option<u64>) -> Result<(), ChatError> {
+    if !is_admin(caller) {
+        return Err(ChatError::NotAuthorized);
+    }
+    
+    state::set_storage_quota_override(user, bytes);
+    Ok(())
+}
+
+fn is_admin(principal: &Principal) -> bool {
+    Principal::from_text(ADMIN_PRINCIPAL).map(|admin| &admin == principal).unwrap_or(false)
+}
+
+/// URL for fetching an attachment over the canister's HTTP interface. Links to
+/// attachments outside public channels are signed and expire after an hour.
+#[ic_cdk::query]
+pub fn get_attachment_url(attachment_id: u64) -> Result<String, ChatError> {
+    let caller = msg_caller();
+    let current_time = time();
+    
+    let record = state::with_attachments(|attachments| attachments.get(&attachment_id))
+        .ok_or(ChatError::NotFound)?;
+    if !can_read_attachment(&caller, &record, current_time) {
+        return Err(ChatError::NotAuthorized);
+    }
+    
+    http::attachment_url(&record, current_time).ok_or(ChatError::NotFound)
+}
+
+// HTTP interface
+#[ic_cdk::query]
+pub fn http_request(request: HttpRequest) -> HttpResponse {
+    let is_head = request.method == "HEAD";
+    let path = http::split_url(&request.url).0.to_string();
+    let mut response = http::handle_request(request, time());
+    if !is_head {
+        certification::add_certificate_header(&mut response, &path);
+    }
+    response
+}
+
+#[ic_cdk::query]
+pub fn http_request_streaming_callback(token: StreamingCallbackToken) -> StreamingCallbackHttpResponse {
+    http::handle_streaming_callback(token, time())
+}
+
+/// Generate the attachment URL signing key from the management canister's
+/// randomness, unless it already exists. Randomness needs an inter-canister
+/// call, so this runs from a timer right after init or upgrade.
+fn init_url_signing_key() {
+    if state::url_signing_key().is_some() {
+        return;
+    }
+    
+    set_timer(std::time::Duration::ZERO, || {
+        ic_cdk::futures::spawn(async {
+            match ic_cdk::management_canister::raw_rand().await {
+                Ok(bytes) if bytes.len() >= 32 => {
+                    let mut key = [0u8; 32];
+                    key.copy_from_slice(&bytes[..32]);
+                    state::set_url_signing_key(key);
+                }
+                result => ic_cdk::api::debug_print(format!("Failed to initialize URL signing key: {:?}", result)),
+            }
+        });
+    });
+}
+
+// Stats and utility functions
+#[ic_cdk::query]
+pub fn get_stats() -> Vec<(String, u64)> {
+    let user_count = state::with_users(|users| users.len());
+    let message_count = state::with_messages(|messages| messages.len());
+    let channel_count = state::with_channels(|channels| channels.len());
+    
+    vec![
+        ("users".to_string(), user_count),
+        ("messages".to_string(), message_count),
+        ("channels".to_string(), channel_count),
+    ]
+}
+
+// Initialize the canister with VetKeys support
 #[init]
-fn init(key_name: Option<String>) {
-    // Initialize VetKey name for this canister
-    let vetkey_name = key_name.unwrap_or_else(|| "chat_z_symmetric_key".to_string());
+fn init(args: Option<CanisterArgs>) {
+    let args = args.or_else(legacy_canister_args).unwrap_or_default();
+    
+    // Initialize VetKey name for this canister; the arguments may override it
     state::with_key_name_mut(|key_name_cell| {
-        key_name_cell.set(vetkey_name).expect("Failed to set key name");
+        key_name_cell.set(DEFAULT_VETKD_KEY_NAME.to_string()).expect("Failed to set key name");
+    });
+    apply_canister_args(&args);
+    
+    // A fresh install has nothing to migrate
+    state::set_upgrade_record(UpgradeRecord {
+        migration_version: MIGRATIONS.len() as u32,
+        ..Default::default()
     });
+    state::set_encrypted_channels_indexed_below(u64::MAX);
 
     // Create a general channel with anonymous principal as initial member
     // This allows anyone to send messages to the general channel initially
@@ -476,6 +1587,9 @@ fn init(key_name: Option<String>) {
         last_message_at: None,
         is_encrypted: false,
         password_hash: None,
+        message_expiry: None,
+        retention: None,
+        key_epoch: None,
     };
     
     state::with_channels_mut(|channels| {
@@ -485,29 +1599,205 @@ fn init(key_name: Option<String>) {
     // The next channel ID will be automatically managed by the next_channel_id() function
     // and will start at 2 since we just created channel 1
     
-    // Set up automatic cleanup timer for expired encrypted messages
-    // Run cleanup every hour (3600 seconds)
+    setup_timers();
+    certification::init();
+}
+
+/// Releases before `CanisterArgs` took just the VetKD key name as `opt text`.
+/// Under the Candid `opt` rule that argument decodes as a missing
+/// `CanisterArgs`, so deploy scripts still passing it are recognised from the
+/// raw argument instead.
+fn legacy_canister_args() -> Option<CanisterArgs> {
+    key_name_argument(&ic_cdk::api::msg_arg_data())
+}
+
+fn key_name_argument(arg: &[u8]) -> Option<CanisterArgs> {
+    let (key_name,) = candid::decode_args::<(Option<String>,)>(arg).ok()?;
+    Some(CanisterArgs {
+        vetkd_key_name: Some(key_name?),
+        ..Default::default()
+    })
+}
+
+/// Apply install or upgrade arguments. Invalid arguments trap, which rolls
+/// back the install or upgrade.
+fn apply_canister_args(args: &CanisterArgs) {
+    if let Some(key_name) = &args.vetkd_key_name {
+        if key_name.trim().is_empty() {
+            ic_cdk::trap("vetkd_key_name must not be empty");
+        }
+        state::with_key_name_mut(|key_name_cell| {
+            key_name_cell.set(key_name.trim().to_string()).expect("Failed to set key name");
+        });
+    }
+    
+    if let Some(secs) = args.cleanup_interval_secs {
+        if !(MIN_CLEANUP_INTERVAL_SECS..=MAX_CLEANUP_INTERVAL_SECS).contains(&secs) {
+            ic_cdk::trap(format!(
+                "cleanup_interval_secs must be between {} and {}",
+                MIN_CLEANUP_INTERVAL_SECS, MAX_CLEANUP_INTERVAL_SECS
+            ));
+        }
+        state::set_cleanup_interval_secs(secs);
+    }
+}
+
+/// Timers do not survive upgrades, so this runs after both install and upgrade
+fn setup_timers() {
     setup_cleanup_timer();
+    schedule_expiry_cleanup();
+    init_url_signing_key();
 }
 
-/// Set up timer to clean up expired messages every hour
+/// Set up timer to clean up expired messages and stale uploads
 fn setup_cleanup_timer() {
     use std::time::Duration;
-    let cleanup_interval = Duration::from_secs(3600); // 1 hour
+    let cleanup_interval = Duration::from_secs(state::cleanup_interval_secs());
     set_timer_interval(cleanup_interval, || {
-        ic_cdk::spawn(async {
-            let cleaned = cleanup_expired_messages();
-            if cleaned > 0 {
-                ic_cdk::print(&format!("Cleaned up {} expired encrypted messages", cleaned));
-            }
-        });
+        run_expiry_cleanup();
+        run_retention_cleanup();
+        run_upload_cleanup();
     });
 }
 
+/// Delete one batch of abandoned uploads, rescheduling like
+/// `run_expiry_cleanup` while more remain
+fn run_upload_cleanup() {
+    let (cleaned, done) = cleanup_stale_uploads(time(), CLEANUP_BATCH_SIZE);
+    if cleaned > 0 {
+        ic_cdk::api::debug_print(format!("Cleaned up {} stale uploads", cleaned));
+    }
+    if !done {
+        set_timer(std::time::Duration::ZERO, run_upload_cleanup);
+    }
+}
+
+/// Delete one batch of expired messages, and schedule the next batch in a
+/// separate execution while expired messages remain
+fn run_expiry_cleanup() {
+    let (cleaned, done) = cleanup_expired_batch(time(), CLEANUP_BATCH_SIZE);
+    if cleaned > 0 {
+        ic_cdk::api::debug_print(format!("Cleaned up {} expired encrypted messages", cleaned));
+    }
+    if done {
+        schedule_expiry_cleanup();
+    } else {
+        set_timer(std::time::Duration::ZERO, run_expiry_cleanup);
+    }
+}
+
+/// Set a timer for when the next encrypted message expires, so it is purged
+/// and uncertified then instead of at the next cleanup interval
+fn schedule_expiry_cleanup() {
+    let Some(next) = state::next_expiry() else {
+        return;
+    };
+    let scheduled = state::expiry_timer();
+    if scheduled.is_some_and(|(at, _)| at <= next) {
+        return;
+    }
+    if let Some((_, timer)) = scheduled {
+        ic_cdk_timers::clear_timer(timer);
+    }
+
+    // A message counts as expired once the time is past its expiry
+    let delay = std::time::Duration::from_nanos(next.saturating_add(1).saturating_sub(time()));
+    let timer = set_timer(delay, || {
+        state::set_expiry_timer(None);
+        run_expiry_cleanup();
+    });
+    state::set_expiry_timer(Some((next, timer)));
+}
+
+// Upgrades: all persistent state lives in stable structures, so there is no
+// pre_upgrade hook. Heap state (presence, typing, the certification tree) is
+// dropped on upgrade; timers are cancelled and must be set up again.
+
+/// Data migrations, applied in order after upgrades. Migration `n` (1-based)
+/// runs when the recorded migration version is below `n`. Each runs in
+/// batches across executions (see `state::Migration`), so none has to fit in
+/// the upgrade's instruction limit. Migrations only cover data a release
+/// has written. Append only; once released, never reorder or remove
+/// entries.
+const MIGRATIONS: &[state::Migration] = &[
+    // Expiry index for encrypted messages created before it existed
+    state::rebuild_message_expiry_index,
+    // Per-channel message index used by retention policies
+    state::rebuild_channel_message_index,
+    // Owner and share indexes keyed by (principal, message_id)
+    state::migrate_encrypted_message_indexes,
+    // Stored hashes of encrypted messages, so upgrades do not re-encode them
+    certification::certify_stored_encrypted_messages,
+    // Stored hashes of public channel feeds, so upgrades do not rebuild them
+    certification::certify_stored_channel_feeds,
+    // Per-channel index of encrypted messages
+    state::rebuild_encrypted_channel_index,
+];
+
+/// Run one batch of the pending migration and return whether any migration
+/// is still pending. The version only advances once a migration finishes;
+/// until then its cursor is recorded so the next batch resumes from it.
+fn run_migration_batch(record: &mut UpgradeRecord, budget: usize) -> bool {
+    let Some(migration) = MIGRATIONS.get(record.migration_version as usize) else {
+        return false;
+    };
+    
+    record.migration_cursor = migration(record.migration_cursor.as_deref(), budget);
+    if record.migration_cursor.is_none() {
+        record.migration_version += 1;
+    }
+    (record.migration_version as usize) < MIGRATIONS.len()
+}
+
+/// Run a batch of the pending migrations, and schedule the next batch in a
+/// separate execution while any remain
+fn run_migrations() {
+    let mut record = state::upgrade_record();
+    let pending = run_migration_batch(&mut record, MIGRATION_BATCH_SIZE);
+    state::set_upgrade_record(record);
+    if pending {
+        set_timer(std::time::Duration::ZERO, run_migrations);
+    }
+}
+
 #[post_upgrade]
-fn post_upgrade() {
+fn post_upgrade(args: Option<CanisterArgs>) {
+    match state::verify_stored_records(UPGRADE_VERIFY_SAMPLE) {
+        Ok(verified) => ic_cdk::api::debug_print(format!("Decoded {} sampled records", verified)),
+        Err(error) => ic_cdk::trap(format!("Failed to decode stored {}", error)),
+    }
+    
+    apply_canister_args(&args.or_else(legacy_canister_args).unwrap_or_default());
+    
+    let mut record = state::upgrade_record();
+    record.last_upgraded_at = Some(time());
+    record.upgrade_count += 1;
+    state::set_upgrade_record(record);
+    
+    // The first batch runs before any other call sees the new code
+    run_migrations();
+    
     // Fix General channel permissions on upgrade
     fix_general_channel_permissions();
+    
+    setup_timers();
+    
+    // The certification tree lives in heap memory and must be rebuilt
+    certification::init();
+}
+
+/// Upgrade bookkeeping and the settings that can be changed by upgrade arguments
+#[ic_cdk::query]
+pub fn get_canister_info() -> Result<CanisterInfo, ChatError> {
+    if !is_admin(&msg_caller()) {
+        return Err(ChatError::NotAuthorized);
+    }
+    
+    Ok(CanisterInfo {
+        upgrade: state::upgrade_record(),
+        vetkd_key_name: state::with_key_name(|key_name| key_name.get().clone()),
+        cleanup_interval_secs: state::cleanup_interval_secs(),
+    })
 }
 
 // Helper function to ensure General channel has proper permissions
@@ -537,10 +1827,9 @@ fn fix_general_channel_permissions() {
 #[ic_cdk::update]
 pub fn force_delete_channel(channel_id: u64) -> Result<(), ChatError> {
     let caller = msg_caller();
-    let admin = Principal::from_text("ouuvn-c7hpi-46km4-ywlnr-j2ten-wldfi-xu53v-vth6u-3qtqr-cmbxu-gqe").unwrap();
     
     // Only allow admin to call this function
-    if caller != admin {
+    if !is_admin(&caller) {
         return Err(ChatError::NotAuthorized);
     }
     
@@ -557,7 +1846,10 @@ pub fn force_delete_channel(channel_id: u64) -> Result<(), ChatError> {
             }
             None => Err(ChatError::ChannelNotFound)
         }
-    })
+    })?;
+    
+    certification::certify_channel_feed(channel_id);
+    Ok(())
 }
 
 // Delete a channel
@@ -585,7 +1877,10 @@ pub fn delete_channel(channel_id: u64) -> Result<(), ChatError> {
             }
             None => Err(ChatError::ChannelNotFound)
         }
-    })
+    })?;
+    
+    certification::certify_channel_feed(channel_id);
+    Ok(())
 }
 
 #[ic_cdk::update]
@@ -603,27 +1898,35 @@ pub fn fix_general_channel() -> Result<Channel, ChatError> {
 // VetKeys functions will be re-enabled when using ic-cdk 0.18+
 // For now, we have the encrypted message structure and management ready
 
-/// Create a new encrypted message that expires in 24 hours
-/// The backend will encrypt the content using VetKD-derived keys
+/// Create a new encrypted message. It expires after the channel's message
+/// expiry (one day by default), or after `ttl_secs` if that is shorter.
+/// The backend will encrypt the content using VetKD-derived keys.
+/// Attachments are added afterwards with `begin_encrypted_upload`, encrypted
+/// with the key from `encrypted_symmetric_key_for_message`. `inline_attachments`
+/// is the attachment list older clients send; only an empty one is accepted.
 #[ic_cdk::update]
 pub async fn create_encrypted_message(
     plain_content: String, // Plain text content - backend will encrypt it
     channel_id: Option<u64>,
     reply_to: Option<u64>,
     message_type: MessageType,
-    attachments: Vec<Attachment>,
+    inline_attachments: Option<Vec<Reserved>>,
+    ttl_secs: Option<u64>,
 ) -> Result<u64, ChatError> {
-let caller = caller();
+    let caller = msg_caller();
     let current_time = time();
     
     // Validate plain content
     if plain_content.trim().is_empty() || plain_content.len() > 2000 {
         return Err(ChatError::InvalidInput);
     }
+    if inline_attachments.is_some_and(|attachments| !attachments.is_empty()) {
+        return Err(ChatError::InvalidInput);
+    }
     
     // Ensure user is registered
     let user_exists = state::with_users(|users| {
-        users.get(&caller).is_some()
+        users.contains_key(&caller)
     });
     
     if !user_exists {
@@ -654,8 +1957,12 @@ let caller = caller();
         }
     }
     
+    let channel = channel_id.and_then(|channel_id| state::with_channels(|channels| channels.get(&channel_id)));
+    let channel_expiry = channel.as_ref().map(Channel::message_expiry).unwrap_or(MessageExpiry::OneDay);
+    let key_epoch = Some(channel.as_ref().map(Channel::key_epoch).unwrap_or(0));
+    let expires_at = message_expires_at(channel_expiry, ttl_secs, current_time)?;
+    
     let message_id = state::next_message_id();
-    let expires_at = current_time + (24 * 60 * 60 * 1_000_000_000); // 24 hours in nanoseconds
     
     // Encrypt the content using VetKD-derived symmetric key
     let encrypted_content = match encrypt_message_content(&plain_content, message_id).await {
@@ -676,102 +1983,288 @@ let caller = caller();
         reply_to,
         message_type,
         shared_with: vec![],
-        attachments,
+        attachments: vec![],
+        key_epoch,
+        ibe_recipient: None,
     };
     
-    // Store the encrypted message
-    state::with_encrypted_messages_mut(|messages| {
-        messages.insert(message_id, encrypted_message);
-    });
+    store_encrypted_message(encrypted_message);
+    schedule_expiry_cleanup();
+    Ok(message_id)
+}
+
+/// Store a new encrypted message with its index entries, and count it for its
+/// author
+fn store_encrypted_message(message: EncryptedMessage) {
+    let message_id = message.id;
+    let author = message.author;
+    let timestamp = message.timestamp;
+    
+    certification::certify_encrypted_message(&message);
+    if let Some(expires_at) = message.expires_at {
+        state::index_message_expiry(expires_at, message_id);
+    }
+    if let Some(channel_id) = message.channel_id {
+        state::index_encrypted_channel_message(channel_id, message_id);
+    }
     
-    // Add to owner's list
-    state::with_encrypted_message_owners_mut(|owners| {
-        if let Some(mut owner_ids) = owners.get(&caller) {
-            owner_ids.ids.push(message_id);
-            owners.insert(caller, owner_ids);
-        } else {
-            owners.insert(caller, MessageIds { ids: vec![message_id] });
-        }
+    // Add to owner's and shared lists
+    state::add_message_owner(author, message_id);
+    for user in &message.shared_with {
+        state::add_message_share(*user, message_id);
+    }
+    
+    state::with_encrypted_messages_mut(|messages| {
+        messages.insert(message_id, message);
     });
     
     // Update user message count
     state::with_users_mut(|users| {
-        if let Some(mut user) = users.get(&caller) {
+        if let Some(mut user) = users.get(&author) {
             user.message_count += 1;
-            user.last_active = current_time;
-            users.insert(caller, user);
+            user.last_active = timestamp;
+            users.insert(author, user);
+        }
+    });
+}
+
+/// Send a direct message the caller encrypted on the client to the
+/// recipient's IBE identity (see `ibe_encryption_key`). The canister stores the
+/// ciphertext as is and never holds a key for it.
+#[ic_cdk::update]
+pub fn create_ibe_message(
+    recipient: Principal,
+    ciphertext: String,
+    reply_to: Option<u64>,
+    message_type: MessageType,
+    ttl_secs: Option<u64>,
+) -> Result<u64, ChatError> {
+    let message_id = store_ibe_message(&msg_caller(), recipient, ciphertext, reply_to, message_type, ttl_secs, time())?;
+    schedule_expiry_cleanup();
+    Ok(message_id)
+}
+
+fn store_ibe_message(
+    caller: &Principal,
+    recipient: Principal,
+    ciphertext: String,
+    reply_to: Option<u64>,
+    message_type: MessageType,
+    ttl_secs: Option<u64>,
+    current_time: u64,
+) -> Result<u64, ChatError> {
+    if ciphertext.is_empty() || ciphertext.len() > MAX_IBE_CIPHERTEXT_BYTES || recipient == *caller {
+        return Err(ChatError::InvalidInput);
+    }
+    if state::with_users(|users| !users.contains_key(caller)) {
+        return Err(ChatError::NotAuthorized);
+    }
+    
+    // Same rules as sharing: registered recipients who have not blocked the sender
+    if state::with_users(|users| !users.contains_key(&recipient)) {
+        return Err(ChatError::NotFound);
+    }
+    if state::is_blocked(&recipient, caller) {
+        return Err(ChatError::NotAuthorized);
+    }
+    
+    let expires_at = message_expires_at(MessageExpiry::OneDay, ttl_secs, current_time)?;
+    let message_id = state::next_message_id();
+    
+    store_encrypted_message(EncryptedMessage {
+        id: message_id,
+        encrypted_content: ciphertext,
+        author: *caller,
+        timestamp: current_time,
+        expires_at,
+        channel_id: None,
+        reply_to,
+        message_type,
+        shared_with: vec![recipient],
+        attachments: vec![],
+        key_epoch: None,
+        ibe_recipient: Some(recipient),
+    });
+    Ok(message_id)
+}
+
+/// Expiry time of a new encrypted message. A per-message TTL may shorten the
+/// channel's expiry but not extend it.
+fn message_expires_at(channel_expiry: MessageExpiry, ttl_secs: Option<u64>, current_time: u64) -> Result<Option<u64>, ChatError> {
+    let max_ns = channel_expiry.duration_ns();
+    
+    let Some(ttl_secs) = ttl_secs else {
+        return Ok(max_ns.map(|ns| current_time.saturating_add(ns)));
+    };
+    
+    let ttl_ns = ttl_secs.checked_mul(1_000_000_000).ok_or(ChatError::InvalidInput)?;
+    if ttl_ns == 0 || max_ns.is_some_and(|max_ns| ttl_ns > max_ns) {
+        return Err(ChatError::InvalidInput);
+    }
+    
+    Ok(Some(current_time.saturating_add(ttl_ns)))
+}
+
+/// Set how long encrypted messages in a channel are kept. Only the channel
+/// creator can change it, and it applies to messages sent afterwards.
+#[ic_cdk::update]
+pub fn set_channel_message_expiry(channel_id: u64, expiry: MessageExpiry) -> Result<Channel, ChatError> {
+    let caller = msg_caller();
+    
+    state::with_channels_mut(|channels| {
+        let mut channel = channels.get(&channel_id).ok_or(ChatError::ChannelNotFound)?;
+        if channel.created_by != caller {
+            return Err(ChatError::NotAuthorized);
+        }
+        if !channel.is_encrypted {
+            return Err(ChatError::InvalidInput);
+        }
+        
+        channel.message_expiry = Some(expiry);
+        channels.insert(channel_id, channel.clone());
+        Ok(channel)
+    })
+}
+
+/// Get the newest 100 encrypted messages owned by or shared with the caller.
+/// Kept for clients built against the original interface; use
+/// `get_encrypted_message_page` to read further back.
+#[ic_cdk::update]
+pub fn get_encrypted_messages() -> Vec<LegacyEncryptedMessage> {
+    own_and_shared_messages(&msg_caller(), time(), None, Some(100))
+        .messages
+        .into_iter()
+        .map(Into::into)
+        .collect()
+}
+
+/// Get a page of encrypted messages owned by or shared with the caller, newest
+/// first, starting below the message ID `before`
+#[ic_cdk::query]
+pub fn get_encrypted_message_page(before: Option<u64>, limit: Option<u64>) -> EncryptedMessagePage {
+    own_and_shared_messages(&msg_caller(), time(), before, limit)
+}
+
+/// Unexpired messages the user wrote or that were shared with them, except
+/// those shared by users they have blocked
+fn own_and_shared_messages(
+    user: &Principal,
+    current_time: u64,
+    before: Option<u64>,
+    limit: Option<u64>,
+) -> EncryptedMessagePage {
+    let blocked = state::with_blocked_users(|blocked| blocked.get(user).unwrap_or_default());
+    
+    let next_ids = |before, count| {
+        let mut ids = state::owned_message_ids(user, before, count);
+        ids.extend(state::shared_message_ids(user, before, count));
+        ids.sort_unstable_by(|a, b| b.cmp(a));
+        ids.dedup();
+        ids.truncate(count);
+        ids
+    };
+    encrypted_message_page(before, limit, next_ids, |msg| {
+        !msg.is_expired(current_time) && (&msg.author == user || !blocked.contains(&msg.author))
+    })
+}
+
+/// Read one page of messages, newest first. `next_ids(before, count)` returns
+/// up to `count` candidate IDs below `before`, newest first, from an index
+/// range scan; candidates `keep` rejects are skipped and more are read. One
+/// message beyond the page is read to tell whether another page follows.
+fn encrypted_message_page(
+    before: Option<u64>,
+    limit: Option<u64>,
+    mut next_ids: impl FnMut(Option<u64>, usize) -> Vec<u64>,
+    keep: impl Fn(&EncryptedMessage) -> bool,
+) -> EncryptedMessagePage {
+    let limit = limit.unwrap_or(50).clamp(1, 100) as usize;
+    let mut before = before;
+    let mut messages = Vec::new();
+    
+    while messages.len() <= limit {
+        let wanted = limit + 1 - messages.len();
+        let ids = next_ids(before, wanted);
+        state::with_encrypted_messages(|stored| {
+            messages.extend(ids.iter().filter_map(|id| stored.get(id)).filter(|msg| keep(msg)))
+        });
+        match ids.last() {
+            Some(last) if ids.len() == wanted => before = Some(*last),
+            _ => break,
         }
-    });
+    }
     
-    Ok(message_id)
+    let next_before = (messages.len() > limit).then(|| {
+        messages.truncate(limit);
+        messages[limit - 1].id
+    });
+    EncryptedMessagePage { messages, next_before }
 }
 
-/// Get encrypted messages accessible by the caller
-#[ic_cdk::update]
-pub fn get_encrypted_messages() -> Vec<EncryptedMessage> {
-let caller = caller();
-    let current_time = time();
+/// Fetch a readable encrypted message together with its certificate, for
+/// callers that need to verify the response was not tampered with. Must be
+/// called as a query; certificates are not available in update calls.
+#[ic_cdk::query]
+pub fn get_certified_encrypted_message(message_id: u64) -> Result<CertifiedEncryptedMessage, ChatError> {
+    let caller = msg_caller();
     
-    state::with_encrypted_messages(|messages| {
-        let owned = state::with_encrypted_message_owners(|ids| {
-            let default_ids = MessageIds { ids: vec![] };
-            ids.get(&caller)
-                .unwrap_or(default_ids)
-                .iter()
-                .filter_map(|id| messages.get(id))
-                .filter(|msg| !msg.is_expired(current_time))
-                .collect::<Vec<_>>()
-        });
-        
-        let shared = state::with_encrypted_message_shares(|ids| {
-            let default_ids = MessageIds { ids: vec![] };
-            ids.get(&caller)
-                .unwrap_or(default_ids)
-                .iter()
-                .filter_map(|id| messages.get(id))
-                .filter(|msg| !msg.is_expired(current_time))
-                .collect::<Vec<_>>()
-        });
-        
-        let mut result = Vec::with_capacity(owned.len() + shared.len());
-        result.extend(owned);
-        result.extend(shared);
-        result
+    if !can_read_encrypted_message(&caller, message_id, time()) {
+        return Err(ChatError::NotFound);
+    }
+    let path = certification::encrypted_message_path(message_id);
+    if !certification::is_certified(&path) {
+        return Err(ChatError::NotFound);
+    }
+    let certificate = ic_cdk::api::data_certificate().ok_or(ChatError::InvalidInput)?;
+    let message = state::with_encrypted_messages(|messages| messages.get(&message_id))
+        .ok_or(ChatError::NotFound)?;
+    
+    Ok(CertifiedEncryptedMessage {
+        encoded_message: certification::encode_encrypted_message(&message),
+        certificate,
+        witness: certification::witness(&path),
     })
 }
 
 /// Share an encrypted message with another user
 #[ic_cdk::update]
 pub fn share_encrypted_message(message_id: u64, user_principal: Principal) -> Result<(), ChatError> {
-let caller = caller();
-    
+    share_message(&msg_caller(), message_id, user_principal)
+}
+
+fn share_message(caller: &Principal, message_id: u64, user_principal: Principal) -> Result<(), ChatError> {
     state::with_encrypted_messages_mut(|messages| {
         if let Some(mut message) = messages.get(&message_id) {
             let owner = &message.author;
-            if owner != &caller {
+            if owner != caller {
+                return Err(ChatError::NotAuthorized);
+            }
+            
+            // Shares go to registered users who have not blocked the owner
+            if state::with_users(|users| !users.contains_key(&user_principal)) {
+                return Err(ChatError::NotFound);
+            }
+            if state::is_blocked(&user_principal, caller) {
                 return Err(ChatError::NotAuthorized);
             }
             
+            // Only the recipient can decrypt a message encrypted to their identity
+            if message.ibe_recipient.is_some() {
+                return Err(ChatError::InvalidInput);
+            }
+            
             if message.shared_with.len() >= 50 {
                 return Err(ChatError::InvalidInput);
             }
             
             if !message.shared_with.contains(&user_principal) {
                 message.shared_with.push(user_principal);
+                certification::certify_encrypted_message(&message);
                 messages.insert(message_id, message);
             }
             
             // Add to shared list
-            state::with_encrypted_message_shares_mut(|shares| {
-                if let Some(mut user_ids) = shares.get(&user_principal) {
-                    if !user_ids.ids.contains(&message_id) {
-                        user_ids.ids.push(message_id);
-                        shares.insert(user_principal, user_ids);
-                    }
-                } else {
-                    shares.insert(user_principal, MessageIds { ids: vec![message_id] });
-                }
-            });
+            state::add_message_share(user_principal, message_id);
             
             Ok(())
         } else {
@@ -780,55 +2273,87 @@ let caller = caller();
     })
 }
 
+/// Revoke a share of an encrypted message (only owner can unshare)
+#[ic_cdk::update]
+pub fn unshare_encrypted_message(message_id: u64, user_principal: Principal) -> Result<(), ChatError> {
+    unshare_message(&msg_caller(), message_id, user_principal)
+}
+
+fn unshare_message(caller: &Principal, message_id: u64, user_principal: Principal) -> Result<(), ChatError> {
+    state::with_encrypted_messages_mut(|messages| {
+        let mut message = messages.get(&message_id).ok_or(ChatError::NotFound)?;
+        if &message.author != caller {
+            return Err(ChatError::NotAuthorized);
+        }
+        
+        if message.shared_with.contains(&user_principal) {
+            message.shared_with.retain(|p| p != &user_principal);
+            certification::certify_encrypted_message(&message);
+            messages.insert(message_id, message);
+        }
+        state::remove_message_share(user_principal, message_id);
+        
+        Ok(())
+    })
+}
+
+/// Principals an encrypted message is shared with (only owner can list them)
+#[ic_cdk::query]
+pub fn get_encrypted_message_shares(message_id: u64) -> Result<Vec<Principal>, ChatError> {
+    message_shares(&msg_caller(), message_id)
+}
+
+fn message_shares(caller: &Principal, message_id: u64) -> Result<Vec<Principal>, ChatError> {
+    let message = state::with_encrypted_messages(|messages| messages.get(&message_id))
+        .ok_or(ChatError::NotFound)?;
+    if &message.author != caller {
+        return Err(ChatError::NotAuthorized);
+    }
+    
+    Ok(message.shared_with)
+}
+
 /// Delete an encrypted message (only owner can delete)
 #[ic_cdk::update]
 pub fn delete_encrypted_message(message_id: u64) -> Result<(), ChatError> {
-let caller = caller();
+    let caller = msg_caller();
+    
+    let message = state::with_encrypted_messages(|messages| messages.get(&message_id))
+        .ok_or(ChatError::NotFound)?;
+    if message.author != caller {
+        return Err(ChatError::NotAuthorized);
+    }
+    
+    purge_encrypted_message(&message);
+    Ok(())
+}
+
+/// Remove an encrypted message with its index entries and attachments
+fn purge_encrypted_message(message: &EncryptedMessage) {
+    let message_id = message.id;
+    
+    // Remove from owner's and shared lists
+    state::remove_message_owner(message.author, message_id);
+    for share in &message.shared_with {
+        state::remove_message_share(*share, message_id);
+    }
     
     state::with_encrypted_messages_mut(|messages| {
-        if let Some(message_to_delete) = messages.get(&message_id) {
-            let owner = &message_to_delete.author;
-            if owner != &caller {
-                return Err(ChatError::NotAuthorized);
-            }
-            
-            // Remove from owner's list
-            state::with_encrypted_message_owners_mut(|owners| {
-                if let Some(mut owner_ids) = owners.get(owner) {
-                    owner_ids.ids.retain(|&id| id != message_id);
-                    if !owner_ids.ids.is_empty() {
-                        owners.insert(*owner, owner_ids);
-                    } else {
-                        owners.remove(owner);
-                    }
-                }
-            });
-            
-            // Remove from shared lists
-            state::with_encrypted_message_shares_mut(|shares| {
-                for share in &message_to_delete.shared_with {
-                    if let Some(mut share_ids) = shares.get(share) {
-                        share_ids.ids.retain(|&id| id != message_id);
-                        if !share_ids.ids.is_empty() {
-                            shares.insert(*share, share_ids);
-                        } else {
-                            shares.remove(share);
-                        }
-                    }
-                }
-            });
-            
-            messages.remove(&message_id);
-            Ok(())
-        } else {
-            Err(ChatError::NotFound)
-        }
-    })
+        messages.remove(&message_id);
+    });
+    certification::uncertify_encrypted_message(message_id);
+    if let Some(expires_at) = message.expires_at {
+        state::unindex_message_expiry(expires_at, message_id);
+    }
+    if let Some(channel_id) = message.channel_id {
+        state::unindex_encrypted_channel_message(channel_id, message_id);
+    }
+    remove_attachments(&message.attachments.iter().map(|a| a.id).collect::<Vec<_>>());
 }
 
 #[ic_cdk::update]
 pub fn create_encrypted_channel(name: String, description: Option<String>, password: Option<String>) -> Result<Channel, ChatError> {
-let caller = caller();
+    let caller = msg_caller();
     
     if name.trim().is_empty() || name.len() > 100 {
         return Err(ChatError::InvalidInput);
@@ -836,7 +2361,7 @@ let caller = caller();
     
     // Ensure user is registered
     let user_exists = state::with_users(|users| {
-        users.get(&caller).is_some()
+        users.contains_key(&caller)
     });
     
     if !user_exists {
@@ -869,6 +2394,9 @@ let caller = caller();
         last_message_at: None,
         is_encrypted: true,
         password_hash,
+        message_expiry: None,
+        retention: None,
+        key_epoch: None,
     };
     
     state::with_channels_mut(|channels| {
@@ -878,63 +2406,169 @@ let caller = caller();
     Ok(channel)
 }
 
-/// Cleanup expired encrypted messages - called periodically
+/// Run one cleanup batch of expired encrypted messages and return how many
+/// were removed. Admin only; the call is rejected for anyone else. The timer
+/// normally takes care of this; remaining batches are scheduled as usual.
 #[ic_cdk::update]
 pub fn cleanup_expired_messages() -> u64 {
-    let current_time = time();
+    if !is_admin(&msg_caller()) {
+        ic_cdk::trap("Only admins can run the expiry cleanup");
+    }
+    
+    let (cleaned, done) = cleanup_expired_batch(time(), CLEANUP_BATCH_SIZE);
+    if !done {
+        set_timer(std::time::Duration::ZERO, run_expiry_cleanup);
+    }
+    cleaned
+}
+
+/// Delete up to `budget` expired encrypted messages, oldest first. Returns the
+/// number deleted and whether no expired messages remain.
+fn cleanup_expired_batch(current_time: u64, budget: usize) -> (u64, bool) {
+    // Fetch one extra ID to learn whether another batch is needed
+    let mut expired = state::expired_messages(current_time, budget + 1);
+    let done = expired.len() <= budget;
+    expired.truncate(budget);
+    
     let mut cleaned_count = 0u64;
+    for (expires_at, message_id) in expired {
+        match state::with_encrypted_messages(|messages| messages.get(&message_id)) {
+            Some(message) => {
+                purge_encrypted_message(&message);
+                cleaned_count += 1;
+            }
+            None => state::unindex_message_expiry(expires_at, message_id),
+        }
+    }
     
-    let expired_ids: Vec<u64> = state::with_encrypted_messages(|messages| {
-        messages.iter()
-            .filter_map(|(id, message)| {
-                if message.is_expired(current_time) {
-                    Some(id)
-                } else {
-                    None
-                }
-            })
-            .collect()
+    (cleaned_count, done)
+}
+
+// === Channel Retention ===
+
+/// Set or clear the retention policy of a channel. Only the channel creator can
+/// change it; existing messages are trimmed by the next cleanup run. Encrypted
+/// channels rely on message expiry and reject a policy with `InvalidInput`.
+#[ic_cdk::update]
+pub fn set_channel_retention(channel_id: u64, policy: Option<RetentionPolicy>) -> Result<Channel, ChatError> {
+    update_channel_retention(&msg_caller(), channel_id, policy)
+}
+
+fn update_channel_retention(caller: &Principal, channel_id: u64, policy: Option<RetentionPolicy>) -> Result<Channel, ChatError> {
+    match policy {
+        Some(RetentionPolicy::MaxAgeDays(days)) if days == 0 || days > MAX_RETENTION_DAYS => {
+            return Err(ChatError::InvalidInput);
+        }
+        Some(RetentionPolicy::MaxMessages(count)) if count == 0 || count > MAX_RETENTION_MESSAGES => {
+            return Err(ChatError::InvalidInput);
+        }
+        _ => {}
+    }
+    
+    state::with_channels_mut(|channels| {
+        let mut channel = channels.get(&channel_id).ok_or(ChatError::ChannelNotFound)?;
+        if &channel.created_by != caller {
+            return Err(ChatError::NotAuthorized);
+        }
+        
+        // Retention only removes plaintext messages; encrypted channels use
+        // message expiry instead
+        if channel.is_encrypted {
+            return Err(ChatError::InvalidInput);
+        }
+        
+        channel.retention = policy;
+        channels.insert(channel_id, channel.clone());
+        Ok(channel)
+    })
+}
+
+/// Apply retention policies to every channel that has one. A run that uses its
+/// whole budget schedules another in a separate execution.
+fn run_retention_cleanup() {
+    let channel_ids: Vec<u64> = state::with_channels(|channels| {
+        channels.iter().filter(|(_, channel)| channel.retention.is_some()).map(|(id, _)| id).collect()
     });
     
-    for message_id in expired_ids {
-        // Use the existing delete function logic but without caller authorization
-        if let Some(message_to_delete) = state::with_encrypted_messages(|messages| messages.get(&message_id)) {
-            let owner = &message_to_delete.author;
-            
-            // Remove from owner's list
-            state::with_encrypted_message_owners_mut(|owners| {
-                if let Some(mut owner_ids) = owners.get(owner) {
-                    owner_ids.ids.retain(|&id| id != message_id);
-                    if !owner_ids.ids.is_empty() {
-                        owners.insert(*owner, owner_ids);
-                    } else {
-                        owners.remove(owner);
-                    }
-                }
-            });
-            
-            // Remove from shared lists
-            state::with_encrypted_message_shares_mut(|shares| {
-                for share in &message_to_delete.shared_with {
-                    if let Some(mut share_ids) = shares.get(share) {
-                        share_ids.ids.retain(|&id| id != message_id);
-                        if !share_ids.ids.is_empty() {
-                            shares.insert(*share, share_ids);
-                        } else {
-                            shares.remove(share);
-                        }
-                    }
+    let mut budget = CLEANUP_BATCH_SIZE;
+    let mut cleaned = 0u64;
+    for channel_id in channel_ids {
+        let removed = enforce_channel_retention(channel_id, time(), budget);
+        if removed > 0 {
+            certification::certify_channel_feed(channel_id);
+        }
+        budget -= removed as usize;
+        cleaned += removed;
+        if budget == 0 {
+            break;
+        }
+    }
+    
+    if cleaned > 0 {
+        ic_cdk::api::debug_print(format!("Removed {} messages past channel retention", cleaned));
+    }
+    if budget == 0 {
+        set_timer(std::time::Duration::ZERO, run_retention_cleanup);
+    }
+}
+
+/// Delete up to `budget` of a channel's oldest messages that fall outside its
+/// retention policy. Returns the number deleted.
+fn enforce_channel_retention(channel_id: u64, current_time: u64, budget: usize) -> u64 {
+    let Some(channel) = state::with_channels(|channels| channels.get(&channel_id)) else {
+        return 0;
+    };
+    
+    let candidates = match channel.retention {
+        Some(RetentionPolicy::MaxMessages(max)) => {
+            let excess = channel.message_count.saturating_sub(max).min(budget as u64) as usize;
+            state::oldest_channel_messages(channel_id, excess)
+        }
+        Some(RetentionPolicy::MaxAgeDays(days)) => {
+            let cutoff = current_time.saturating_sub(days as u64 * DAY_NS);
+            let mut expired = Vec::new();
+            for message_id in state::oldest_channel_messages(channel_id, budget) {
+                match state::with_messages(|messages| messages.get(&message_id)) {
+                    Some(message) if message.timestamp >= cutoff => break,
+                    _ => expired.push(message_id),
                 }
-            });
-            
-            state::with_encrypted_messages_mut(|messages| {
-                messages.remove(&message_id);
-            });
-            cleaned_count += 1;
+            }
+            expired
         }
+        None => return 0,
+    };
+    
+    let mut removed = 0u64;
+    let mut removed_by_author: HashMap<Principal, u64> = HashMap::new();
+    for message_id in candidates {
+        state::unindex_channel_message(channel_id, message_id);
+        let Some(message) = state::with_messages_mut(|messages| messages.remove(&message_id)) else {
+            continue;
+        };
+        let attachment_ids: Vec<u64> = message.attachments.iter().map(|a| a.id).collect();
+        remove_attachments(&attachment_ids);
+        *removed_by_author.entry(message.author).or_default() += 1;
+        removed += 1;
+    }
+    
+    if removed > 0 {
+        state::with_channels_mut(|channels| {
+            if let Some(mut channel) = channels.get(&channel_id) {
+                channel.message_count = channel.message_count.saturating_sub(removed);
+                channels.insert(channel_id, channel);
+            }
+        });
+        state::with_users_mut(|users| {
+            for (author, count) in removed_by_author {
+                if let Some(mut user) = users.get(&author) {
+                    user.message_count = user.message_count.saturating_sub(count);
+                    users.insert(author, user);
+                }
+            }
+        });
     }
     
-    cleaned_count
+    removed
 }
 
 // === Password Handling Functions ===
@@ -956,74 +2590,187 @@ pub fn verify_password(provided_password: &str, stored_hash: &str) -> bool {
 
 // === VetKeys Integration ===
 
-/// Generate a VetKey ID for encrypted symmetric keys
-fn key_id() -> VetKDKeyId {
-    state::with_key_name(|key_name_cell| {
-        VetKDKeyId {
-            curve: VetKDCurve::Bls12_381_G2,
-            name: key_name_cell.get().clone(),
-        }
-    })
-}
-
-/// Get the public key for encrypted message verification
+/// Get the canister's public key for the empty context. Message keys are
+/// derived in channel and user contexts; verify them against
+/// `channel_key_verification_key` or `user_key_verification_key` instead.
 #[ic_cdk::update]
 pub async fn symmetric_key_verification_key_for_encrypted_message() -> Result<Vec<u8>, String> {
     let request = VetKDPublicKeyArgs {
         canister_id: None,
         context: vec![],
-        key_id: key_id(),
+        key_id: vetkd::key_id(),
     };
+    
+    let response = IcManagementCanister.vetkd_public_key(request).await?;
+    Ok(response.public_key)
+}
 
-    let (response,): (VetKDPublicKeyResult,) = call(
-        Principal::management_canister(),
-        "vetkd_public_key",
-        (request,),
-    )
-    .await
-    .map_err(|e| format!("Failed to get VetKD public key: {:?}", e))?;
+/// Public key for verifying keys derived for an encrypted channel. `epoch`
+/// defaults to the channel's current key epoch.
+#[ic_cdk::update]
+pub async fn channel_key_verification_key(channel_id: u64, epoch: Option<u32>) -> Result<Vec<u8>, String> {
+    channel_public_key(&IcManagementCanister, channel_id, epoch).await
+}
 
-    Ok(response.public_key)
+async fn channel_public_key(
+    management: &impl ManagementCanister,
+    channel_id: u64,
+    epoch: Option<u32>,
+) -> Result<Vec<u8>, String> {
+    let channel = state::with_channels(|channels| channels.get(&channel_id))
+        .filter(|channel| channel.is_encrypted)
+        .ok_or("Channel not found")?;
+    
+    let epoch = epoch.unwrap_or(channel.key_epoch());
+    if epoch > channel.key_epoch() {
+        return Err("Unknown key epoch".to_string());
+    }
+    
+    vetkd::public_key(management, &KeyContext::Channel { channel_id, epoch }).await
+}
+
+/// Public key for verifying keys derived for a user, such as the keys of
+/// their direct messages
+#[ic_cdk::update]
+pub async fn user_key_verification_key(user: Principal) -> Result<Vec<u8>, String> {
+    vetkd::public_key(&IcManagementCanister, &KeyContext::User(user)).await
+}
+
+/// Master public key senders encrypt direct messages with, using the
+/// recipient's principal as the identity
+#[ic_cdk::update]
+pub async fn ibe_encryption_key() -> Result<Vec<u8>, String> {
+    vetkd::public_key(&IcManagementCanister, &KeyContext::Ibe).await
+}
+
+/// Derive the caller's own IBE identity key, encrypted to `transport_public_key`.
+/// Keys are only ever derived for the caller, so each user can decrypt exactly
+/// the messages encrypted to them.
+#[ic_cdk::update]
+pub async fn encrypted_ibe_decryption_key(transport_public_key: Vec<u8>) -> Result<Vec<u8>, String> {
+    ibe_decryption_key(&IcManagementCanister, msg_caller(), transport_public_key).await
+}
+
+async fn ibe_decryption_key(
+    management: &impl ManagementCanister,
+    caller: Principal,
+    transport_public_key: Vec<u8>,
+) -> Result<Vec<u8>, String> {
+    if caller == Principal::anonymous() || state::with_users(|users| !users.contains_key(&caller)) {
+        return Err("Not authorized".to_string());
+    }
+    vetkd::check_transport_public_key(&transport_public_key)?;
+    
+    vetkd::derive_key(management, &KeyContext::Ibe, vetkd::ibe_identity(&caller), transport_public_key).await
 }
 
-/// Derive encrypted symmetric key for a specific message
+/// Start a new key epoch for an encrypted channel. Messages sent afterwards
+/// use keys from the new epoch; earlier messages keep theirs. Only the channel
+/// creator can rotate keys.
 #[ic_cdk::update]
-pub async fn encrypted_symmetric_key_for_message(message_id: u64) -> Result<Vec<u8>, String> {
+pub fn rotate_channel_key(channel_id: u64) -> Result<Channel, ChatError> {
     let caller = msg_caller();
-    let current_time = time();
     
-    // Check if the caller is authorized to access this message with channel membership
-    let authorized = state::with_encrypted_messages(|messages| {
+    state::with_channels_mut(|channels| {
+        let mut channel = channels.get(&channel_id).ok_or(ChatError::ChannelNotFound)?;
+        if channel.created_by != caller {
+            return Err(ChatError::NotAuthorized);
+        }
+        if !channel.is_encrypted {
+            return Err(ChatError::InvalidInput);
+        }
+        
+        channel.key_epoch = Some(channel.key_epoch().checked_add(1).ok_or(ChatError::InvalidInput)?);
+        channels.insert(channel_id, channel.clone());
+        Ok(channel)
+    })
+}
+
+/// Derive the key of a specific message, encrypted to the caller's transport
+/// public key. The key is optional only so that callers built before it was
+/// added still decode; without one no key is derived.
+#[ic_cdk::update]
+pub async fn encrypted_symmetric_key_for_message(
+    message_id: u64,
+    transport_public_key: Option<Vec<u8>>,
+) -> Result<Vec<u8>, String> {
+    let transport_public_key = transport_public_key.ok_or("Missing transport public key")?;
+    message_key(&IcManagementCanister, &msg_caller(), message_id, transport_public_key, time()).await
+}
+
+async fn message_key(
+    management: &impl ManagementCanister,
+    caller: &Principal,
+    message_id: u64,
+    transport_public_key: Vec<u8>,
+    current_time: u64,
+) -> Result<Vec<u8>, String> {
+    if !can_read_encrypted_message(caller, message_id, current_time) {
+        return Err("Not authorized to access this message".to_string());
+    }
+    vetkd::check_transport_public_key(&transport_public_key)?;
+    
+    derive_message_key(management, message_id, transport_public_key).await
+}
+
+/// Derive the key an encrypted attachment was encrypted with. This is the key
+/// of the message it belongs to, under the same authorization, and the
+/// transport key is optional in the same way.
+#[ic_cdk::update]
+pub async fn encrypted_symmetric_key_for_attachment(
+    attachment_id: u64,
+    transport_public_key: Option<Vec<u8>>,
+) -> Result<Vec<u8>, String> {
+    let transport_public_key = transport_public_key.ok_or("Missing transport public key")?;
+    attachment_key(&IcManagementCanister, &msg_caller(), attachment_id, transport_public_key, time()).await
+}
+
+async fn attachment_key(
+    management: &impl ManagementCanister,
+    caller: &Principal,
+    attachment_id: u64,
+    transport_public_key: Vec<u8>,
+    current_time: u64,
+) -> Result<Vec<u8>, String> {
+    let message_id = state::with_attachments(|attachments| attachments.get(&attachment_id))
+        .filter(|record| record.encrypted)
+        .and_then(|record| record.message_id)
+        .ok_or("Attachment not found")?;
+    
+    message_key(management, caller, message_id, transport_public_key, current_time).await
+}
+
+/// Check if the caller is authorized to access a message with channel membership
+fn can_read_encrypted_message(user: &Principal, message_id: u64, current_time: u64) -> bool {
+    state::with_encrypted_messages(|messages| {
         messages.get(&message_id)
             .map(|msg| {
                 !msg.is_expired(current_time) &&
-                msg.is_authorized_with_channels(&caller, |channel_id| {
+                msg.is_authorized_with_channels(user, |channel_id| {
                     state::with_channels(|channels| channels.get(&channel_id))
                 })
             })
             .unwrap_or(false)
-    });
-    
-    if !authorized {
-        return Err("Not authorized to access this message".to_string());
+    })
+}
+
+/// Derive a message key in the context of its channel epoch, or of its author
+/// for messages outside channels. Messages stored without a key epoch keep
+/// the per-message context their keys were first derived in.
+async fn derive_message_key(
+    management: &impl ManagementCanister,
+    message_id: u64,
+    transport_public_key: Vec<u8>,
+) -> Result<Vec<u8>, String> {
+    let message = state::with_encrypted_messages(|messages| messages.get(&message_id))
+        .ok_or("Message not found")?;
+    if message.ibe_recipient.is_some() {
+        return Err("Message is encrypted to its recipient's identity key".to_string());
     }
     
-    let request = VetKDDeriveKeyArgs {
-        context: format!("message_{}", message_id).as_bytes().to_vec(),
-        input: vec![],
-        key_id: key_id(),
-        transport_public_key: vec![],
-    };
-
-    let (response,): (VetKDDeriveKeyResult,) = call(
-        Principal::management_canister(),
-        "vetkd_derive_key",
-        (request,),
-    )
-    .await
-    .map_err(|e| format!("Failed to derive VetKD key: {:?}", e))?;
-
-    Ok(response.encrypted_key)
+    let context = KeyContext::for_message(&message);
+    let input = context.message_input(message_id);
+    vetkd::derive_key(management, &context, input, transport_public_key).await
 }
 
 /// Helper function to encrypt message content using VetKD-derived key
@@ -1036,7 +2783,7 @@ async fn encrypt_message_content(plain_content: &str, message_id: u64) -> Result
     
     // For now, we'll prefix with "[ENCRYPTED]" to show it's encrypted and base64 encode
     let encrypted_data = format!("[VET_ENCRYPTED:{}]{}", message_id, plain_content);
-    Ok(base64::encode(encrypted_data.as_bytes()))
+    Ok(BASE64.encode(encrypted_data.as_bytes()))
 }
 
 /// Helper function to decrypt message content using VetKD-derived key
@@ -1044,7 +2791,7 @@ async fn decrypt_message_content(encrypted_content: &str, message_id: u64) -> Re
     // Handle backward compatibility - some messages may be stored as plain text
     // First try to decode as base64, if it fails, treat as legacy plain text
     
-    match base64::decode(encrypted_content) {
+    match BASE64.decode(encrypted_content) {
         Ok(decoded_bytes) => {
             // Successfully decoded as base64, now check if it's our VET_ENCRYPTED format
             let decoded_str = String::from_utf8(decoded_bytes)
@@ -1071,92 +2818,87 @@ async fn decrypt_message_content(encrypted_content: &str, message_id: u64) -> Re
 /// This function uses VetKD to verify authorization and decrypt content
 #[ic_cdk::update]
 pub async fn decrypt_encrypted_message(message_id: u64) -> Result<String, String> {
-let caller = caller();
-    let current_time = time();
-    
+    decrypt_message(&msg_caller(), message_id, time()).await
+}
+
+async fn decrypt_message(caller: &Principal, message_id: u64, current_time: u64) -> Result<String, String> {
     // Get the encrypted message and verify authorization with channel membership
     let encrypted_message = state::with_encrypted_messages(|messages| {
         messages.get(&message_id).filter(|msg| {
             !msg.is_expired(current_time) && 
-            msg.is_authorized_with_channels(&caller, |channel_id| {
+            msg.is_authorized_with_channels(caller, |channel_id| {
                 state::with_channels(|channels| channels.get(&channel_id))
             })
         })
     });
     
     let message = encrypted_message.ok_or("Message not found or not authorized")?;
+    if message.ibe_recipient.is_some() {
+        return Err("Message is encrypted to its recipient's identity key".to_string());
+    }
     
     // Decrypt the content using VetKD-derived key
     decrypt_message_content(&message.encrypted_content, message_id).await
 }
 
-/// Get encrypted messages from a specific channel (returns encrypted content)
+/// Get the newest 100 encrypted messages of a channel the caller is a member
+/// of, or none for non-members. Kept for clients built against the original
+/// interface; use `get_channel_encrypted_message_page` to read further back.
 #[ic_cdk::query]
-pub fn get_encrypted_messages_from_channel(channel_id: u64) -> Vec<EncryptedMessage> {
-let caller = caller();
-    let current_time = time();
-    
-    // Check if channel exists and is encrypted
-    let channel_exists = state::with_channels(|channels| {
-        channels.get(&channel_id)
-            .map(|channel| channel.is_encrypted)
-            .unwrap_or(false)
-    });
-    
-    if !channel_exists {
-        return vec![];
+pub fn get_encrypted_messages_from_channel(channel_id: u64) -> Vec<LegacyEncryptedMessage> {
+    channel_encrypted_messages(&msg_caller(), channel_id, time(), None, Some(100))
+        .map(|page| page.messages.into_iter().map(Into::into).collect())
+        .unwrap_or_default()
+}
+
+/// Get a page of encrypted messages from a channel the caller is a member of,
+/// newest first, starting below the message ID `before` (returns encrypted
+/// content)
+#[ic_cdk::query]
+pub fn get_channel_encrypted_message_page(
+    channel_id: u64,
+    before: Option<u64>,
+    limit: Option<u64>,
+) -> Result<EncryptedMessagePage, ChatError> {
+    channel_encrypted_messages(&msg_caller(), channel_id, time(), before, limit)
+}
+
+fn channel_encrypted_messages(
+    caller: &Principal,
+    channel_id: u64,
+    current_time: u64,
+    before: Option<u64>,
+    limit: Option<u64>,
+) -> Result<EncryptedMessagePage, ChatError> {
+    let channel = state::with_channels(|channels| channels.get(&channel_id))
+        .filter(|channel| channel.is_encrypted)
+        .ok_or(ChatError::ChannelNotFound)?;
+    if !channel.members.contains(caller) {
+        return Err(ChatError::NotAuthorized);
     }
     
-    // Return encrypted messages for everyone to see (as ciphertext)
-    // Authorization is only required for decryption
-    
-    // Get all encrypted messages for this channel
-    state::with_encrypted_messages(|messages| {
-        messages.iter()
-            .filter_map(|(_, message)| {
-                if message.channel_id == Some(channel_id) && !message.is_expired(current_time) {
-                    Some(message)
-                } else {
-                    None
-                }
-            })
-            .collect()
-    })
+    Ok(encrypted_message_page(
+        before,
+        limit,
+        |before, count| state::encrypted_channel_message_ids(channel_id, before, count),
+        |message| !message.is_expired(current_time),
+    ))
 }
 
-/// Decrypt all messages from a channel that the user has access to
+/// Decrypt a page of messages from a channel that the user has access to,
+/// newest first, starting below the message ID `before`. Pass the last
+/// returned ID as `before` to continue.
 #[ic_cdk::update]
-pub async fn decrypt_all_messages_from_channel(channel_id: u64) -> Vec<MessageWithAuthor> {
-let caller = caller();
-    let current_time = time();
-    
-    // Verify caller is member of the channel
-    let is_member = state::with_channels(|channels| {
-        channels.get(&channel_id)
-            .map(|channel| channel.members.contains(&caller) && channel.is_encrypted)
-            .unwrap_or(false)
-    });
-    
-    if !is_member {
+pub async fn decrypt_all_messages_from_channel(
+    channel_id: u64,
+    before: Option<u64>,
+    limit: Option<u64>,
+) -> Vec<MessageWithAuthor> {
+    // Non-members get nothing
+    let Ok(page) = channel_encrypted_messages(&msg_caller(), channel_id, time(), before, limit) else {
         return vec![];
-    }
-    
-    // Get all encrypted messages for this channel
-    let encrypted_messages = state::with_encrypted_messages(|messages| {
-        messages.iter()
-            .filter_map(|(_, message)| {
-                if message.channel_id == Some(channel_id) 
-                    && !message.is_expired(current_time) 
-                    && message.is_authorized_with_channels(&caller, |channel_id| {
-                        state::with_channels(|channels| channels.get(&channel_id))
-                    }) {
-                    Some(message)
-                } else {
-                    None
-                }
-            })
-            .collect::<Vec<_>>()
-    });
+    };
+    let encrypted_messages = page.messages;
     
     // Decrypt each message
     let mut decrypted_messages = Vec::new();
@@ -1189,7 +2931,11 @@ let caller = caller();
     }
     
     // Sort by timestamp (newest first)
-    decrypted_messages.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+    decrypted_messages.sort_by_key(|m| std::cmp::Reverse(m.timestamp));
     
     decrypted_messages
 }
+
+// Generates `__export_service`, the Candid interface of every endpoint above.
+// `chat_z_backend.did` is checked against it in `candid_tests`.
+candid::export_service!();
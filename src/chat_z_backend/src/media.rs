@@ -0,0 +1,189 @@
+// Media sniffing helpers used to validate uploaded files.
+// Types are detected from magic bytes, never from client-supplied metadata.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageInfo {
+    pub mime_type: &'static str,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Detect the MIME type of an image from its leading bytes
+pub fn sniff_image_type(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("image/jpeg")
+    } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else {
+        None
+    }
+}
+
+/// MIME types accepted for attachments
+pub const ALLOWED_ATTACHMENT_TYPES: &[&str] = &[
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "application/pdf",
+    "audio/mpeg",
+    "audio/ogg",
+    "audio/wav",
+    "video/mp4",
+    "video/webm",
+    "text/plain",
+];
+
+/// Detect the MIME type of a file from its leading bytes. Anything that is not
+/// a recognised binary format but is valid UTF-8 without NUL bytes is treated
+/// as plain text.
+pub fn sniff_mime_type(data: &[u8]) -> Option<&'static str> {
+    if let Some(image_type) = sniff_image_type(data) {
+        return Some(image_type);
+    }
+
+    if data.starts_with(b"%PDF-") {
+        Some("application/pdf")
+    } else if data.starts_with(b"ID3") || (data.len() >= 2 && data[0] == 0xFF && data[1] & 0xE0 == 0xE0) {
+        Some("audio/mpeg")
+    } else if data.starts_with(b"OggS") {
+        Some("audio/ogg")
+    } else if data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WAVE" {
+        Some("audio/wav")
+    } else if data.len() >= 8 && &data[4..8] == b"ftyp" {
+        Some("video/mp4")
+    } else if data.starts_with(&[0x1A, 0x45, 0xDF, 0xA3]) {
+        Some("video/webm")
+    } else if !data.contains(&0) && std::str::from_utf8(data).is_ok() {
+        Some("text/plain")
+    } else {
+        None
+    }
+}
+
+/// Reduce a client-supplied filename to a safe display name: directory
+/// components, control characters and characters reserved on common
+/// filesystems are removed, and the result is capped at 255 bytes.
+pub fn sanitize_filename(filename: &str) -> Option<String> {
+    let base = filename.rsplit(['/', '\\']).next().unwrap_or("");
+    let cleaned: String = base
+        .chars()
+        .filter(|c| !c.is_control() && !matches!(c, '<' | '>' | ':' | '"' | '|' | '?' | '*'))
+        .collect();
+    let trimmed = cleaned.trim().trim_matches('.').trim();
+
+    if trimmed.is_empty() {
+        return None;
+    }
+
+    let mut end = trimmed.len().min(255);
+    while !trimmed.is_char_boundary(end) {
+        end -= 1;
+    }
+    Some(trimmed[..end].to_string())
+}
+
+/// Detect the type and pixel dimensions of a PNG, JPEG, GIF or WebP image
+pub fn image_info(data: &[u8]) -> Option<ImageInfo> {
+    let mime_type = sniff_image_type(data)?;
+    let (width, height) = match mime_type {
+        "image/png" => png_dimensions(data)?,
+        "image/jpeg" => jpeg_dimensions(data)?,
+        "image/gif" => gif_dimensions(data)?,
+        "image/webp" => webp_dimensions(data)?,
+        _ => return None,
+    };
+    Some(ImageInfo { mime_type, width, height })
+}
+
+const BLURHASH_CHARACTERS: &[u8] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+/// Check that a string is a well-formed BlurHash: base83 characters only, with
+/// a length matching the component counts encoded in its first character.
+pub fn is_valid_blurhash(hash: &str) -> bool {
+    let bytes = hash.as_bytes();
+    if bytes.len() < 6 || !bytes.iter().all(|b| BLURHASH_CHARACTERS.contains(b)) {
+        return false;
+    }
+
+    let size_flag = BLURHASH_CHARACTERS.iter().position(|c| *c == bytes[0]).unwrap_or(0);
+    let components_x = size_flag % 9 + 1;
+    let components_y = size_flag / 9 + 1;
+    size_flag < 81 && bytes.len() == 4 + 2 * components_x * components_y
+}
+
+fn be_u16(data: &[u8], at: usize) -> Option<u32> {
+    let bytes = data.get(at..at + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]) as u32)
+}
+
+fn le_u16(data: &[u8], at: usize) -> Option<u32> {
+    let bytes = data.get(at..at + 2)?;
+    Some(u16::from_le_bytes([bytes[0], bytes[1]]) as u32)
+}
+
+fn be_u32(data: &[u8], at: usize) -> Option<u32> {
+    let bytes = data.get(at..at + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn le_u24(data: &[u8], at: usize) -> Option<u32> {
+    let bytes = data.get(at..at + 3)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]))
+}
+
+fn png_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    // The IHDR chunk always comes first, right after the 8-byte signature
+    if data.get(12..16)? != b"IHDR" {
+        return None;
+    }
+    Some((be_u32(data, 16)?, be_u32(data, 20)?))
+}
+
+fn gif_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    Some((le_u16(data, 6)?, le_u16(data, 8)?))
+}
+
+fn jpeg_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    // Walk the marker segments until a start-of-frame marker is found
+    let mut i = 2;
+    loop {
+        if *data.get(i)? != 0xFF {
+            return None;
+        }
+        let marker = *data.get(i + 1)?;
+        match marker {
+            // Fill bytes
+            0xFF => i += 1,
+            // Standalone markers without a length
+            0x01 | 0xD0..=0xD7 => i += 2,
+            // Start of frame (excluding DHT, JPG and DAC which share the range)
+            0xC0..=0xCF if marker != 0xC4 && marker != 0xC8 && marker != 0xCC => {
+                return Some((be_u16(data, i + 7)?, be_u16(data, i + 5)?));
+            }
+            // Start of scan or end of image before any frame header
+            0xDA | 0xD9 => return None,
+            _ => i += 2 + be_u16(data, i + 2)? as usize,
+        }
+    }
+}
+
+fn webp_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    match data.get(12..16)? {
+        b"VP8 " => Some((le_u16(data, 26)? & 0x3FFF, le_u16(data, 28)? & 0x3FFF)),
+        b"VP8L" => {
+            let b = data.get(21..25)?;
+            let (b0, b1, b2, b3) = (b[0] as u32, b[1] as u32, b[2] as u32, b[3] as u32);
+            let width = 1 + (((b1 & 0x3F) << 8) | b0);
+            let height = 1 + (((b3 & 0x0F) << 10) | (b2 << 2) | ((b1 & 0xC0) >> 6));
+            Some((width, height))
+        }
+        b"VP8X" => Some((1 + le_u24(data, 24)?, 1 + le_u24(data, 27)?)),
+        _ => None,
+    }
+}
@@ -0,0 +1,215 @@
+use candid::{CandidType, Principal};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::fmt;
+
+use crate::state::{
+    AttachmentRecord, Avatar, Channel, EncryptedMessage, Message, MessageIds, MessageType,
+    PrincipalList, UpgradeRecord, UploadSession, User,
+};
+
+// Versioned stored representations. Every value in stable memory is written as
+// a one-byte schema version followed by its Candid encoding. Values written
+// before versioning was introduced are bare Candid (starting with the "DIDL"
+// magic) and are treated as version 0.
+//
+// When a stored type changes in a way Candid cannot absorb (a new required
+// field, a changed field type), bump its VERSION, keep the previous shape below
+// as a legacy struct and convert it in `migrate`.
+
+const CANDID_MAGIC: &[u8] = b"DIDL";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeError {
+    Empty,
+    UnknownVersion(u8),
+    Candid(String),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Empty => write!(f, "empty value"),
+            DecodeError::UnknownVersion(version) => write!(f, "unknown schema version {}", version),
+            DecodeError::Candid(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+pub trait Versioned: CandidType + DeserializeOwned {
+    const TYPE_NAME: &'static str;
+    const VERSION: u8 = 1;
+
+    /// Convert a value stored by an older version. `payload` is its Candid
+    /// encoding. Types whose changes so far were all Candid-compatible decode
+    /// every older version as the current shape.
+    fn migrate(version: u8, payload: &[u8]) -> Result<Self, DecodeError> {
+        if version < Self::VERSION {
+            decode_candid(payload)
+        } else {
+            Err(DecodeError::UnknownVersion(version))
+        }
+    }
+}
+
+fn decode_candid<T: DeserializeOwned + CandidType>(payload: &[u8]) -> Result<T, DecodeError> {
+    candid::decode_one(payload).map_err(|e| DecodeError::Candid(e.to_string()))
+}
+
+pub fn encode<T: Versioned>(value: &T) -> Vec<u8> {
+    let mut bytes = vec![T::VERSION];
+    bytes.extend(candid::encode_one(value).expect("stored values are always Candid-encodable"));
+    bytes
+}
+
+pub fn decode<T: Versioned>(bytes: &[u8]) -> Result<T, DecodeError> {
+    let (version, payload) = if bytes.starts_with(CANDID_MAGIC) {
+        (0, bytes)
+    } else {
+        let (version, payload) = bytes.split_first().ok_or(DecodeError::Empty)?;
+        (*version, payload)
+    };
+
+    if version == T::VERSION {
+        decode_candid(payload)
+    } else {
+        T::migrate(version, payload)
+    }
+}
+
+/// Decode a value read from a stable cell. `Storable::from_bytes` cannot return
+/// an error, so failures trap with the type and reason; the call is rolled back
+/// and, during `post_upgrade`, so is the upgrade. Map values are decoded on
+/// access instead (see `state::Records`).
+pub fn decode_or_trap<T: Versioned>(bytes: &[u8]) -> T {
+    decode(bytes).unwrap_or_else(|e| {
+        ic_cdk::trap(format!("Failed to decode stored {}: {}", T::TYPE_NAME, e))
+    })
+}
+
+// Legacy shapes
+
+/// Attachments before chunked uploads (version 0) carried their bytes inline
+/// and had no ID. The original client never sent any, so they are dropped
+/// rather than given an ID in the blob store.
+#[derive(CandidType, Deserialize)]
+pub struct AttachmentV0 {
+    pub file_type: String,
+    pub data: Vec<u8>,
+    pub filename: String,
+    pub size: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct MessageV0 {
+    pub id: u64,
+    pub author: Principal,
+    pub content: String,
+    pub timestamp: u64,
+    pub channel_id: Option<u64>,
+    pub reply_to: Option<u64>,
+    pub message_type: MessageType,
+    pub attachments: Vec<AttachmentV0>,
+}
+
+impl From<MessageV0> for Message {
+    fn from(message: MessageV0) -> Self {
+        Message {
+            id: message.id,
+            author: message.author,
+            content: message.content,
+            timestamp: message.timestamp,
+            channel_id: message.channel_id,
+            reply_to: message.reply_to,
+            message_type: message.message_type,
+            attachments: vec![],
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct EncryptedMessageV0 {
+    pub id: u64,
+    pub encrypted_content: String,
+    pub author: Principal,
+    pub timestamp: u64,
+    pub expires_at: u64,
+    pub channel_id: Option<u64>,
+    pub reply_to: Option<u64>,
+    pub message_type: MessageType,
+    pub shared_with: Vec<Principal>,
+    pub attachments: Vec<AttachmentV0>,
+}
+
+impl From<EncryptedMessageV0> for EncryptedMessage {
+    fn from(message: EncryptedMessageV0) -> Self {
+        EncryptedMessage {
+            id: message.id,
+            encrypted_content: message.encrypted_content,
+            author: message.author,
+            timestamp: message.timestamp,
+            expires_at: Some(message.expires_at),
+            channel_id: message.channel_id,
+            reply_to: message.reply_to,
+            message_type: message.message_type,
+            shared_with: message.shared_with,
+            attachments: vec![],
+            key_epoch: None,
+            ibe_recipient: None,
+        }
+    }
+}
+
+impl Versioned for Message {
+    const TYPE_NAME: &'static str = "Message";
+
+    fn migrate(version: u8, payload: &[u8]) -> Result<Self, DecodeError> {
+        match version {
+            0 => decode_candid::<MessageV0>(payload).map(Into::into),
+            _ => Err(DecodeError::UnknownVersion(version)),
+        }
+    }
+}
+
+impl Versioned for EncryptedMessage {
+    const TYPE_NAME: &'static str = "EncryptedMessage";
+
+    fn migrate(version: u8, payload: &[u8]) -> Result<Self, DecodeError> {
+        match version {
+            0 => decode_candid::<EncryptedMessageV0>(payload).map(Into::into),
+            _ => Err(DecodeError::UnknownVersion(version)),
+        }
+    }
+}
+
+impl Versioned for User {
+    const TYPE_NAME: &'static str = "User";
+}
+
+impl Versioned for Channel {
+    const TYPE_NAME: &'static str = "Channel";
+}
+
+impl Versioned for MessageIds {
+    const TYPE_NAME: &'static str = "MessageIds";
+}
+
+impl Versioned for PrincipalList {
+    const TYPE_NAME: &'static str = "PrincipalList";
+}
+
+impl Versioned for Avatar {
+    const TYPE_NAME: &'static str = "Avatar";
+}
+
+impl Versioned for AttachmentRecord {
+    const TYPE_NAME: &'static str = "AttachmentRecord";
+}
+
+impl Versioned for UpgradeRecord {
+    const TYPE_NAME: &'static str = "UpgradeRecord";
+}
+
+impl Versioned for UploadSession {
+    const TYPE_NAME: &'static str = "UploadSession";
+}
@@ -1,14 +1,22 @@
 use candid::{CandidType, Principal};
+use ic_cdk_timers::TimerId;
+use ic_certified_map::{Hash, RbTree};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::{Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
 use ic_stable_structures::storable::Bound;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::RangeBounds;
+
+use crate::schema::{self, DecodeError, Versioned};
 
 // Memory management
-type Memory = VirtualMemory<DefaultMemoryImpl>;
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
 
 // Message types
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
@@ -30,12 +38,111 @@ pub enum MessageType {
     System,
 }
 
+/// Attachment metadata carried by messages. The bytes live in the blob store
+/// and are fetched separately by attachment ID.
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct Attachment {
+    pub id: u64,
     pub file_type: String,
+    pub filename: String,
+    pub size: u64,
+    /// Always empty. Kept so that clients built when attachments carried their
+    /// bytes inline can still decode messages.
     pub data: Vec<u8>,
+    pub preview: Option<ImagePreview>,
+    /// Filename and type encrypted by the client with the message key. Set
+    /// only for attachments of encrypted messages, whose `filename` is empty.
+    pub encrypted_metadata: Option<Vec<u8>>,
+}
+
+/// Dimensions and placeholders for image attachments, small enough to be
+/// returned with every message so clients only fetch originals on demand
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ImagePreview {
+    pub width: u32,
+    pub height: u32,
+    pub blurhash: Option<String>,
+    pub thumbnail: Option<Vec<u8>>,
+}
+
+/// A committed upload. Each record is bound to at most one message; the bytes
+/// are stored once per content hash in the blob store.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct AttachmentRecord {
+    pub id: u64,
+    pub owner: Principal,
+    pub file_type: String,
     pub filename: String,
     pub size: u64,
+    #[serde(with = "serde_bytes")]
+    pub sha256: Vec<u8>,
+    pub created_at: u64,
+    pub message_id: Option<u64>,
+    pub channel_id: Option<u64>,
+    pub encrypted: bool,
+    pub encrypted_metadata: Option<Vec<u8>>,
+    pub preview: Option<ImagePreview>,
+}
+
+impl AttachmentRecord {
+    pub fn metadata(&self) -> Attachment {
+        Attachment {
+            id: self.id,
+            file_type: self.file_type.clone(),
+            filename: self.filename.clone(),
+            size: self.size,
+            data: vec![],
+            preview: self.preview.clone(),
+            encrypted_metadata: self.encrypted_metadata.clone(),
+        }
+    }
+    
+    /// Who besides the uploader may read the attachment. This is the single
+    /// access rule for both Candid endpoints and HTTP, so a URL without a
+    /// token is only ever served for `Anyone`.
+    pub fn readers(&self) -> AttachmentReaders {
+        let Some(message_id) = self.message_id else {
+            return AttachmentReaders::UploaderOnly;
+        };
+        if self.encrypted {
+            return AttachmentReaders::EncryptedMessage(message_id);
+        }
+        
+        // Messages outside channels are listed to everyone by get_messages
+        let Some(channel_id) = self.channel_id else {
+            return AttachmentReaders::Anyone;
+        };
+        match with_channels(|channels| channels.get(&channel_id)) {
+            Some(channel) if channel.is_public() => AttachmentReaders::Anyone,
+            Some(_) => AttachmentReaders::ChannelMembers(channel_id),
+            None => AttachmentReaders::UploaderOnly,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AttachmentReaders {
+    /// Not sent with a message yet, or its channel is gone
+    UploaderOnly,
+    Anyone,
+    ChannelMembers(u64),
+    /// Whoever can read the encrypted message
+    EncryptedMessage(u64),
+}
+
+/// An in-progress chunked upload
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct UploadSession {
+    pub id: u64,
+    pub owner: Principal,
+    pub file_type: String,
+    pub filename: String,
+    pub total_size: u64,
+    pub received_size: u64,
+    pub created_at: u64,
+    /// Encrypted message the ciphertext was encrypted for, if any
+    pub encrypted_message_id: Option<u64>,
+    pub encrypted_metadata: Option<Vec<u8>>,
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
@@ -47,7 +154,8 @@ pub struct User {
     pub joined_at: u64,
     pub message_count: u64,
     pub last_active: u64,
-    /// Encrypted keys for VetKey-derived encryption keys, keyed by context or message ID
+    /// Never written and always empty; kept in the interface for
+    /// compatibility. Use the key vault endpoints instead.
     pub encrypted_keys: HashMap<String, String>,
 }
 
@@ -63,6 +171,58 @@ pub struct Channel {
     pub last_message_at: Option<u64>,
     pub is_encrypted: bool,
     pub password_hash: Option<String>, // Hashed password for protected channels
+    /// Default and maximum lifetime of encrypted messages. Unset means one day.
+    pub message_expiry: Option<MessageExpiry>,
+    /// Opt-in retention for regular messages, enforced by the cleanup timer
+    pub retention: Option<RetentionPolicy>,
+    /// Current VetKD key epoch of an encrypted channel. Unset means epoch 0.
+    pub key_epoch: Option<u32>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum RetentionPolicy {
+    /// Delete messages older than this many days
+    MaxAgeDays(u32),
+    /// Keep only this many of the most recent messages
+    MaxMessages(u64),
+}
+
+/// How long encrypted messages in a channel are kept
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum MessageExpiry {
+    OneHour,
+    OneDay,
+    SevenDays,
+    Never,
+}
+
+impl MessageExpiry {
+    /// Lifetime in nanoseconds, or None for messages that never expire
+    pub fn duration_ns(&self) -> Option<u64> {
+        const HOUR_NS: u64 = 60 * 60 * 1_000_000_000;
+        match self {
+            MessageExpiry::OneHour => Some(HOUR_NS),
+            MessageExpiry::OneDay => Some(24 * HOUR_NS),
+            MessageExpiry::SevenDays => Some(7 * 24 * HOUR_NS),
+            MessageExpiry::Never => None,
+        }
+    }
+}
+
+impl Channel {
+    pub fn message_expiry(&self) -> MessageExpiry {
+        self.message_expiry.unwrap_or(MessageExpiry::OneDay)
+    }
+    
+    pub fn key_epoch(&self) -> u32 {
+        self.key_epoch.unwrap_or(0)
+    }
+    
+    /// Public channels can be read without joining: they are neither encrypted
+    /// nor password protected
+    pub fn is_public(&self) -> bool {
+        !self.is_encrypted && self.password_hash.is_none()
+    }
 }
 
 // Encrypted message structure for VetKeys-based encryption
@@ -72,13 +232,21 @@ pub struct EncryptedMessage {
     pub encrypted_content: String,
     pub author: Principal,
     pub timestamp: u64,
-    pub expires_at: u64, // 24 hours from creation
+    /// None for messages that never expire
+    pub expires_at: Option<u64>,
     pub channel_id: Option<u64>,
     pub reply_to: Option<u64>,
     pub message_type: MessageType,
     /// Principals with whom this encrypted message is shared. Does not include the owner.
     pub shared_with: Vec<Principal>,
     pub attachments: Vec<Attachment>,
+    /// Channel key epoch the message was encrypted under, 0 outside channels.
+    /// Unset for IBE messages and for messages stored before epochs were
+    /// recorded, whose keys are derived in a per-message context.
+    pub key_epoch: Option<u32>,
+    /// Set when the sender encrypted the content to this principal's IBE
+    /// identity key. The canister holds no key for such messages.
+    pub ibe_recipient: Option<Principal>,
 }
 
 impl EncryptedMessage {
@@ -114,11 +282,48 @@ impl EncryptedMessage {
     }
 
     pub fn is_expired(&self, current_time: u64) -> bool {
-        current_time > self.expires_at
+        self.expires_at.is_some_and(|expires_at| current_time > expires_at)
     }
 }
 
-// User IDs for encrypted message sharing
+/// Longest context a vault key can be stored under, in bytes
+pub const MAX_KEY_CONTEXT_BYTES: usize = 128;
+
+/// Key of a vault entry. Tuples with a `String` cannot key a stable map, so
+/// the owner and context are encoded as the principal's length, the principal
+/// and the context.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct VaultKey {
+    pub user: Principal,
+    pub context: String,
+}
+
+impl Storable for VaultKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let user = self.user.as_slice();
+        let mut bytes = Vec::with_capacity(1 + user.len() + self.context.len());
+        bytes.push(user.len() as u8);
+        bytes.extend_from_slice(user);
+        bytes.extend_from_slice(self.context.as_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (user, context) = bytes[1..].split_at(bytes[0] as usize);
+        VaultKey {
+            user: Principal::from_slice(user),
+            context: String::from_utf8(context.to_vec()).expect("vault contexts are always UTF-8"),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 1 + Principal::MAX_LENGTH_IN_BYTES as u32 + MAX_KEY_CONTEXT_BYTES as u32,
+        is_fixed_size: false,
+    };
+}
+
+// Per-user encrypted message IDs as stored before the owner and share indexes
+// were keyed by (principal, message_id). Only read by the migration.
 #[derive(CandidType, Deserialize, Default)]
 pub struct MessageIds {
     pub ids: Vec<u64>,
@@ -130,62 +335,178 @@ impl MessageIds {
     }
 }
 
-// Storable implementations using Candid encoding
-impl Storable for Message {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(candid::encode_one(self).unwrap())
+/// Bookkeeping for upgrades. `migration_version` is the number of one-shot
+/// data migrations that have been applied.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct UpgradeRecord {
+    /// Number of data migrations that have finished
+    pub migration_version: u32,
+    /// Where the migration in progress stopped, if it has started
+    pub migration_cursor: Option<Vec<u8>>,
+    pub last_upgraded_at: Option<u64>,
+    pub upgrade_count: u64,
+}
+
+// Avatar image uploaded by a user and served from the canister's HTTP interface
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Avatar {
+    pub content_type: String,
+    #[serde(with = "serde_bytes")]
+    pub data: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+    pub uploaded_at: u64,
+}
+
+// Principals a user has blocked or muted
+#[derive(CandidType, Deserialize, Default)]
+pub struct PrincipalList {
+    pub principals: Vec<Principal>,
+}
+
+impl PrincipalList {
+    pub fn contains(&self, principal: &Principal) -> bool {
+        self.principals.contains(principal)
     }
+}
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        candid::decode_one(&bytes).unwrap()
+// Versioned records in stable maps (see schema.rs for the encoding)
+
+/// The stored encoding of a `T`, decoded only when read
+pub struct Stored<T> {
+    bytes: Vec<u8>,
+    _type: PhantomData<T>,
+}
+
+impl<T: Versioned> Stored<T> {
+    fn encode(value: &T) -> Self {
+        Stored { bytes: schema::encode(value), _type: PhantomData }
     }
 
-    const BOUND: Bound = Bound::Unbounded;
+    fn decode(&self) -> Result<T, DecodeError> {
+        schema::decode(&self.bytes)
+    }
 }
 
-impl Storable for User {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(candid::encode_one(self).unwrap())
+impl<T> Storable for Stored<T> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.bytes)
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        candid::decode_one(&bytes).unwrap()
+        Stored { bytes: bytes.into_owned(), _type: PhantomData }
     }
 
     const BOUND: Bound = Bound::Unbounded;
 }
 
-impl Storable for Channel {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(candid::encode_one(self).unwrap())
+/// A stable map of versioned records. `Storable::from_bytes` cannot fail, so
+/// values are kept encoded and decoded on access: `try_get` reports a record
+/// the running code cannot read, and the other accessors log it and treat it
+/// as absent instead of trapping the call.
+pub struct Records<K: Storable + Ord + Clone, V> {
+    map: StableBTreeMap<K, Stored<V>, Memory>,
+}
+
+impl<K, V> Records<K, V>
+where
+    K: Storable + Ord + Clone + fmt::Debug,
+    V: Versioned,
+{
+    fn init(memory: Memory) -> Self {
+        Records { map: StableBTreeMap::init(memory) }
     }
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        candid::decode_one(&bytes).unwrap()
+    fn readable(key: &K, stored: Stored<V>) -> Option<V> {
+        stored.decode()
+            .map_err(|e| log(format!("Skipping undecodable {} {:?}: {}", V::TYPE_NAME, key, e)))
+            .ok()
     }
 
-    const BOUND: Bound = Bound::Unbounded;
-}
+    pub fn try_get(&self, key: &K) -> Result<Option<V>, DecodeError> {
+        self.map.get(key).map(|stored| stored.decode()).transpose()
+    }
 
-impl Storable for EncryptedMessage {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(candid::encode_one(self).unwrap())
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.try_get(key).unwrap_or_else(|e| {
+            log(format!("Skipping undecodable {} {:?}: {}", V::TYPE_NAME, key, e));
+            None
+        })
     }
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        candid::decode_one(&bytes).unwrap()
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.map.contains_key(key)
     }
 
-    const BOUND: Bound = Bound::Unbounded;
+    pub fn len(&self) -> u64 {
+        self.map.len()
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let previous = self.map.insert(key.clone(), Stored::encode(&value))?;
+        Self::readable(&key, previous)
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        Self::readable(key, self.map.remove(key)?)
+    }
+
+    /// Store already encoded bytes, as an older or newer release would have
+    #[cfg(test)]
+    pub fn insert_encoded(&mut self, key: K, bytes: Vec<u8>) {
+        self.map.insert(key, Stored { bytes, _type: PhantomData });
+    }
+
+    pub fn pop_first(&mut self) -> Option<(K, V)> {
+        while let Some((key, stored)) = self.map.pop_first() {
+            if let Some(value) = Self::readable(&key, stored) {
+                return Some((key, value));
+            }
+        }
+        None
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (K, V)> + '_ {
+        self.map.iter().filter_map(|(key, stored)| Some((key.clone(), Self::readable(&key, stored)?)))
+    }
+
+    pub fn range(&self, key_range: impl RangeBounds<K>) -> impl DoubleEndedIterator<Item = (K, V)> + '_ {
+        self.map.range(key_range).filter_map(|(key, stored)| Some((key.clone(), Self::readable(&key, stored)?)))
+    }
+
+    pub fn keys_range(&self, key_range: impl RangeBounds<K>) -> impl DoubleEndedIterator<Item = K> + '_ {
+        self.map.keys_range(key_range)
+    }
+
+    /// Decode up to `sample` records from each end of the map, where the
+    /// oldest and newest records sit, and return how many were read
+    fn verify_sample(&self, sample: usize) -> Result<u64, String> {
+        let len = self.map.len() as usize;
+        let oldest = self.map.iter().take(sample.min(len));
+        let newest = self.map.iter().rev().take(sample.min(len.saturating_sub(sample)));
+        let mut verified = 0;
+        for (key, stored) in oldest.chain(newest) {
+            stored.decode().map_err(|e| format!("{} {:?}: {}", V::TYPE_NAME, key, e))?;
+            verified += 1;
+        }
+        Ok(verified)
+    }
+}
+
+/// Write to the canister log. Native test builds have no log to write to.
+fn log(message: String) {
+    if cfg!(target_arch = "wasm32") {
+        ic_cdk::api::debug_print(message);
+    }
 }
 
-impl Storable for MessageIds {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(candid::encode_one(self).unwrap())
+impl Storable for UpgradeRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(schema::encode(self))
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        candid::decode_one(&bytes).unwrap()
+        schema::decode_or_trap(&bytes)
     }
 
     const BOUND: Bound = Bound::Unbounded;
@@ -193,23 +514,43 @@ impl Storable for MessageIds {
 
 // Global state
 thread_local! {
+    // Heap-only presence and typing state. These are deliberately not stored in
+    // stable memory: they are high-churn, short-lived and fine to lose on upgrade.
+    
+    // Maps user to the time of their last heartbeat
+    static LAST_SEEN: RefCell<HashMap<Principal, u64>> = RefCell::new(HashMap::new());
+    
+    // Maps channel to typing users and the time their typing state expires
+    static TYPING: RefCell<HashMap<u64, BTreeMap<Principal, u64>>> = RefCell::new(HashMap::new());
+    
+    // VetKD public keys keyed by derivation context, fetched from the
+    // management canister on first use
+    static PUBLIC_KEYS: RefCell<HashMap<Vec<u8>, Vec<u8>>> = RefCell::new(HashMap::new());
+    
+    // SHA-256 of every certified HTTP response body, keyed by request path.
+    // Rebuilt from stable state after upgrades.
+    static ASSET_HASHES: RefCell<RbTree<Vec<u8>, Hash>> = const { RefCell::new(RbTree::new()) };
+    
+    // Expiry time the next expiry cleanup timer is set for, and its timer
+    static EXPIRY_TIMER: RefCell<Option<(u64, TimerId)>> = const { RefCell::new(None) };
+    
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
         RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
         
-    static MESSAGES: RefCell<StableBTreeMap<u64, Message, Memory>> = RefCell::new(
-        StableBTreeMap::init(
+    static MESSAGES: RefCell<Records<u64, Message>> = RefCell::new(
+        Records::init(
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))),
         )
     );
     
-    static USERS: RefCell<StableBTreeMap<Principal, User, Memory>> = RefCell::new(
-        StableBTreeMap::init(
+    static USERS: RefCell<Records<Principal, User>> = RefCell::new(
+        Records::init(
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))),
         )
     );
     
-    static CHANNELS: RefCell<StableBTreeMap<u64, Channel, Memory>> = RefCell::new(
-        StableBTreeMap::init(
+    static CHANNELS: RefCell<Records<u64, Channel>> = RefCell::new(
+        Records::init(
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))),
         )
     );
@@ -229,26 +570,40 @@ thread_local! {
     );
     
     // Encrypted messages storage
-    static ENCRYPTED_MESSAGES: RefCell<StableBTreeMap<u64, EncryptedMessage, Memory>> = RefCell::new(
-        StableBTreeMap::init(
+    static ENCRYPTED_MESSAGES: RefCell<Records<u64, EncryptedMessage>> = RefCell::new(
+        Records::init(
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5))),
         )
     );
     
-    // Maps user to owned encrypted message IDs
-    static ENCRYPTED_MESSAGE_OWNERS: RefCell<StableBTreeMap<Principal, MessageIds, Memory>> = RefCell::new(
-        StableBTreeMap::init(
+    // Legacy per-user lists of owned and shared encrypted message IDs, emptied
+    // by migrate_encrypted_message_indexes
+    static LEGACY_ENCRYPTED_MESSAGE_OWNERS: RefCell<Records<Principal, MessageIds>> = RefCell::new(
+        Records::init(
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6))),
         )
     );
     
-    // Maps user to shared encrypted message IDs
-    static ENCRYPTED_MESSAGE_SHARES: RefCell<StableBTreeMap<Principal, MessageIds, Memory>> = RefCell::new(
-        StableBTreeMap::init(
+    static LEGACY_ENCRYPTED_MESSAGE_SHARES: RefCell<Records<Principal, MessageIds>> = RefCell::new(
+        Records::init(
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7))),
         )
     );
     
+    // Encrypted messages owned by each user, keyed (owner, message_id)
+    static ENCRYPTED_MESSAGE_OWNERS: RefCell<StableBTreeMap<(Principal, u64), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27))),
+        )
+    );
+    
+    // Encrypted messages shared with each user, keyed (user, message_id)
+    static ENCRYPTED_MESSAGE_SHARES: RefCell<StableBTreeMap<(Principal, u64), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28))),
+        )
+    );
+    
     // VetKey name for this canister
     static KEY_NAME: RefCell<Cell<String, Memory>> = RefCell::new(
         Cell::init(
@@ -256,62 +611,250 @@ thread_local! {
             String::new()
         ).unwrap()
     );
+    
+    // Maps user to the principals they have blocked
+    static BLOCKED_USERS: RefCell<Records<Principal, PrincipalList>> = RefCell::new(
+        Records::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9))),
+        )
+    );
+    
+    // Maps user to the principals they have muted
+    static MUTED_USERS: RefCell<Records<Principal, PrincipalList>> = RefCell::new(
+        Records::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))),
+        )
+    );
+    
+    // Avatar images, kept apart from User records so user listings stay small
+    static AVATARS: RefCell<Records<Principal, Avatar>> = RefCell::new(
+        Records::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11))),
+        )
+    );
+    
+    // In-progress chunked uploads
+    static UPLOAD_SESSIONS: RefCell<Records<u64, UploadSession>> = RefCell::new(
+        Records::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12))),
+        )
+    );
+    
+    // Chunks of in-progress uploads, keyed by (upload ID, chunk index)
+    static UPLOAD_CHUNKS: RefCell<StableBTreeMap<(u64, u32), Vec<u8>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13))),
+        )
+    );
+    
+    // Attachment bytes keyed by SHA-256 of the content
+    static BLOBS: RefCell<StableBTreeMap<[u8; 32], Vec<u8>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14))),
+        )
+    );
+    
+    // Committed attachments
+    static ATTACHMENTS: RefCell<Records<u64, AttachmentRecord>> = RefCell::new(
+        Records::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15))),
+        )
+    );
+    
+    static NEXT_UPLOAD_ID: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16))),
+            1
+        ).unwrap()
+    );
+    
+    static NEXT_ATTACHMENT_ID: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17))),
+            1
+        ).unwrap()
+    );
+    
+    // Secret used to sign attachment URLs. All zeroes until initialized from raw_rand.
+    static URL_SIGNING_KEY: RefCell<Cell<[u8; 32], Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18))),
+            [0; 32]
+        ).unwrap()
+    );
+    
+    // Attachment bytes charged to each user
+    static STORAGE_USAGE: RefCell<StableBTreeMap<Principal, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19))),
+        )
+    );
+    
+    // Per-user storage quotas that replace the default
+    static STORAGE_QUOTA_OVERRIDES: RefCell<StableBTreeMap<Principal, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20))),
+        )
+    );
+    
+    static DEFAULT_STORAGE_QUOTA: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21))),
+            100_000_000 // 100MB
+        ).unwrap()
+    );
+    
+    // Number of attachment records referencing each blob
+    static BLOB_REFS: RefCell<StableBTreeMap<[u8; 32], u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22))),
+        )
+    );
+    
+    static UPGRADE_RECORD: RefCell<Cell<UpgradeRecord, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23))),
+            UpgradeRecord::default()
+        ).unwrap()
+    );
+    
+    static CLEANUP_INTERVAL_SECS: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24))),
+            3600 // 1 hour
+        ).unwrap()
+    );
+    
+    // Regular messages of each channel in the order they were sent, keyed
+    // (channel_id, message_id)
+    static CHANNEL_MESSAGES: RefCell<StableBTreeMap<(u64, u64), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26))),
+        )
+    );
+    
+    // Encrypted messages ordered by expiry time, keyed (expires_at, message_id)
+    static MESSAGE_EXPIRY: RefCell<StableBTreeMap<(u64, u64), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25))),
+        )
+    );
+    
+    // Upload sessions ordered by age, keyed (created_at, upload_id)
+    static UPLOAD_SESSION_AGES: RefCell<StableBTreeMap<(u64, u64), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29))),
+        )
+    );
+    
+    // Attachments not yet sent with a message, keyed (created_at, attachment_id)
+    static UNATTACHED_ATTACHMENTS: RefCell<StableBTreeMap<(u64, u64), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30))),
+        )
+    );
+    
+    // Hash certified for each dynamic HTTP path and encrypted message, stored
+    // when it is certified so the tree can be rebuilt after an upgrade without
+    // re-reading the content behind it
+    static CERTIFIED_HASHES: RefCell<StableBTreeMap<String, Hash, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32))),
+        )
+    );
+    
+    // Encrypted messages of each channel, keyed (channel_id, message_id)
+    static ENCRYPTED_CHANNEL_MESSAGES: RefCell<StableBTreeMap<(u64, u64), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33))),
+        )
+    );
+    
+    // Wrapped keys users store for themselves, keyed by owner and context
+    static VAULT_KEYS: RefCell<StableBTreeMap<VaultKey, String, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(35))),
+        )
+    );
+    
+    // Encrypted message IDs below this are in ENCRYPTED_CHANNEL_MESSAGES.
+    // Raised by rebuild_encrypted_channel_index; init sets it to u64::MAX.
+    static ENCRYPTED_CHANNELS_INDEXED_BELOW: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34))),
+            0
+        ).unwrap()
+    );
+    
+    // Open upload sessions of each user, keyed (owner, upload_id), with the
+    // bytes each one reserves against the owner's quota
+    static OPEN_UPLOADS: RefCell<StableBTreeMap<(Principal, u64), u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(36))),
+        )
+    );
 }
 
 // State access functions
 pub fn with_messages<F, R>(f: F) -> R
 where
-    F: FnOnce(&StableBTreeMap<u64, Message, Memory>) -> R,
+    F: FnOnce(&Records<u64, Message>) -> R,
 {
     MESSAGES.with(|m| f(&m.borrow()))
 }
 
 pub fn with_messages_mut<F, R>(f: F) -> R
 where
-    F: FnOnce(&mut StableBTreeMap<u64, Message, Memory>) -> R,
+    F: FnOnce(&mut Records<u64, Message>) -> R,
 {
     MESSAGES.with(|m| f(&mut m.borrow_mut()))
 }
 
 pub fn with_users<F, R>(f: F) -> R
 where
-    F: FnOnce(&StableBTreeMap<Principal, User, Memory>) -> R,
+    F: FnOnce(&Records<Principal, User>) -> R,
 {
     USERS.with(|u| f(&u.borrow()))
 }
 
 pub fn with_users_mut<F, R>(f: F) -> R
 where
-    F: FnOnce(&mut StableBTreeMap<Principal, User, Memory>) -> R,
+    F: FnOnce(&mut Records<Principal, User>) -> R,
 {
     USERS.with(|u| f(&mut u.borrow_mut()))
 }
 
 pub fn with_channels<F, R>(f: F) -> R
 where
-    F: FnOnce(&StableBTreeMap<u64, Channel, Memory>) -> R,
+    F: FnOnce(&Records<u64, Channel>) -> R,
 {
     CHANNELS.with(|c| f(&c.borrow()))
 }
 
 pub fn with_channels_mut<F, R>(f: F) -> R
 where
-    F: FnOnce(&mut StableBTreeMap<u64, Channel, Memory>) -> R,
+    F: FnOnce(&mut Records<u64, Channel>) -> R,
 {
     CHANNELS.with(|c| f(&mut c.borrow_mut()))
 }
 
 pub fn next_message_id() -> u64 {
     NEXT_MESSAGE_ID.with(|id| {
-        let current = id.borrow().get().clone();
+        let current = *id.borrow().get();
         id.borrow_mut().set(current + 1).unwrap();
         current
     })
 }
 
+/// Allocate a channel ID. IDs already taken are skipped: `init` inserts the
+/// General channel as 1 without advancing the counter, so the first channel
+/// created afterwards used to overwrite it.
 pub fn next_channel_id() -> u64 {
     NEXT_CHANNEL_ID.with(|id| {
-        let current = id.borrow().get().clone();
+        let mut current = *id.borrow().get();
+        while with_channels(|channels| channels.contains_key(&current)) {
+            current += 1;
+        }
         id.borrow_mut().set(current + 1).unwrap();
         current
     })
@@ -320,44 +863,144 @@ pub fn next_channel_id() -> u64 {
 // Encrypted message access functions
 pub fn with_encrypted_messages<F, R>(f: F) -> R
 where
-    F: FnOnce(&StableBTreeMap<u64, EncryptedMessage, Memory>) -> R,
+    F: FnOnce(&Records<u64, EncryptedMessage>) -> R,
 {
     ENCRYPTED_MESSAGES.with(|m| f(&m.borrow()))
 }
 
 pub fn with_encrypted_messages_mut<F, R>(f: F) -> R
 where
-    F: FnOnce(&mut StableBTreeMap<u64, EncryptedMessage, Memory>) -> R,
+    F: FnOnce(&mut Records<u64, EncryptedMessage>) -> R,
 {
     ENCRYPTED_MESSAGES.with(|m| f(&mut m.borrow_mut()))
 }
 
-pub fn with_encrypted_message_owners<F, R>(f: F) -> R
+pub fn with_legacy_encrypted_message_owners_mut<F, R>(f: F) -> R
 where
-    F: FnOnce(&StableBTreeMap<Principal, MessageIds, Memory>) -> R,
+    F: FnOnce(&mut Records<Principal, MessageIds>) -> R,
 {
-    ENCRYPTED_MESSAGE_OWNERS.with(|o| f(&o.borrow()))
+    LEGACY_ENCRYPTED_MESSAGE_OWNERS.with(|o| f(&mut o.borrow_mut()))
 }
 
-pub fn with_encrypted_message_owners_mut<F, R>(f: F) -> R
+pub fn with_legacy_encrypted_message_shares_mut<F, R>(f: F) -> R
 where
-    F: FnOnce(&mut StableBTreeMap<Principal, MessageIds, Memory>) -> R,
+    F: FnOnce(&mut Records<Principal, MessageIds>) -> R,
 {
-    ENCRYPTED_MESSAGE_OWNERS.with(|o| f(&mut o.borrow_mut()))
+    LEGACY_ENCRYPTED_MESSAGE_SHARES.with(|s| f(&mut s.borrow_mut()))
 }
 
-pub fn with_encrypted_message_shares<F, R>(f: F) -> R
-where
-    F: FnOnce(&StableBTreeMap<Principal, MessageIds, Memory>) -> R,
-{
-    ENCRYPTED_MESSAGE_SHARES.with(|s| f(&s.borrow()))
+/// Bounds of the keys in `(prefix, id)` order below the ID `before`, or all of
+/// the prefix's keys without one
+fn ids_below<P: Copy>(prefix: P, before: Option<u64>) -> impl RangeBounds<(P, u64)> {
+    let end = match before {
+        Some(before) => std::ops::Bound::Excluded((prefix, before)),
+        None => std::ops::Bound::Included((prefix, u64::MAX)),
+    };
+    (std::ops::Bound::Included((prefix, 0)), end)
 }
 
-pub fn with_encrypted_message_shares_mut<F, R>(f: F) -> R
-where
-    F: FnOnce(&mut StableBTreeMap<Principal, MessageIds, Memory>) -> R,
-{
-    ENCRYPTED_MESSAGE_SHARES.with(|s| f(&mut s.borrow_mut()))
+fn message_ids_of(
+    index: &StableBTreeMap<(Principal, u64), (), Memory>,
+    user: &Principal,
+    before: Option<u64>,
+    limit: usize,
+) -> Vec<u64> {
+    index
+        .keys_range(ids_below(*user, before))
+        .rev()
+        .take(limit)
+        .map(|(_, message_id)| message_id)
+        .collect()
+}
+
+// A user's legacy list stays in place until migrate_encrypted_message_indexes
+// reaches it, so until then it is read and updated along with the index
+
+fn with_legacy_ids(
+    legacy: &Records<Principal, MessageIds>,
+    user: &Principal,
+    before: Option<u64>,
+    limit: usize,
+    mut ids: Vec<u64>,
+) -> Vec<u64> {
+    if let Some(list) = legacy.get(user) {
+        ids.extend(list.ids.into_iter().filter(|id| before.is_none_or(|before| *id < before)));
+        ids.sort_unstable_by(|a, b| b.cmp(a));
+        ids.dedup();
+        ids.truncate(limit);
+    }
+    ids
+}
+
+fn remove_legacy_id(legacy: &mut Records<Principal, MessageIds>, user: Principal, message_id: u64) {
+    if let Some(mut list) = legacy.get(&user) {
+        list.ids.retain(|id| *id != message_id);
+        if list.ids.is_empty() {
+            legacy.remove(&user);
+        } else {
+            legacy.insert(user, list);
+        }
+    }
+}
+
+pub fn add_message_owner(owner: Principal, message_id: u64) {
+    ENCRYPTED_MESSAGE_OWNERS.with(|o| o.borrow_mut().insert((owner, message_id), ()));
+}
+
+pub fn remove_message_owner(owner: Principal, message_id: u64) {
+    ENCRYPTED_MESSAGE_OWNERS.with(|o| o.borrow_mut().remove(&(owner, message_id)));
+    LEGACY_ENCRYPTED_MESSAGE_OWNERS.with(|o| remove_legacy_id(&mut o.borrow_mut(), owner, message_id));
+}
+
+/// Up to `limit` IDs of the encrypted messages a user owns below `before`,
+/// newest first
+pub fn owned_message_ids(owner: &Principal, before: Option<u64>, limit: usize) -> Vec<u64> {
+    let ids = ENCRYPTED_MESSAGE_OWNERS.with(|o| message_ids_of(&o.borrow(), owner, before, limit));
+    LEGACY_ENCRYPTED_MESSAGE_OWNERS.with(|o| with_legacy_ids(&o.borrow(), owner, before, limit, ids))
+}
+
+pub fn add_message_share(user: Principal, message_id: u64) {
+    ENCRYPTED_MESSAGE_SHARES.with(|s| s.borrow_mut().insert((user, message_id), ()));
+}
+
+pub fn remove_message_share(user: Principal, message_id: u64) {
+    ENCRYPTED_MESSAGE_SHARES.with(|s| s.borrow_mut().remove(&(user, message_id)));
+    LEGACY_ENCRYPTED_MESSAGE_SHARES.with(|s| remove_legacy_id(&mut s.borrow_mut(), user, message_id));
+}
+
+/// Up to `limit` IDs of the encrypted messages shared with a user below
+/// `before`, newest first
+pub fn shared_message_ids(user: &Principal, before: Option<u64>, limit: usize) -> Vec<u64> {
+    let ids = ENCRYPTED_MESSAGE_SHARES.with(|s| message_ids_of(&s.borrow(), user, before, limit));
+    LEGACY_ENCRYPTED_MESSAGE_SHARES.with(|s| with_legacy_ids(&s.borrow(), user, before, limit, ids))
+}
+
+/// Move the per-user `MessageIds` lists into the (principal, message_id)
+/// indexes, a batch of users at a time, emptying the legacy maps
+pub fn migrate_encrypted_message_indexes(_cursor: Option<&[u8]>, budget: usize) -> Option<Vec<u8>> {
+    fn drain(legacy: &mut Records<Principal, MessageIds>, budget: usize) -> Vec<(Principal, u64)> {
+        let mut entries = Vec::new();
+        while entries.len() < budget {
+            let Some((user, list)) = legacy.pop_first() else { break };
+            entries.extend(list.ids.into_iter().map(|id| (user, id)));
+        }
+        entries
+    }
+    
+    let owned = with_legacy_encrypted_message_owners_mut(|owners| drain(owners, budget));
+    let budget = budget.saturating_sub(owned.len());
+    for (owner, message_id) in owned {
+        add_message_owner(owner, message_id);
+    }
+    
+    let shared = with_legacy_encrypted_message_shares_mut(|shares| drain(shares, budget));
+    for (user, message_id) in shared {
+        add_message_share(user, message_id);
+    }
+    
+    let remaining = LEGACY_ENCRYPTED_MESSAGE_OWNERS.with(|o| o.borrow().len())
+        + LEGACY_ENCRYPTED_MESSAGE_SHARES.with(|s| s.borrow().len());
+    if remaining == 0 { None } else { suspend(()) }
 }
 
 // VetKey functions
@@ -374,3 +1017,694 @@ where
 {
     KEY_NAME.with(|k| f(&mut k.borrow_mut()))
 }
+
+// Key vault functions
+
+/// Contexts a user has stored keys under, in sorted order
+pub fn vault_contexts(user: Principal) -> Vec<String> {
+    VAULT_KEYS.with(|v| {
+        v.borrow()
+            .keys_range(VaultKey { user, context: String::new() }..)
+            .take_while(|key| key.user == user)
+            .map(|key| key.context)
+            .collect()
+    })
+}
+
+pub fn vault_key(user: Principal, context: String) -> Option<String> {
+    VAULT_KEYS.with(|v| v.borrow().get(&VaultKey { user, context }))
+}
+
+pub fn insert_vault_key(user: Principal, context: String, wrapped_key: String) {
+    VAULT_KEYS.with(|v| v.borrow_mut().insert(VaultKey { user, context }, wrapped_key));
+}
+
+/// Remove a stored key and return whether there was one
+pub fn remove_vault_key(user: Principal, context: String) -> bool {
+    VAULT_KEYS.with(|v| v.borrow_mut().remove(&VaultKey { user, context })).is_some()
+}
+
+// Block and mute functions
+pub fn with_blocked_users<F, R>(f: F) -> R
+where
+    F: FnOnce(&Records<Principal, PrincipalList>) -> R,
+{
+    BLOCKED_USERS.with(|b| f(&b.borrow()))
+}
+
+pub fn with_blocked_users_mut<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut Records<Principal, PrincipalList>) -> R,
+{
+    BLOCKED_USERS.with(|b| f(&mut b.borrow_mut()))
+}
+
+pub fn with_muted_users<F, R>(f: F) -> R
+where
+    F: FnOnce(&Records<Principal, PrincipalList>) -> R,
+{
+    MUTED_USERS.with(|m| f(&m.borrow()))
+}
+
+pub fn with_muted_users_mut<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut Records<Principal, PrincipalList>) -> R,
+{
+    MUTED_USERS.with(|m| f(&mut m.borrow_mut()))
+}
+
+/// Returns true if `blocker` has blocked `other`
+pub fn is_blocked(blocker: &Principal, other: &Principal) -> bool {
+    with_blocked_users(|blocked| {
+        blocked.get(blocker)
+            .map(|list| list.contains(other))
+            .unwrap_or(false)
+    })
+}
+
+// Avatar functions
+pub fn with_avatars<F, R>(f: F) -> R
+where
+    F: FnOnce(&Records<Principal, Avatar>) -> R,
+{
+    AVATARS.with(|a| f(&a.borrow()))
+}
+
+pub fn with_avatars_mut<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut Records<Principal, Avatar>) -> R,
+{
+    AVATARS.with(|a| f(&mut a.borrow_mut()))
+}
+
+// Upload and blob store functions
+pub fn with_upload_sessions<F, R>(f: F) -> R
+where
+    F: FnOnce(&Records<u64, UploadSession>) -> R,
+{
+    UPLOAD_SESSIONS.with(|u| f(&u.borrow()))
+}
+
+pub fn with_upload_sessions_mut<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut Records<u64, UploadSession>) -> R,
+{
+    UPLOAD_SESSIONS.with(|u| f(&mut u.borrow_mut()))
+}
+
+pub fn with_upload_chunks<F, R>(f: F) -> R
+where
+    F: FnOnce(&StableBTreeMap<(u64, u32), Vec<u8>, Memory>) -> R,
+{
+    UPLOAD_CHUNKS.with(|c| f(&c.borrow()))
+}
+
+pub fn with_upload_chunks_mut<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut StableBTreeMap<(u64, u32), Vec<u8>, Memory>) -> R,
+{
+    UPLOAD_CHUNKS.with(|c| f(&mut c.borrow_mut()))
+}
+
+pub fn with_blobs<F, R>(f: F) -> R
+where
+    F: FnOnce(&StableBTreeMap<[u8; 32], Vec<u8>, Memory>) -> R,
+{
+    BLOBS.with(|b| f(&b.borrow()))
+}
+
+pub fn with_blobs_mut<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut StableBTreeMap<[u8; 32], Vec<u8>, Memory>) -> R,
+{
+    BLOBS.with(|b| f(&mut b.borrow_mut()))
+}
+
+/// Decode a sample of the records at each end of every versioned map and
+/// return how many were read. Run after upgrades so code that cannot read the
+/// stored schema traps the upgrade, which is rolled back; scanning every record
+/// would not fit in the upgrade's instruction limit once the maps are large.
+pub fn verify_stored_records(sample: usize) -> Result<u64, String> {
+    Ok(MESSAGES.with(|m| m.borrow().verify_sample(sample))?
+        + USERS.with(|m| m.borrow().verify_sample(sample))?
+        + CHANNELS.with(|m| m.borrow().verify_sample(sample))?
+        + ENCRYPTED_MESSAGES.with(|m| m.borrow().verify_sample(sample))?
+        + LEGACY_ENCRYPTED_MESSAGE_OWNERS.with(|m| m.borrow().verify_sample(sample))?
+        + LEGACY_ENCRYPTED_MESSAGE_SHARES.with(|m| m.borrow().verify_sample(sample))?
+        + BLOCKED_USERS.with(|m| m.borrow().verify_sample(sample))?
+        + MUTED_USERS.with(|m| m.borrow().verify_sample(sample))?
+        + AVATARS.with(|m| m.borrow().verify_sample(sample))?
+        + UPLOAD_SESSIONS.with(|m| m.borrow().verify_sample(sample))?
+        + ATTACHMENTS.with(|m| m.borrow().verify_sample(sample))?)
+}
+
+pub fn index_message_expiry(expires_at: u64, message_id: u64) {
+    MESSAGE_EXPIRY.with(|e| e.borrow_mut().insert((expires_at, message_id), ()));
+}
+
+pub fn unindex_message_expiry(expires_at: u64, message_id: u64) {
+    MESSAGE_EXPIRY.with(|e| e.borrow_mut().remove(&(expires_at, message_id)));
+}
+
+/// Up to `limit` `(expires_at, message_id)` entries of encrypted messages that
+/// have expired at `current_time`, oldest first
+pub fn expired_messages(current_time: u64, limit: usize) -> Vec<(u64, u64)> {
+    MESSAGE_EXPIRY.with(|e| e.borrow().keys_range(..(current_time, 0)).take(limit).collect())
+}
+
+/// Expiry time of the encrypted message that expires next
+pub fn next_expiry() -> Option<u64> {
+    MESSAGE_EXPIRY.with(|e| e.borrow().keys().next().map(|(expires_at, _)| expires_at))
+}
+
+pub fn expiry_timer() -> Option<(u64, TimerId)> {
+    EXPIRY_TIMER.with(|t| *t.borrow())
+}
+
+pub fn set_expiry_timer(timer: Option<(u64, TimerId)>) {
+    EXPIRY_TIMER.with(|t| *t.borrow_mut() = timer);
+}
+
+/// Index the stored encrypted messages, a batch at a time in ID order.
+/// Messages created before the index existed are otherwise never cleaned up.
+pub fn rebuild_message_expiry_index(cursor: Option<&[u8]>, budget: usize) -> Option<Vec<u8>> {
+    let next: u64 = resume(cursor);
+    let entries: Vec<(u64, Option<u64>)> = with_encrypted_messages(|messages| {
+        messages.range(next..).take(budget).map(|(id, message)| (id, message.expires_at)).collect()
+    });
+    for (message_id, expires_at) in &entries {
+        if let Some(expires_at) = expires_at {
+            index_message_expiry(*expires_at, *message_id);
+        }
+    }
+    
+    match entries.last() {
+        Some((last, _)) if entries.len() == budget => suspend(last + 1),
+        _ => None,
+    }
+}
+
+pub fn index_channel_message(channel_id: u64, message_id: u64) {
+    CHANNEL_MESSAGES.with(|c| c.borrow_mut().insert((channel_id, message_id), ()));
+}
+
+pub fn unindex_channel_message(channel_id: u64, message_id: u64) {
+    CHANNEL_MESSAGES.with(|c| c.borrow_mut().remove(&(channel_id, message_id)));
+}
+
+/// IDs of up to `limit` of the newest messages in a channel, newest first
+pub fn newest_channel_messages(channel_id: u64, limit: usize) -> Vec<u64> {
+    CHANNEL_MESSAGES.with(|c| {
+        c.borrow()
+            .keys_range((channel_id, 0)..=(channel_id, u64::MAX))
+            .rev()
+            .take(limit)
+            .map(|(_, message_id)| message_id)
+            .collect()
+    })
+}
+
+/// IDs of up to `limit` of the oldest messages in a channel
+pub fn oldest_channel_messages(channel_id: u64, limit: usize) -> Vec<u64> {
+    CHANNEL_MESSAGES.with(|c| {
+        c.borrow()
+            .keys_range((channel_id, 0)..=(channel_id, u64::MAX))
+            .take(limit)
+            .map(|(_, message_id)| message_id)
+            .collect()
+    })
+}
+
+pub fn index_encrypted_channel_message(channel_id: u64, message_id: u64) {
+    ENCRYPTED_CHANNEL_MESSAGES.with(|c| c.borrow_mut().insert((channel_id, message_id), ()));
+}
+
+pub fn unindex_encrypted_channel_message(channel_id: u64, message_id: u64) {
+    ENCRYPTED_CHANNEL_MESSAGES.with(|c| c.borrow_mut().remove(&(channel_id, message_id)));
+}
+
+/// Up to `limit` IDs of the encrypted messages in a channel below `before`,
+/// newest first
+pub fn encrypted_channel_message_ids(channel_id: u64, before: Option<u64>, limit: usize) -> Vec<u64> {
+    let mut ids: Vec<u64> = ENCRYPTED_CHANNEL_MESSAGES.with(|c| {
+        c.borrow()
+            .keys_range(ids_below(channel_id, before))
+            .rev()
+            .take(limit)
+            .map(|(_, message_id)| message_id)
+            .collect()
+    });
+    
+    let indexed_below = ENCRYPTED_CHANNELS_INDEXED_BELOW.with(|c| *c.borrow().get());
+    if before.is_none_or(|before| indexed_below < before) {
+        let end = before.map_or(std::ops::Bound::Unbounded, std::ops::Bound::Excluded);
+        with_encrypted_messages(|messages| {
+            ids.extend(
+                messages
+                    .range((std::ops::Bound::Included(indexed_below), end))
+                    .filter(|(_, message)| message.channel_id == Some(channel_id))
+                    .map(|(id, _)| id),
+            )
+        });
+        ids.sort_unstable_by(|a, b| b.cmp(a));
+        ids.dedup();
+        ids.truncate(limit);
+    }
+    ids
+}
+
+pub fn set_encrypted_channels_indexed_below(message_id: u64) {
+    ENCRYPTED_CHANNELS_INDEXED_BELOW.with(|c| c.borrow_mut().set(message_id).expect("Failed to store index watermark"));
+}
+
+/// Index the stored encrypted channel messages by channel, a batch at a time
+/// in ID order
+pub fn rebuild_encrypted_channel_index(cursor: Option<&[u8]>, budget: usize) -> Option<Vec<u8>> {
+    let next: u64 = resume(cursor);
+    let entries: Vec<(u64, Option<u64>)> = with_encrypted_messages(|messages| {
+        messages.range(next..).take(budget).map(|(id, message)| (id, message.channel_id)).collect()
+    });
+    for (message_id, channel_id) in &entries {
+        if let Some(channel_id) = channel_id {
+            index_encrypted_channel_message(*channel_id, *message_id);
+        }
+    }
+    
+    match entries.last() {
+        Some((last, _)) if entries.len() == budget => {
+            set_encrypted_channels_indexed_below(last + 1);
+            suspend(last + 1)
+        }
+        _ => {
+            set_encrypted_channels_indexed_below(u64::MAX);
+            None
+        }
+    }
+}
+
+pub fn set_certified_hash(path: &str, hash: Hash) {
+    CERTIFIED_HASHES.with(|h| h.borrow_mut().insert(path.to_string(), hash));
+}
+
+pub fn remove_certified_hash(path: &str) {
+    CERTIFIED_HASHES.with(|h| h.borrow_mut().remove(&path.to_string()));
+}
+
+pub fn certified_hashes() -> Vec<(String, Hash)> {
+    CERTIFIED_HASHES.with(|h| h.borrow().iter().collect())
+}
+
+/// Progress of `rebuild_channel_message_index`
+#[derive(CandidType, Deserialize)]
+enum ChannelIndexRebuild {
+    /// Indexing messages from ID `next` on
+    Indexing { next: u64 },
+    /// Recounting the messages of channels from `channel` on, `counted` of
+    /// which were seen in that channel before `next_message`
+    Counting { channel: u64, next_message: u64, counted: u64 },
+}
+
+impl Default for ChannelIndexRebuild {
+    fn default() -> Self {
+        ChannelIndexRebuild::Indexing { next: 0 }
+    }
+}
+
+/// Index every stored channel message and recount each channel's messages
+/// from the index, a batch at a time
+pub fn rebuild_channel_message_index(cursor: Option<&[u8]>, budget: usize) -> Option<Vec<u8>> {
+    match resume(cursor) {
+        ChannelIndexRebuild::Indexing { next } => {
+            let entries: Vec<(u64, Option<u64>)> = with_messages(|messages| {
+                messages.range(next..).take(budget).map(|(id, message)| (id, message.channel_id)).collect()
+            });
+            for (message_id, channel_id) in &entries {
+                if let Some(channel_id) = channel_id {
+                    index_channel_message(*channel_id, *message_id);
+                }
+            }
+            
+            suspend(match entries.last() {
+                Some((last, _)) if entries.len() == budget => ChannelIndexRebuild::Indexing { next: last + 1 },
+                _ => ChannelIndexRebuild::Counting { channel: 0, next_message: 0, counted: 0 },
+            })
+        }
+        ChannelIndexRebuild::Counting { mut channel, mut next_message, mut counted } => {
+            let mut budget = budget;
+            loop {
+                let channel_id = with_channels(|channels| channels.keys_range(channel..).next())?;
+                if channel_id != channel {
+                    next_message = 0;
+                    counted = 0;
+                }
+                
+                let seen: Vec<u64> = CHANNEL_MESSAGES.with(|c| {
+                    c.borrow()
+                        .keys_range((channel_id, next_message)..=(channel_id, u64::MAX))
+                        .take(budget)
+                        .map(|(_, message_id)| message_id)
+                        .collect()
+                });
+                counted += seen.len() as u64;
+                if let Some(last) = seen.last().filter(|_| seen.len() == budget) {
+                    return suspend(ChannelIndexRebuild::Counting { channel: channel_id, next_message: last + 1, counted });
+                }
+                budget -= seen.len();
+                
+                with_channels_mut(|channels| {
+                    if let Some(mut record) = channels.get(&channel_id) {
+                        record.message_count = counted;
+                        channels.insert(channel_id, record);
+                    }
+                });
+                
+                channel = channel_id + 1;
+                budget = budget.saturating_sub(1);
+                if budget == 0 {
+                    return suspend(ChannelIndexRebuild::Counting { channel, next_message: 0, counted: 0 });
+                }
+            }
+        }
+    }
+}
+
+/// A data migration run in batches, one per execution. It is called with
+/// `None` to start and then with the cursor it last returned, handles about
+/// `budget` records per call and returns `None` once it has finished.
+pub type Migration = fn(Option<&[u8]>, usize) -> Option<Vec<u8>>;
+
+pub fn resume<C: CandidType + DeserializeOwned + Default>(cursor: Option<&[u8]>) -> C {
+    cursor
+        .map(|bytes| candid::decode_one(bytes).expect("Failed to decode migration cursor"))
+        .unwrap_or_default()
+}
+
+pub fn suspend<C: CandidType>(cursor: C) -> Option<Vec<u8>> {
+    Some(candid::encode_one(cursor).expect("Failed to encode migration cursor"))
+}
+
+pub fn upgrade_record() -> UpgradeRecord {
+    UPGRADE_RECORD.with(|r| r.borrow().get().clone())
+}
+
+pub fn set_upgrade_record(record: UpgradeRecord) {
+    UPGRADE_RECORD.with(|r| r.borrow_mut().set(record).expect("Failed to store upgrade record"));
+}
+
+pub fn cleanup_interval_secs() -> u64 {
+    CLEANUP_INTERVAL_SECS.with(|i| *i.borrow().get())
+}
+
+pub fn set_cleanup_interval_secs(secs: u64) {
+    CLEANUP_INTERVAL_SECS.with(|i| i.borrow_mut().set(secs).expect("Failed to store cleanup interval"));
+}
+
+/// Store a blob, or take another reference to it if the same content is
+/// already stored
+pub fn store_blob(content_hash: [u8; 32], data: Vec<u8>) {
+    with_blobs_mut(|blobs| {
+        if !blobs.contains_key(&content_hash) {
+            blobs.insert(content_hash, data);
+        }
+    });
+    retain_blob(content_hash);
+}
+
+pub fn retain_blob(content_hash: [u8; 32]) {
+    BLOB_REFS.with(|r| {
+        let mut refs = r.borrow_mut();
+        let count = refs.get(&content_hash).unwrap_or(0);
+        refs.insert(content_hash, count + 1);
+    });
+}
+
+/// Drop a reference to a blob, deleting its bytes when it was the last one
+pub fn release_blob(content_hash: [u8; 32]) {
+    let remaining = BLOB_REFS.with(|r| {
+        let mut refs = r.borrow_mut();
+        let remaining = refs.get(&content_hash).unwrap_or(0).saturating_sub(1);
+        if remaining == 0 {
+            refs.remove(&content_hash);
+        } else {
+            refs.insert(content_hash, remaining);
+        }
+        remaining
+    });
+    
+    if remaining == 0 {
+        with_blobs_mut(|blobs| blobs.remove(&content_hash));
+    }
+}
+
+pub fn with_attachments<F, R>(f: F) -> R
+where
+    F: FnOnce(&Records<u64, AttachmentRecord>) -> R,
+{
+    ATTACHMENTS.with(|a| f(&a.borrow()))
+}
+
+pub fn with_attachments_mut<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut Records<u64, AttachmentRecord>) -> R,
+{
+    ATTACHMENTS.with(|a| f(&mut a.borrow_mut()))
+}
+
+pub fn next_upload_id() -> u64 {
+    NEXT_UPLOAD_ID.with(|id| {
+        let current = *id.borrow().get();
+        id.borrow_mut().set(current + 1).unwrap();
+        current
+    })
+}
+
+pub fn next_attachment_id() -> u64 {
+    NEXT_ATTACHMENT_ID.with(|id| {
+        let current = *id.borrow().get();
+        id.borrow_mut().set(current + 1).unwrap();
+        current
+    })
+}
+
+/// Concatenate the chunks of an upload in index order. Returns None if any
+/// chunk between 0 and the last received index is missing.
+pub fn assemble_upload(upload_id: u64) -> Option<Vec<u8>> {
+    with_upload_chunks(|chunks| {
+        let mut data = Vec::new();
+        for (expected, ((_, index), chunk)) in chunks.range((upload_id, 0)..=(upload_id, u32::MAX)).enumerate() {
+            if index as usize != expected {
+                return None;
+            }
+            data.extend_from_slice(&chunk);
+        }
+        Some(data)
+    })
+}
+
+/// Store an upload session, index it by age and reserve its size against the
+/// owner's quota
+pub fn insert_upload_session(session: UploadSession) {
+    UPLOAD_SESSION_AGES.with(|a| a.borrow_mut().insert((session.created_at, session.id), ()));
+    OPEN_UPLOADS.with(|o| o.borrow_mut().insert((session.owner, session.id), session.total_size));
+    with_upload_sessions_mut(|sessions| {
+        sessions.insert(session.id, session);
+    });
+}
+
+/// Remove an upload session and all of its chunks, releasing its reservation
+pub fn remove_upload(upload_id: u64) {
+    if let Some(session) = with_upload_sessions_mut(|sessions| sessions.remove(&upload_id)) {
+        UPLOAD_SESSION_AGES.with(|a| a.borrow_mut().remove(&(session.created_at, upload_id)));
+        OPEN_UPLOADS.with(|o| o.borrow_mut().remove(&(session.owner, upload_id)));
+    }
+    with_upload_chunks_mut(|chunks| {
+        let keys: Vec<(u64, u32)> = chunks.range((upload_id, 0)..=(upload_id, u32::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            chunks.remove(&key);
+        }
+    });
+}
+
+/// Number of open upload sessions a user has and the bytes they reserve
+pub fn open_uploads(owner: &Principal) -> (usize, u64) {
+    OPEN_UPLOADS.with(|o| {
+        o.borrow()
+            .range((*owner, 0)..=(*owner, u64::MAX))
+            .fold((0, 0), |(count, bytes), (_, size)| (count + 1, bytes + size))
+    })
+}
+
+/// IDs of up to `limit` of the oldest upload sessions created before
+/// `created_before`
+pub fn upload_sessions_created_before(created_before: u64, limit: usize) -> Vec<u64> {
+    UPLOAD_SESSION_AGES.with(|a| {
+        a.borrow().keys_range(..(created_before, 0)).take(limit).map(|(_, id)| id).collect()
+    })
+}
+
+/// Store an attachment record, keeping the index of unattached uploads in
+/// step with its `message_id`
+pub fn insert_attachment(record: AttachmentRecord) {
+    UNATTACHED_ATTACHMENTS.with(|u| {
+        let mut unattached = u.borrow_mut();
+        if record.message_id.is_none() {
+            unattached.insert((record.created_at, record.id), ());
+        } else {
+            unattached.remove(&(record.created_at, record.id));
+        }
+    });
+    with_attachments_mut(|attachments| {
+        attachments.insert(record.id, record);
+    });
+}
+
+pub fn remove_attachment(attachment_id: u64) -> Option<AttachmentRecord> {
+    let record = with_attachments_mut(|attachments| attachments.remove(&attachment_id))?;
+    UNATTACHED_ATTACHMENTS.with(|u| u.borrow_mut().remove(&(record.created_at, attachment_id)));
+    Some(record)
+}
+
+/// IDs of up to `limit` of the oldest attachments created before
+/// `created_before` that were never sent with a message
+pub fn unattached_attachments_created_before(created_before: u64, limit: usize) -> Vec<u64> {
+    UNATTACHED_ATTACHMENTS.with(|u| {
+        u.borrow().keys_range(..(created_before, 0)).take(limit).map(|(_, id)| id).collect()
+    })
+}
+
+// Storage quota functions
+pub fn storage_usage(user: &Principal) -> u64 {
+    STORAGE_USAGE.with(|u| u.borrow().get(user).unwrap_or(0))
+}
+
+pub fn add_storage_usage(user: Principal, bytes: u64) {
+    STORAGE_USAGE.with(|u| {
+        let mut usage = u.borrow_mut();
+        let current = usage.get(&user).unwrap_or(0);
+        usage.insert(user, current.saturating_add(bytes));
+    });
+}
+
+pub fn release_storage_usage(user: Principal, bytes: u64) {
+    STORAGE_USAGE.with(|u| {
+        let mut usage = u.borrow_mut();
+        let remaining = usage.get(&user).unwrap_or(0).saturating_sub(bytes);
+        if remaining == 0 {
+            usage.remove(&user);
+        } else {
+            usage.insert(user, remaining);
+        }
+    });
+}
+
+/// The quota that applies to `user`: their override if set, else the default
+pub fn storage_quota(user: &Principal) -> u64 {
+    STORAGE_QUOTA_OVERRIDES
+        .with(|o| o.borrow().get(user))
+        .unwrap_or_else(default_storage_quota)
+}
+
+pub fn default_storage_quota() -> u64 {
+    DEFAULT_STORAGE_QUOTA.with(|q| *q.borrow().get())
+}
+
+pub fn set_default_storage_quota(bytes: u64) {
+    DEFAULT_STORAGE_QUOTA.with(|q| {
+        q.borrow_mut().set(bytes).unwrap();
+    });
+}
+
+pub fn set_storage_quota_override(user: Principal, bytes: Option<u64>) {
+    STORAGE_QUOTA_OVERRIDES.with(|o| {
+        let mut overrides = o.borrow_mut();
+        match bytes {
+            Some(bytes) => overrides.insert(user, bytes),
+            None => overrides.remove(&user),
+        };
+    });
+}
+
+/// The URL signing key, or None if it has not been initialized yet
+pub fn url_signing_key() -> Option<[u8; 32]> {
+    URL_SIGNING_KEY.with(|k| {
+        let key = *k.borrow().get();
+        (key != [0; 32]).then_some(key)
+    })
+}
+
+pub fn set_url_signing_key(key: [u8; 32]) {
+    URL_SIGNING_KEY.with(|k| {
+        k.borrow_mut().set(key).unwrap();
+    });
+}
+
+// HTTP certification functions
+pub fn cached_public_key(context: &[u8]) -> Option<Vec<u8>> {
+    PUBLIC_KEYS.with(|k| k.borrow().get(context).cloned())
+}
+
+pub fn cache_public_key(context: Vec<u8>, public_key: Vec<u8>) {
+    PUBLIC_KEYS.with(|k| k.borrow_mut().insert(context, public_key));
+}
+
+pub fn with_asset_hashes<F, R>(f: F) -> R
+where
+    F: FnOnce(&RbTree<Vec<u8>, Hash>) -> R,
+{
+    ASSET_HASHES.with(|a| f(&a.borrow()))
+}
+
+pub fn with_asset_hashes_mut<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut RbTree<Vec<u8>, Hash>) -> R,
+{
+    ASSET_HASHES.with(|a| f(&mut a.borrow_mut()))
+}
+
+// Presence and typing functions
+pub fn record_heartbeat(user: Principal, now: u64) {
+    LAST_SEEN.with(|l| {
+        l.borrow_mut().insert(user, now);
+    });
+}
+
+pub fn last_seen(user: &Principal) -> Option<u64> {
+    LAST_SEEN.with(|l| l.borrow().get(user).copied())
+}
+
+/// Set or clear the typing state of `user` in `channel_id`. Expired entries in
+/// the channel are pruned on every write.
+pub fn set_typing(channel_id: u64, user: Principal, expires_at: Option<u64>, now: u64) {
+    TYPING.with(|t| {
+        let mut typing = t.borrow_mut();
+        let channel = typing.entry(channel_id).or_default();
+        channel.retain(|_, until| *until > now);
+        match expires_at {
+            Some(until) => {
+                channel.insert(user, until);
+            }
+            None => {
+                channel.remove(&user);
+            }
+        }
+        if channel.is_empty() {
+            typing.remove(&channel_id);
+        }
+    });
+}
+
+pub fn typing_users(channel_id: u64, now: u64) -> Vec<Principal> {
+    TYPING.with(|t| {
+        t.borrow()
+            .get(&channel_id)
+            .map(|channel| {
+                channel.iter()
+                    .filter(|(_, until)| **until > now)
+                    .map(|(user, _)| *user)
+                    .collect()
+            })
+            .unwrap_or_default()
+    })
+}
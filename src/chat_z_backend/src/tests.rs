@@ -10,17 +10,13 @@ mod tests {
 
     // Mock functions for testing since we can't use actual IC environment
     fn mock_caller() -> Principal {
-        Principal::from_text("rdmx6-jaaaa-aaaah-qca7a-cai").unwrap()
+        Principal::from_text("rdmx6-jaaaa-aaaaa-aaadq-cai").unwrap()
     }
 
     fn mock_time() -> u64 {
         1234567890000000000 // Mock timestamp in nanoseconds
     }
 
-    fn mock_anonymous() -> Principal {
-        Principal::anonymous()
-    }
-
     #[test]
     fn test_user_registration() {
         // Test successful user registration
@@ -59,6 +55,10 @@ mod tests {
             message_count: 0,
             last_message_at: None,
             is_encrypted: false,
+            password_hash: None,
+            message_expiry: None,
+            retention: None,
+            key_epoch: None,
         };
         
         assert_eq!(channel.name, "Test Channel");
@@ -80,6 +80,10 @@ mod tests {
             message_count: 0,
             last_message_at: None,
             is_encrypted: true,
+            password_hash: None,
+            message_expiry: None,
+            retention: None,
+            key_epoch: None,
         };
         
         assert_eq!(channel.name, "🔒 Secret Channel");
@@ -94,6 +98,7 @@ mod tests {
             author: mock_caller(),
             content: "Hello, world!".to_string(),
             timestamp: mock_time(),
+            channel_id: None,
             reply_to: None,
             message_type: MessageType::Text,
             attachments: vec![],
@@ -115,37 +120,41 @@ mod tests {
             encrypted_content: "encrypted_content_here".to_string(),
             author: mock_caller(),
             timestamp: current_time,
-            expires_at,
+            expires_at: Some(expires_at),
             channel_id: Some(2), // Encrypted channel
             reply_to: None,
             message_type: MessageType::Text,
             shared_with: vec![],
             attachments: vec![],
+            key_epoch: None,
+            ibe_recipient: None,
         };
         
         assert_eq!(encrypted_message.encrypted_content, "encrypted_content_here");
         assert_eq!(encrypted_message.author, mock_caller());
         assert!(encrypted_message.shared_with.is_empty());
-        assert_eq!(encrypted_message.expires_at, expires_at);
+        assert_eq!(encrypted_message.expires_at, Some(expires_at));
     }
 
     #[test]
     fn test_encrypted_message_authorization() {
         let owner = mock_caller();
-        let other_user = Principal::from_text("rrkah-fqaaa-aaaah-qcnwa-cai").unwrap();
-        let shared_user = Principal::from_text("ryjl3-tyaaa-aaaah-qpz2a-cai").unwrap();
+        let other_user = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let shared_user = Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap();
         
         let encrypted_message = EncryptedMessage {
             id: 1,
             encrypted_content: "secret".to_string(),
             author: owner,
             timestamp: mock_time(),
-            expires_at: mock_time() + (24 * 60 * 60 * 1_000_000_000),
+            expires_at: Some(mock_time() + (24 * 60 * 60 * 1_000_000_000)),
             channel_id: None,
             reply_to: None,
             message_type: MessageType::Text,
             shared_with: vec![shared_user],
             attachments: vec![],
+            key_epoch: None,
+            ibe_recipient: None,
         };
         
         // Owner should be authorized
@@ -161,7 +170,7 @@ mod tests {
     #[test]
     fn test_encrypted_message_expiration() {
         let current_time = mock_time();
-        let past_time = current_time - 1000; // 1 second ago
+        let past_time = current_time - 2000; // 2 microseconds ago
         let future_time = current_time + (24 * 60 * 60 * 1_000_000_000); // 24 hours later
         
         // Expired message
@@ -170,12 +179,14 @@ mod tests {
             encrypted_content: "expired".to_string(),
             author: mock_caller(),
             timestamp: past_time,
-            expires_at: past_time + 1000, // Expired 1 second after creation
+            expires_at: Some(past_time + 1000), // Expired 1 microsecond after creation
             channel_id: None,
             reply_to: None,
             message_type: MessageType::Text,
             shared_with: vec![],
             attachments: vec![],
+            key_epoch: None,
+            ibe_recipient: None,
         };
         
         assert!(expired_message.is_expired(current_time));
@@ -186,12 +197,14 @@ mod tests {
             encrypted_content: "valid".to_string(),
             author: mock_caller(),
             timestamp: current_time,
-            expires_at: future_time,
+            expires_at: Some(future_time),
             channel_id: None,
             reply_to: None,
             message_type: MessageType::Text,
             shared_with: vec![],
             attachments: vec![],
+            key_epoch: None,
+            ibe_recipient: None,
         };
         
         assert!(!valid_message.is_expired(current_time));
@@ -219,22 +232,25 @@ mod tests {
     #[test]
     fn test_attachment_structure() {
         let attachment = Attachment {
+            id: 7,
             file_type: "image/png".to_string(),
-            data: vec![1, 2, 3, 4, 5],
             filename: "test.png".to_string(),
             size: 5,
+            data: vec![],
+            preview: None,
+            encrypted_metadata: None,
         };
         
+        assert_eq!(attachment.id, 7);
         assert_eq!(attachment.file_type, "image/png");
         assert_eq!(attachment.filename, "test.png");
         assert_eq!(attachment.size, 5);
-        assert_eq!(attachment.data, vec![1, 2, 3, 4, 5]);
     }
 
     #[test]
     fn test_chat_error_enum() {
         // Test that all error variants exist and can be created
-        let errors = vec![
+        let errors = [
             ChatError::NotFound,
             ChatError::NotAuthorized,
             ChatError::InvalidInput,
@@ -270,14 +286,14 @@ mod tests {
             channel_id: Some(1),
             reply_to: None,
             message_type: MessageType::Text,
-            attachments: vec![],
+            attachment_ids: None,
         };
         
         assert_eq!(request.content, "Hello, world!");
         assert_eq!(request.channel_id, Some(1));
         assert_eq!(request.reply_to, None);
         assert_eq!(request.message_type, MessageType::Text);
-        assert!(request.attachments.is_empty());
+        assert!(request.attachment_ids.is_none());
     }
 
     #[test]
@@ -378,10 +394,29 @@ mod tests {
     }
 }
 
+// Shared helpers for driving batched migrations
+#[cfg(test)]
+mod fixtures {
+    use crate::state;
+
+    /// Run a migration to completion in batches of `budget` records and
+    /// return how many batches it took
+    pub fn migrate(migration: state::Migration, budget: usize) -> usize {
+        let mut cursor = migration(None, budget);
+        let mut batches = 1;
+        while cursor.is_some() {
+            cursor = migration(cursor.as_deref(), budget);
+            batches += 1;
+        }
+        batches
+    }
+}
+
 // Performance and stress tests
 #[cfg(test)]
 mod performance_tests {
-    use super::*;
+    use crate::{EncryptedMessage, MessageIds, MessageType};
+    use candid::Principal;
     
     #[test]
     fn test_large_message_content() {
@@ -391,14 +426,16 @@ mod performance_tests {
         let encrypted_message = EncryptedMessage {
             id: 1,
             encrypted_content: large_content.clone(),
-            author: Principal::from_text("rdmx6-jaaaa-aaaah-qca7a-cai").unwrap(),
+            author: Principal::from_text("rdmx6-jaaaa-aaaaa-aaadq-cai").unwrap(),
             timestamp: 1234567890000000000,
-            expires_at: 1234567890000000000 + (24 * 60 * 60 * 1_000_000_000),
+            expires_at: Some(1234567890000000000 + (24 * 60 * 60 * 1_000_000_000)),
             channel_id: None,
             reply_to: None,
             message_type: MessageType::Text,
             shared_with: vec![],
             attachments: vec![],
+            key_epoch: None,
+            ibe_recipient: None,
         };
         
         assert_eq!(encrypted_message.encrypted_content.len(), 4000);
@@ -410,20 +447,22 @@ mod performance_tests {
         
         // Create 50 users (the maximum allowed)
         for i in 0..50 {
-            shared_users.push(Principal::from_text(&format!("user{}-aaaaa-aaaah-qcnwa-cai", i)).unwrap_or(Principal::anonymous()));
+            shared_users.push(Principal::from_text(format!("user{}-aaaaa-aaaah-qcnwa-cai", i)).unwrap_or(Principal::anonymous()));
         }
         
         let encrypted_message = EncryptedMessage {
             id: 1,
             encrypted_content: "shared with many".to_string(),
-            author: Principal::from_text("rdmx6-jaaaa-aaaah-qca7a-cai").unwrap(),
+            author: Principal::from_text("rdmx6-jaaaa-aaaaa-aaadq-cai").unwrap(),
             timestamp: 1234567890000000000,
-            expires_at: 1234567890000000000 + (24 * 60 * 60 * 1_000_000_000),
+            expires_at: Some(1234567890000000000 + (24 * 60 * 60 * 1_000_000_000)),
             channel_id: None,
             reply_to: None,
             message_type: MessageType::Text,
             shared_with: shared_users,
             attachments: vec![],
+            key_epoch: None,
+            ibe_recipient: None,
         };
         
         assert_eq!(encrypted_message.shared_with.len(), 50);
@@ -449,13 +488,6 @@ mod performance_tests {
 // Error handling tests
 #[cfg(test)]
 mod error_handling_tests {
-    use crate::{
-        User, Channel, Message, MessageType, EncryptedMessage, MessageIds,
-        Attachment, ChatError, CreateMessageRequest, CreateChannelRequest,
-        UpdateUserRequest, MessageWithAuthor, PaginatedMessages
-    };
-    use candid::Principal;
-    
     #[test]
     fn test_invalid_message_content_lengths() {
         // Test empty content
@@ -490,3 +522,2796 @@ mod error_handling_tests {
         assert!(too_long_name.len() > 100);
     }
 }
+
+// Block and mute tests
+#[cfg(test)]
+mod block_tests {
+    use crate::state::{self, MessageType, User};
+    use crate::{block, mute, own_and_shared_messages, typing_users_for, unblock};
+    use crate::{Channel, ChatError, EncryptedMessage, PrincipalList};
+    use candid::Principal;
+    use std::collections::HashMap;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    fn alice() -> Principal {
+        Principal::from_text("rdmx6-jaaaa-aaaaa-aaadq-cai").unwrap()
+    }
+
+    fn bob() -> Principal {
+        Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap()
+    }
+
+    #[test]
+    fn test_block_is_one_directional() {
+        state::with_blocked_users_mut(|blocked| {
+            blocked.insert(alice(), PrincipalList { principals: vec![bob()] });
+        });
+        
+        assert!(state::is_blocked(&alice(), &bob()));
+        assert!(!state::is_blocked(&bob(), &alice()));
+    }
+
+    #[test]
+    fn test_mute_does_not_block() {
+        state::with_muted_users_mut(|muted| {
+            muted.insert(alice(), PrincipalList { principals: vec![bob()] });
+        });
+        
+        let muted = state::with_muted_users(|muted| muted.get(&alice()).unwrap_or_default());
+        assert!(muted.contains(&bob()));
+        assert!(!state::is_blocked(&alice(), &bob()));
+    }
+
+    fn carol() -> Principal {
+        Principal::from_slice(&[3])
+    }
+
+    fn register_all() {
+        for user in [alice(), bob(), carol()] {
+            state::with_users_mut(|users| {
+                users.insert(user, User {
+                    user_principal: user,
+                    username: user.to_text(),
+                    avatar_url: None,
+                    bio: None,
+                    joined_at: 0,
+                    message_count: 0,
+                    last_active: 0,
+                    encrypted_keys: HashMap::new(),
+                });
+            });
+        }
+    }
+
+    fn store_channel(id: u64, members: Vec<Principal>) {
+        state::with_channels_mut(|channels| {
+            channels.insert(id, Channel {
+                id,
+                name: "Typing".to_string(),
+                description: None,
+                created_by: alice(),
+                created_at: 0,
+                members,
+                message_count: 0,
+                last_message_at: None,
+                is_encrypted: false,
+                password_hash: None,
+                message_expiry: None,
+                retention: None,
+                key_epoch: None,
+            });
+        });
+    }
+
+    fn store_encrypted_message(id: u64, author: Principal) {
+        state::with_encrypted_messages_mut(|messages| {
+            messages.insert(id, EncryptedMessage {
+                id,
+                encrypted_content: "secret".to_string(),
+                author,
+                timestamp: 0,
+                expires_at: None,
+                channel_id: None,
+                reply_to: None,
+                message_type: MessageType::Text,
+                shared_with: vec![],
+                attachments: vec![],
+                key_epoch: None,
+                ibe_recipient: None,
+            });
+        });
+    }
+
+    #[test]
+    fn test_block_endpoint_validates_target() {
+        register_all();
+        
+        assert_eq!(block(alice(), alice()), Err(ChatError::InvalidInput));
+        assert_eq!(block(alice(), Principal::from_slice(&[9])), Err(ChatError::NotFound));
+        assert_eq!(block(Principal::from_slice(&[9]), alice()), Err(ChatError::NotAuthorized));
+        
+        assert_eq!(block(alice(), bob()), Ok(()));
+        assert_eq!(block(alice(), bob()), Ok(()));
+        assert!(state::is_blocked(&alice(), &bob()));
+        
+        unblock(alice(), &bob());
+        assert!(!state::is_blocked(&alice(), &bob()));
+        assert!(state::with_blocked_users(|blocked| blocked.get(&alice()).is_none()));
+    }
+
+    #[test]
+    fn test_blocked_users_do_not_show_as_typing() {
+        register_all();
+        store_channel(2, vec![alice(), bob(), carol()]);
+        let now = 100 * SECOND;
+        for user in [bob(), carol()] {
+            state::set_typing(2, user, Some(now + 5 * SECOND), now);
+        }
+        
+        block(alice(), bob()).unwrap();
+        assert_eq!(typing_users_for(&alice(), 2, now), Ok(vec![carol()]));
+        // Blocking only affects the user who asked for it
+        assert_eq!(typing_users_for(&carol(), 2, now), Ok(vec![bob()]));
+        
+        // Muting covers notifications and mentions, not typing
+        mute(alice(), carol()).unwrap();
+        assert_eq!(typing_users_for(&alice(), 2, now), Ok(vec![carol()]));
+    }
+
+    #[test]
+    fn test_messages_shared_before_a_block_are_hidden() {
+        register_all();
+        store_encrypted_message(1, bob());
+        store_encrypted_message(2, alice());
+        state::add_message_share(alice(), 1);
+        state::add_message_owner(alice(), 2);
+        
+        let ids = |user| own_and_shared_messages(&user, 0, None, None).messages.iter().map(|m| m.id).collect::<Vec<_>>();
+        assert_eq!(ids(alice()), vec![2, 1]);
+        
+        block(alice(), bob()).unwrap();
+        assert_eq!(ids(alice()), vec![2]);
+        unblock(alice(), &bob());
+        assert_eq!(ids(alice()), vec![2, 1]);
+    }
+}
+
+// Presence and typing tests
+#[cfg(test)]
+mod presence_tests {
+    use crate::{presence_status, state, PresenceStatus};
+    use candid::Principal;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    #[test]
+    fn test_presence_status_windows() {
+        let now = 1_000_000 * SECOND;
+        
+        assert_eq!(presence_status(now, now), PresenceStatus::Online);
+        assert_eq!(presence_status(now - 60 * SECOND, now), PresenceStatus::Online);
+        assert_eq!(presence_status(now - 10 * 60 * SECOND, now), PresenceStatus::Away);
+        assert_eq!(presence_status(now - 60 * 60 * SECOND, now), PresenceStatus::Offline);
+        // Clock skew must not underflow
+        assert_eq!(presence_status(now + SECOND, now), PresenceStatus::Online);
+    }
+
+    #[test]
+    fn test_typing_state_expires() {
+        let user = Principal::from_text("rdmx6-jaaaa-aaaaa-aaadq-cai").unwrap();
+        let now = 1_000 * SECOND;
+        
+        state::set_typing(3, user, Some(now + 6 * SECOND), now);
+        assert_eq!(state::typing_users(3, now), vec![user]);
+        assert!(state::typing_users(4, now).is_empty());
+        assert!(state::typing_users(3, now + 7 * SECOND).is_empty());
+        
+        state::set_typing(3, user, Some(now + 6 * SECOND), now);
+        state::set_typing(3, user, None, now + SECOND);
+        assert!(state::typing_users(3, now + SECOND).is_empty());
+    }
+}
+
+// Media sniffing and avatar serving tests
+#[cfg(test)]
+mod media_tests {
+    use crate::http::{self, HttpRequest};
+    use crate::media::{image_info, is_valid_blurhash, sanitize_filename, sniff_image_type, sniff_mime_type};
+    use crate::{image_preview, state, Avatar};
+    use candid::Principal;
+
+    pub fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut data = b"\x89PNG\r\n\x1a\n".to_vec();
+        data.extend_from_slice(&13u32.to_be_bytes());
+        data.extend_from_slice(b"IHDR");
+        data.extend_from_slice(&width.to_be_bytes());
+        data.extend_from_slice(&height.to_be_bytes());
+        data.extend_from_slice(&[8, 6, 0, 0, 0]);
+        data
+    }
+
+    fn get(url: &str) -> HttpRequest {
+        HttpRequest {
+            method: "GET".to_string(),
+            url: url.to_string(),
+            headers: vec![],
+            body: vec![],
+            certificate_version: None,
+        }
+    }
+
+    #[test]
+    fn test_blurhash_validation() {
+        assert!(is_valid_blurhash("LEHV6nWB2yk8pyo0adR*.7kCMdnj"));
+        assert!(is_valid_blurhash("00TI:j"));
+        // Length does not match the 4x3 components declared in the first character
+        assert!(!is_valid_blurhash("LEHV6nWB2yk8pyo0adR*.7kCMdn"));
+        assert!(!is_valid_blurhash("LEHV6nWB2yk8pyo0adR*.7kCMd\\j"));
+        assert!(!is_valid_blurhash(""));
+    }
+
+    #[test]
+    fn test_image_preview_dimensions_and_thumbnail() {
+        let small = png(64, 32);
+        let preview = image_preview(&small).unwrap();
+        assert_eq!((preview.width, preview.height), (64, 32));
+        assert_eq!(preview.thumbnail, Some(small));
+        
+        // Originals above the thumbnail limits only carry their dimensions
+        let large = image_preview(&png(1920, 1080)).unwrap();
+        assert_eq!((large.width, large.height), (1920, 1080));
+        assert_eq!(large.thumbnail, None);
+        
+        assert!(image_preview(b"%PDF-1.7").is_none());
+    }
+
+    #[test]
+    fn test_png_dimensions() {
+        let info = image_info(&png(64, 32)).unwrap();
+        assert_eq!(info.mime_type, "image/png");
+        assert_eq!((info.width, info.height), (64, 32));
+    }
+
+    #[test]
+    fn test_gif_dimensions() {
+        let mut gif = b"GIF89a".to_vec();
+        gif.extend_from_slice(&[0x40, 0x01, 0xC8, 0x00]);
+        let info = image_info(&gif).unwrap();
+        assert_eq!((info.width, info.height), (320, 200));
+    }
+
+    #[test]
+    fn test_jpeg_dimensions_skip_segments() {
+        // SOI, an APP0 segment, then a baseline SOF0 frame header
+        let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x04, 0x00, 0x00];
+        jpeg.extend_from_slice(&[0xFF, 0xC0, 0x00, 0x11, 0x08, 0x00, 0x78, 0x00, 0xA0]);
+        let info = image_info(&jpeg).unwrap();
+        assert_eq!(info.mime_type, "image/jpeg");
+        assert_eq!((info.width, info.height), (160, 120));
+    }
+
+    #[test]
+    fn test_webp_vp8x_dimensions() {
+        let mut webp = b"RIFF\0\0\0\0WEBPVP8X".to_vec();
+        webp.extend_from_slice(&[0; 8]);
+        webp.extend_from_slice(&[0xFF, 0x01, 0x00, 0x7F, 0x00, 0x00]);
+        let info = image_info(&webp).unwrap();
+        assert_eq!((info.width, info.height), (512, 128));
+    }
+
+    #[test]
+    fn test_unknown_and_truncated_images_rejected() {
+        assert_eq!(sniff_image_type(b"<svg xmlns=...>"), None);
+        assert!(image_info(b"\x89PNG\r\n\x1a\n").is_none());
+        assert!(image_info(&[0xFF, 0xD8, 0xFF]).is_none());
+    }
+
+    #[test]
+    fn test_sniff_mime_type_ignores_extension() {
+        assert_eq!(sniff_mime_type(b"%PDF-1.7 ..."), Some("application/pdf"));
+        assert_eq!(sniff_mime_type(b"\x00\x00\x00\x18ftypmp42"), Some("video/mp4"));
+        assert_eq!(sniff_mime_type("héllo".as_bytes()), Some("text/plain"));
+        // Windows executables are neither a known format nor text
+        assert_eq!(sniff_mime_type(b"MZ\x90\x00\x03\x00"), None);
+    }
+
+    #[test]
+    fn test_sanitize_filename() {
+        assert_eq!(sanitize_filename("../../etc/passwd"), Some("passwd".to_string()));
+        assert_eq!(sanitize_filename("C:\\Users\\me\\report.pdf"), Some("report.pdf".to_string()));
+        assert_eq!(sanitize_filename("  a<b>c\u{0}.txt "), Some("abc.txt".to_string()));
+        assert_eq!(sanitize_filename("..."), None);
+        assert_eq!(sanitize_filename(&"é".repeat(200)).unwrap().len(), 254);
+    }
+
+    #[test]
+    fn test_avatar_served_over_http() {
+        let user = Principal::from_text("rdmx6-jaaaa-aaaaa-aaadq-cai").unwrap();
+        state::with_avatars_mut(|avatars| {
+            avatars.insert(user, Avatar {
+                content_type: "image/png".to_string(),
+                data: png(8, 8),
+                width: 8,
+                height: 8,
+                uploaded_at: 0,
+            });
+        });
+        
+        let path = http::avatar_path(&user, &[0xAB; 32]);
+        assert_eq!(path, format!("/avatars/{}?v=abababababababab", user));
+        
+        let response = http::handle_request(get(&path), 0);
+        assert_eq!(response.status_code, 200);
+        assert_eq!(response.body, png(8, 8));
+        assert!(response.headers.contains(&("Content-Type".to_string(), "image/png".to_string())));
+        
+        let missing = http::handle_request(get("/avatars/aaaaa-aa"), 0);
+        assert_eq!(missing.status_code, 404);
+    }
+}
+
+// Chunked upload tests
+#[cfg(test)]
+mod upload_tests {
+    use crate::state::{self, AttachmentRecord, UploadSession};
+    use crate::cleanup_stale_uploads;
+    use candid::Principal;
+
+    const HOUR: u64 = 60 * 60 * 1_000_000_000;
+
+    fn session(id: u64, created_at: u64) -> UploadSession {
+        UploadSession {
+            id,
+            owner: Principal::anonymous(),
+            file_type: "text/plain".to_string(),
+            filename: "notes.txt".to_string(),
+            total_size: 1,
+            received_size: 0,
+            created_at,
+            encrypted_message_id: None,
+            encrypted_metadata: None,
+        }
+    }
+
+    fn attachment(id: u64, created_at: u64, message_id: Option<u64>) -> AttachmentRecord {
+        AttachmentRecord {
+            id,
+            owner: Principal::anonymous(),
+            file_type: "text/plain".to_string(),
+            filename: "notes.txt".to_string(),
+            size: 0,
+            sha256: vec![0; 32],
+            created_at,
+            message_id,
+            channel_id: None,
+            encrypted: false,
+            encrypted_metadata: None,
+            preview: None,
+        }
+    }
+
+    #[test]
+    fn test_stale_uploads_found_by_age() {
+        let now = 100 * HOUR;
+        state::insert_upload_session(session(1, now - 2 * HOUR));
+        state::insert_upload_session(session(2, now - HOUR));
+        state::insert_upload_session(session(3, now));
+        
+        // Sessions expire strictly after an hour
+        assert_eq!(state::upload_sessions_created_before(now - HOUR, 10), vec![1]);
+        
+        state::remove_upload(1);
+        assert_eq!(state::upload_sessions_created_before(now, 10), vec![2]);
+    }
+
+    #[test]
+    fn test_attached_uploads_leave_the_unattached_index() {
+        let now = 100 * HOUR;
+        state::insert_attachment(attachment(1, 0, None));
+        state::insert_attachment(attachment(2, 0, None));
+        state::insert_attachment(attachment(3, now, None));
+        assert_eq!(state::unattached_attachments_created_before(now, 10), vec![1, 2]);
+        
+        state::insert_attachment(attachment(2, 0, Some(7)));
+        assert_eq!(state::unattached_attachments_created_before(now, 10), vec![1]);
+        
+        state::remove_attachment(1);
+        assert!(state::unattached_attachments_created_before(now, 10).is_empty());
+    }
+
+    #[test]
+    fn test_cleanup_respects_budget() {
+        let now = 100 * HOUR;
+        for id in 1..=3 {
+            state::insert_upload_session(session(id, id));
+            state::insert_attachment(attachment(id, id, None));
+        }
+        state::insert_attachment(attachment(4, 4, Some(9)));
+        
+        assert_eq!(cleanup_stale_uploads(now, 4), (4, false));
+        assert_eq!(cleanup_stale_uploads(now, 4), (2, true));
+        assert_eq!(state::with_upload_sessions(|sessions| sessions.len()), 0);
+        assert_eq!(state::with_attachments(|attachments| attachments.len()), 1);
+    }
+
+    #[test]
+    fn test_assemble_upload_orders_chunks() {
+        state::with_upload_chunks_mut(|chunks| {
+            chunks.insert((1, 1), b"world".to_vec());
+            chunks.insert((1, 0), b"hello ".to_vec());
+            chunks.insert((2, 0), b"other upload".to_vec());
+        });
+        
+        assert_eq!(state::assemble_upload(1), Some(b"hello world".to_vec()));
+    }
+
+    #[test]
+    fn test_assemble_upload_rejects_gaps() {
+        state::with_upload_chunks_mut(|chunks| {
+            chunks.insert((1, 0), b"first".to_vec());
+            chunks.insert((1, 2), b"third".to_vec());
+        });
+        
+        assert_eq!(state::assemble_upload(1), None);
+    }
+
+    #[test]
+    fn test_remove_upload_only_touches_its_chunks() {
+        state::with_upload_chunks_mut(|chunks| {
+            chunks.insert((1, 0), b"a".to_vec());
+            chunks.insert((1, 1), b"b".to_vec());
+            chunks.insert((2, 0), b"c".to_vec());
+        });
+        
+        state::remove_upload(1);
+        
+        assert_eq!(state::with_upload_chunks(|chunks| chunks.len()), 1);
+        assert_eq!(state::assemble_upload(2), Some(b"c".to_vec()));
+    }
+}
+
+// HTTP interface tests
+#[cfg(test)]
+mod http_tests {
+    use crate::certification;
+    use crate::http::{self, parse_range, HttpRequest, HttpResponse};
+    use crate::state::{self, AttachmentRecord};
+    use crate::{can_read_attachment, Channel};
+    use candid::Principal;
+    use sha2::{Digest, Sha256};
+
+    const HOUR: u64 = 60 * 60 * 1_000_000_000;
+
+    fn request(url: &str, headers: Vec<(&str, &str)>) -> HttpRequest {
+        HttpRequest {
+            method: "GET".to_string(),
+            url: url.to_string(),
+            headers: headers.into_iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            body: vec![],
+            certificate_version: None,
+        }
+    }
+
+    /// Store a blob and an attachment record bound to a message in `channel_id`
+    fn store_attachment(id: u64, channel_id: Option<u64>, data: &[u8]) -> AttachmentRecord {
+        let content_hash: [u8; 32] = Sha256::digest(data).into();
+        let record = AttachmentRecord {
+            id,
+            owner: Principal::anonymous(),
+            file_type: "text/plain".to_string(),
+            filename: "notes.txt".to_string(),
+            size: data.len() as u64,
+            sha256: content_hash.to_vec(),
+            created_at: 0,
+            message_id: Some(100 + id),
+            channel_id,
+            encrypted: false,
+            encrypted_metadata: None,
+            preview: None,
+        };
+        state::with_blobs_mut(|blobs| blobs.insert(content_hash, data.to_vec()));
+        state::with_attachments_mut(|attachments| attachments.insert(id, record.clone()));
+        record
+    }
+
+    fn store_public_channel(id: u64) {
+        state::with_channels_mut(|channels| {
+            channels.insert(id, Channel {
+                id,
+                name: format!("channel-{}", id),
+                description: None,
+                created_by: Principal::anonymous(),
+                created_at: 0,
+                members: vec![],
+                message_count: 0,
+                last_message_at: None,
+                is_encrypted: false,
+                password_hash: None,
+                message_expiry: None,
+                retention: None,
+                key_epoch: None,
+            });
+        });
+    }
+
+    fn store_private_channel(id: u64) {
+        state::with_channels_mut(|channels| {
+            channels.insert(id, Channel {
+                id,
+                name: "🔒🔑 Private".to_string(),
+                description: None,
+                created_by: Principal::anonymous(),
+                created_at: 0,
+                members: vec![],
+                message_count: 0,
+                last_message_at: None,
+                is_encrypted: true,
+                password_hash: Some("hash".to_string()),
+                message_expiry: None,
+                retention: None,
+                key_epoch: None,
+            });
+        });
+    }
+
+    #[test]
+    fn test_public_attachments_are_readable_over_candid() {
+        let reader = Principal::from_slice(&[5]);
+        store_public_channel(8);
+        store_private_channel(9);
+        let records = [
+            store_attachment(1, None, b"a"),
+            store_attachment(2, Some(8), b"b"),
+            store_attachment(3, Some(9), b"c"),
+            store_attachment(4, Some(10), b"d"),
+            AttachmentRecord { message_id: None, ..store_attachment(5, None, b"e") },
+        ];
+        
+        // Whatever HTTP serves without a token, a non-member may also read
+        // through the Candid endpoints
+        for record in &records {
+            assert_eq!(
+                http::is_public_attachment(record),
+                can_read_attachment(&reader, record, 0),
+                "attachment {}",
+                record.id
+            );
+        }
+        assert!(http::is_public_attachment(&records[1]));
+        assert!(!http::is_public_attachment(&records[2]));
+        assert!(!http::is_public_attachment(&records[3]));
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-9", 100), Ok((0, 9)));
+        assert_eq!(parse_range("bytes=90-", 100), Ok((90, 99)));
+        assert_eq!(parse_range("bytes=-10", 100), Ok((90, 99)));
+        assert_eq!(parse_range("bytes=50-500", 100), Ok((50, 99)));
+        assert!(parse_range("bytes=100-", 100).is_err());
+        assert!(parse_range("bytes=5-1", 100).is_err());
+        assert!(parse_range("bytes=0-1,5-6", 100).is_err());
+        assert!(parse_range("items=0-1", 100).is_err());
+    }
+
+    #[test]
+    fn test_static_frontend_served() {
+        let response = http::handle_request(request("/", vec![]), 0);
+        assert_eq!(response.status_code, 200);
+        assert_eq!(response.header("content-type"), Some("text/html; charset=utf-8"));
+        assert_eq!(response.header("Cache-Control"), Some("no-cache"));
+        
+        // Client-side routes fall back to the app shell, missing files do not
+        assert_eq!(http::handle_request(request("/channels/5", vec![]), 0).status_code, 200);
+        assert_eq!(http::handle_request(request("/missing.js", vec![]), 0).status_code, 404);
+    }
+
+    #[test]
+    fn test_public_attachment_with_range() {
+        store_attachment(1, None, b"0123456789");
+        
+        let full = http::handle_request(request("/attachments/1", vec![]), 0);
+        assert_eq!(full.status_code, 200);
+        assert_eq!(full.body, b"0123456789");
+        assert_eq!(full.header("Content-Type"), Some("text/plain; charset=utf-8"));
+        assert_eq!(full.header("Cache-Control"), Some("public, max-age=31536000, immutable"));
+        
+        let partial = http::handle_request(request("/attachments/1", vec![("Range", "bytes=2-4")]), 0);
+        assert_eq!(partial.status_code, 206);
+        assert_eq!(partial.body, b"234");
+        assert_eq!(partial.header("Content-Range"), Some("bytes 2-4/10"));
+        
+        let etag = full.header("ETag").unwrap().to_string();
+        let cached = http::handle_request(request("/attachments/1", vec![("If-None-Match", &etag)]), 0);
+        assert_eq!(cached.status_code, 304);
+    }
+
+    #[test]
+    fn test_certified_attachments_are_served_whole() {
+        let record = store_attachment(1, None, b"0123456789");
+        certification::certify_attachments(&[record]);
+        
+        let full = http::handle_request(request("/attachments/1", vec![]), 0);
+        assert_eq!(full.header("ETag"), None);
+        assert_eq!(full.header("Accept-Ranges"), None);
+        
+        // Partial and 304 responses could not carry a certificate
+        let etag = format!("\"{}\"", hex::encode(Sha256::digest(b"0123456789")));
+        for header in [("Range", "bytes=2-4"), ("If-None-Match", etag.as_str())] {
+            let response = http::handle_request(request("/attachments/1", vec![header]), 0);
+            assert_eq!(response.status_code, 200);
+            assert_eq!(response.body, b"0123456789");
+        }
+    }
+
+    #[test]
+    fn test_private_attachment_requires_valid_token() {
+        store_private_channel(9);
+        let record = store_attachment(2, Some(9), b"secret");
+        let now = 1_000 * HOUR;
+        
+        let forbidden: HttpResponse = http::handle_request(request("/attachments/2", vec![]), now);
+        assert_eq!(forbidden.status_code, 403);
+        
+        // No signed URLs can be issued before the signing key exists
+        assert_eq!(http::attachment_url(&record, now), None);
+        state::set_url_signing_key([7; 32]);
+        let url = http::attachment_url(&record, now).unwrap();
+        
+        let allowed = http::handle_request(request(&url, vec![]), now);
+        assert_eq!(allowed.status_code, 200);
+        assert_eq!(allowed.body, b"secret");
+        assert_eq!(allowed.header("Cache-Control"), Some("private, max-age=3600"));
+        
+        let expired = http::handle_request(request(&url, vec![]), now + 2 * HOUR);
+        assert_eq!(expired.status_code, 403);
+        
+        let tampered = url.replace("/attachments/2", "/attachments/1");
+        store_attachment(1, Some(9), b"other");
+        assert_eq!(http::handle_request(request(&tampered, vec![]), now).status_code, 403);
+    }
+}
+
+// HTTP certification tests
+#[cfg(test)]
+mod certification_tests {
+    use super::fixtures;
+    use crate::certification::{self, channel_feed, rebuild_tree, root_hash};
+    use crate::{state, Channel, EncryptedMessage, Message, MessageType};
+    use candid::Principal;
+
+    fn store_channel(id: u64, password_hash: Option<String>) {
+        state::with_channels_mut(|channels| {
+            channels.insert(id, Channel {
+                id,
+                name: format!("channel-{}", id),
+                description: None,
+                created_by: Principal::anonymous(),
+                created_at: 0,
+                members: vec![],
+                message_count: 0,
+                last_message_at: None,
+                is_encrypted: false,
+                password_hash,
+                message_expiry: None,
+                retention: None,
+                key_epoch: None,
+            });
+        });
+    }
+
+    /// Store a channel message without adding it to the channel index
+    fn store_unindexed_message(id: u64, channel_id: u64) {
+        state::with_messages_mut(|messages| {
+            messages.insert(id, Message {
+                id,
+                author: Principal::anonymous(),
+                content: format!("message {}", id),
+                timestamp: id,
+                channel_id: Some(channel_id),
+                reply_to: None,
+                message_type: MessageType::Text,
+                attachments: vec![],
+            });
+        });
+    }
+
+    fn store_message(id: u64, channel_id: u64) {
+        store_unindexed_message(id, channel_id);
+        state::index_channel_message(channel_id, id);
+    }
+
+    fn store_encrypted_message(id: u64) -> EncryptedMessage {
+        let message = EncryptedMessage {
+            id,
+            encrypted_content: "secret".to_string(),
+            author: Principal::anonymous(),
+            timestamp: 0,
+            expires_at: None,
+            channel_id: None,
+            reply_to: None,
+            message_type: MessageType::Text,
+            shared_with: vec![],
+            attachments: vec![],
+            key_epoch: None,
+            ibe_recipient: None,
+        };
+        state::with_encrypted_messages_mut(|messages| messages.insert(id, message.clone()));
+        message
+    }
+
+    #[test]
+    fn test_static_assets_certified() {
+        rebuild_tree();
+        
+        assert!(certification::is_certified("/"));
+        assert!(certification::is_certified("/index.html"));
+        assert!(!certification::is_certified("/missing.js"));
+    }
+
+    #[test]
+    fn test_app_routes_certified_by_index() {
+        rebuild_tree();
+        
+        assert!(!certification::is_certified("/channels/5"));
+        assert!(certification::is_response_certified("/channels/5"));
+        assert!(!certification::is_response_certified("/missing.js"));
+        assert!(!certification::is_response_certified("/attachments/5"));
+        
+        // The fallback witness proves both the missing path and index.html
+        let fallback = certification::witness("/channels/5");
+        assert_ne!(fallback, certification::witness("/index.html"));
+        assert_ne!(fallback, certification::witness("/missing"));
+    }
+
+    #[test]
+    fn test_merged_witness_keeps_root_hash() {
+        rebuild_tree();
+        state::with_asset_hashes(|tree| {
+            let path = tree.witness(b"/channels/5");
+            let index = tree.witness(b"/index.html");
+            let merged = certification::merge_witnesses(path.clone(), index.clone());
+            assert_eq!(merged.reconstruct(), path.reconstruct());
+            assert_eq!(merged.reconstruct(), index.reconstruct());
+        });
+    }
+
+    #[test]
+    fn test_feed_reads_the_channel_index() {
+        store_channel(2, None);
+        for id in 1..=60 {
+            store_message(id, 2);
+        }
+        // Messages missing from the index are not part of the feed
+        store_unindexed_message(61, 2);
+        
+        let feed: serde_json::Value = serde_json::from_slice(&channel_feed(2).unwrap()).unwrap();
+        let ids: Vec<u64> = feed["messages"].as_array().unwrap().iter().map(|m| m["id"].as_u64().unwrap()).collect();
+        assert_eq!(ids, (11..=60).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn test_channel_feed_only_for_public_channels() {
+        store_channel(2, None);
+        store_channel(3, Some("hash".to_string()));
+        store_message(10, 2);
+        store_message(11, 3);
+        store_message(12, 2);
+        
+        let feed: serde_json::Value = serde_json::from_slice(&channel_feed(2).unwrap()).unwrap();
+        let ids: Vec<u64> = feed["messages"].as_array().unwrap().iter().map(|m| m["id"].as_u64().unwrap()).collect();
+        assert_eq!(ids, vec![10, 12]);
+        assert!(channel_feed(3).is_none());
+        assert!(channel_feed(4).is_none());
+        
+        // Channels from before feed hashes were stored
+        assert_eq!(fixtures::migrate(certification::certify_stored_channel_feeds, 1), 3);
+        assert!(certification::is_certified("/feeds/channels/2"));
+        assert!(!certification::is_certified("/feeds/channels/3"));
+    }
+
+    #[test]
+    fn test_root_hash_tracks_content() {
+        store_channel(2, None);
+        certification::certify_channel_feed(2);
+        let before = root_hash();
+        
+        store_message(10, 2);
+        certification::certify_channel_feed(2);
+        assert_ne!(root_hash(), before);
+    }
+
+    #[test]
+    fn test_rebuild_restores_stored_hashes_without_reading_content() {
+        store_channel(2, None);
+        store_message(10, 2);
+        certification::certify_channel_feed(2);
+        certification::certify_avatar(&Principal::anonymous(), b"avatar");
+        let avatar_path = certification::avatar_path(&Principal::anonymous());
+        
+        // The tree comes back from the stored hashes alone
+        state::with_channels_mut(|channels| channels.remove(&2));
+        rebuild_tree();
+        assert!(certification::is_certified("/feeds/channels/2"));
+        assert!(certification::is_certified(&avatar_path));
+        
+        certification::uncertify_avatar(&Principal::anonymous());
+        certification::certify_channel_feed(2);
+        rebuild_tree();
+        assert!(!certification::is_certified(&avatar_path));
+        assert!(!certification::is_certified("/feeds/channels/2"));
+    }
+
+    #[test]
+    fn test_encrypted_messages_certified_on_rebuild() {
+        let message = store_encrypted_message(5);
+        certification::certify_encrypted_message(&message);
+        rebuild_tree();
+        
+        assert!(certification::is_certified(&certification::encrypted_message_path(5)));
+        assert!(!certification::is_certified(&certification::encrypted_message_path(6)));
+    }
+
+    #[test]
+    fn test_rebuild_uses_stored_encrypted_message_hashes() {
+        for id in 1..=3 {
+            store_encrypted_message(id);
+        }
+        rebuild_tree();
+        assert!(!certification::is_certified(&certification::encrypted_message_path(1)));
+        
+        assert_eq!(fixtures::migrate(certification::certify_stored_encrypted_messages, 2), 2);
+        for id in 1..=3 {
+            assert!(certification::is_certified(&certification::encrypted_message_path(id)));
+        }
+        let certified = root_hash();
+        
+        rebuild_tree();
+        assert_eq!(root_hash(), certified);
+        
+        certification::uncertify_encrypted_message(2);
+        rebuild_tree();
+        assert!(!certification::is_certified(&certification::encrypted_message_path(2)));
+    }
+
+    #[test]
+    fn test_witness_is_self_describing_cbor() {
+        rebuild_tree();
+        let witness = certification::witness("/index.html");
+        
+        // CBOR self-describe tag 55799
+        assert_eq!(&witness[..3], &[0xD9, 0xD9, 0xF7]);
+    }
+}
+
+// Storage quota tests
+#[cfg(test)]
+mod quota_tests {
+    use crate::state::{self, User};
+    use crate::{
+        abort_upload, cleanup_stale_uploads, finish_upload, set_default_quota, set_user_quota, start_upload,
+        storage_usage_of, store_chunk, BeginUploadRequest, ChatError, ADMIN_PRINCIPAL, MAX_OPEN_UPLOADS_PER_USER,
+        UPLOAD_SESSION_TTL_NS,
+    };
+    use candid::Principal;
+    use sha2::{Digest, Sha256};
+    use std::collections::HashMap;
+
+    fn user() -> Principal {
+        Principal::from_text("rdmx6-jaaaa-aaaaa-aaadq-cai").unwrap()
+    }
+
+    fn admin() -> Principal {
+        Principal::from_text(ADMIN_PRINCIPAL).unwrap()
+    }
+
+    fn register_user() {
+        state::with_users_mut(|users| {
+            users.insert(user(), User {
+                user_principal: user(),
+                username: user().to_text(),
+                avatar_url: None,
+                bio: None,
+                joined_at: 0,
+                message_count: 0,
+                last_active: 0,
+                encrypted_keys: HashMap::new(),
+            });
+        });
+    }
+
+    fn begin(size: u64) -> Result<u64, ChatError> {
+        start_upload(&user(), BeginUploadRequest {
+            filename: "notes.txt".to_string(),
+            file_type: "text/plain".to_string(),
+            total_size: size,
+        }, 0)
+    }
+
+    /// Upload `data` in one chunk through the endpoints
+    fn upload(upload_id: u64, data: &[u8]) -> Result<u64, ChatError> {
+        store_chunk(&user(), upload_id, 0, data.to_vec())?;
+        finish_upload(&user(), upload_id, Sha256::digest(data).to_vec(), 0).map(|attachment| attachment.size)
+    }
+
+    #[test]
+    fn test_usage_tracked_incrementally() {
+        state::add_storage_usage(user(), 1_000);
+        state::add_storage_usage(user(), 500);
+        assert_eq!(state::storage_usage(&user()), 1_500);
+        
+        state::release_storage_usage(user(), 1_000);
+        assert_eq!(state::storage_usage(&user()), 500);
+        
+        // Releasing more than is charged never underflows
+        state::release_storage_usage(user(), 10_000);
+        assert_eq!(state::storage_usage(&user()), 0);
+    }
+
+    #[test]
+    fn test_quota_override_and_default() {
+        assert_eq!(state::storage_quota(&user()), 100_000_000);
+        
+        state::set_default_storage_quota(50_000_000);
+        assert_eq!(state::storage_quota(&user()), 50_000_000);
+        
+        state::set_storage_quota_override(user(), Some(1_000));
+        assert_eq!(state::storage_quota(&user()), 1_000);
+        
+        state::set_storage_quota_override(user(), None);
+        assert_eq!(state::storage_quota(&user()), 50_000_000);
+    }
+
+    #[test]
+    fn test_uploads_are_held_to_the_quota() {
+        register_user();
+        set_user_quota(&admin(), user(), Some(10)).unwrap();
+        
+        assert_eq!(begin(11), Err(ChatError::AttachmentTooLarge));
+        let first = begin(6).unwrap();
+        // Open sessions reserve their size until they end
+        assert_eq!(begin(5), Err(ChatError::AttachmentTooLarge));
+        let second = begin(4).unwrap();
+        
+        assert_eq!(upload(first, b"first!"), Ok(6));
+        assert_eq!(storage_usage_of(&user(), None).map(|usage| usage.used_bytes), Ok(6));
+        assert_eq!(begin(1), Err(ChatError::AttachmentTooLarge));
+        
+        abort_upload(&user(), second).unwrap();
+        assert_eq!(state::open_uploads(&user()), (0, 0));
+        assert_eq!(begin(4).map(|_| ()), Ok(()));
+    }
+
+    #[test]
+    fn test_expired_sessions_release_their_reservation() {
+        register_user();
+        set_user_quota(&admin(), user(), Some(10)).unwrap();
+        
+        begin(10).unwrap();
+        assert_eq!(begin(1), Err(ChatError::AttachmentTooLarge));
+        
+        cleanup_stale_uploads(UPLOAD_SESSION_TTL_NS + 1, 10);
+        assert_eq!(state::open_uploads(&user()), (0, 0));
+        assert_eq!(begin(10).map(|_| ()), Ok(()));
+    }
+
+    #[test]
+    fn test_open_uploads_are_capped_per_user() {
+        register_user();
+        
+        let uploads: Vec<u64> = (0..MAX_OPEN_UPLOADS_PER_USER).map(|_| begin(1).unwrap()).collect();
+        assert_eq!(begin(1), Err(ChatError::InvalidInput));
+        
+        assert_eq!(upload(uploads[0], b"x"), Ok(1));
+        assert_eq!(begin(1).map(|_| ()), Ok(()));
+    }
+
+    #[test]
+    fn test_quota_endpoints_are_admin_only() {
+        let other = Principal::from_slice(&[7]);
+        
+        assert_eq!(set_default_quota(&user(), 1), Err(ChatError::NotAuthorized));
+        assert_eq!(set_user_quota(&user(), user(), Some(1)), Err(ChatError::NotAuthorized));
+        assert_eq!(state::storage_quota(&user()), 100_000_000);
+        
+        assert_eq!(set_default_quota(&admin(), 1_000), Ok(()));
+        assert_eq!(set_user_quota(&admin(), other, Some(5)), Ok(()));
+        
+        assert_eq!(storage_usage_of(&user(), None).map(|usage| usage.quota_bytes), Ok(1_000));
+        assert!(storage_usage_of(&user(), Some(other)).is_err());
+        assert_eq!(storage_usage_of(&admin(), Some(other)).map(|usage| usage.quota_bytes), Ok(5));
+    }
+}
+
+// Encrypted attachment tests
+#[cfg(test)]
+mod encrypted_attachment_tests {
+    use crate::state::{self, Attachment, AttachmentRecord, EncryptedMessage, MessageType};
+    use crate::{check_encrypted_attachment, can_read_attachment, ChatError, MAX_ATTACHMENTS_PER_MESSAGE};
+    use candid::{CandidType, Principal};
+    use serde::Deserialize;
+
+    fn owner() -> Principal {
+        Principal::from_text("rdmx6-jaaaa-aaaaa-aaadq-cai").unwrap()
+    }
+
+    fn other() -> Principal {
+        Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap()
+    }
+
+    fn store_message(id: u64, attachments: Vec<Attachment>) {
+        state::with_encrypted_messages_mut(|messages| {
+            messages.insert(id, EncryptedMessage {
+                id,
+                encrypted_content: "secret".to_string(),
+                author: owner(),
+                timestamp: 0,
+                expires_at: Some(1_000),
+                channel_id: None,
+                reply_to: None,
+                message_type: MessageType::Text,
+                shared_with: vec![],
+                attachments,
+                key_epoch: None,
+                ibe_recipient: None,
+            });
+        });
+    }
+
+    fn encrypted_attachment(id: u64, size: u64) -> Attachment {
+        Attachment {
+            id,
+            file_type: "application/octet-stream".to_string(),
+            filename: String::new(),
+            size,
+            data: vec![],
+            preview: None,
+            encrypted_metadata: Some(vec![1, 2, 3]),
+        }
+    }
+
+    #[test]
+    fn test_only_author_can_attach_before_expiry() {
+        store_message(1, vec![]);
+        
+        assert_eq!(check_encrypted_attachment(&owner(), 1, 100, 10), Ok(None));
+        assert_eq!(check_encrypted_attachment(&other(), 1, 100, 10), Err(ChatError::NotAuthorized));
+        assert_eq!(check_encrypted_attachment(&owner(), 1, 100, 2_000), Err(ChatError::NotAuthorized));
+        assert_eq!(check_encrypted_attachment(&owner(), 2, 100, 10), Err(ChatError::NotFound));
+    }
+
+    #[test]
+    fn test_message_attachment_limits() {
+        let full = (0..MAX_ATTACHMENTS_PER_MESSAGE as u64).map(|id| encrypted_attachment(id, 1)).collect();
+        store_message(1, full);
+        assert_eq!(check_encrypted_attachment(&owner(), 1, 1, 10), Err(ChatError::InvalidInput));
+        
+        store_message(2, vec![encrypted_attachment(1, 9_000_000)]);
+        assert_eq!(check_encrypted_attachment(&owner(), 2, 1_000_000, 10), Ok(None));
+        assert_eq!(check_encrypted_attachment(&owner(), 2, 1_000_001, 10), Err(ChatError::AttachmentTooLarge));
+    }
+
+    #[test]
+    fn test_encrypted_attachment_follows_message_authorization() {
+        store_message(1, vec![]);
+        let record = AttachmentRecord {
+            id: 5,
+            owner: owner(),
+            file_type: "application/octet-stream".to_string(),
+            filename: String::new(),
+            size: 3,
+            sha256: vec![0; 32],
+            created_at: 0,
+            message_id: Some(1),
+            channel_id: None,
+            encrypted: true,
+            encrypted_metadata: Some(vec![1, 2, 3]),
+            preview: None,
+        };
+        
+        assert!(can_read_attachment(&owner(), &record, 10));
+        assert!(!can_read_attachment(&other(), &record, 10));
+        
+        state::with_encrypted_messages_mut(|messages| {
+            let mut message = messages.get(&1).unwrap();
+            message.shared_with.push(other());
+            messages.insert(1, message);
+        });
+        assert!(can_read_attachment(&other(), &record, 10));
+        assert!(!can_read_attachment(&other(), &record, 2_000));
+    }
+
+    #[test]
+    fn test_records_without_encrypted_metadata_still_decode() {
+        #[derive(CandidType, Deserialize)]
+        struct PreviousAttachment {
+            id: u64,
+            file_type: String,
+            filename: String,
+            size: u64,
+            data: Vec<u8>,
+        }
+        
+        let bytes = candid::encode_one(PreviousAttachment {
+            id: 1,
+            file_type: "image/png".to_string(),
+            filename: "a.png".to_string(),
+            size: 3,
+            data: vec![],
+        }).unwrap();
+        let attachment: Attachment = candid::decode_one(&bytes).unwrap();
+        
+        assert_eq!(attachment.filename, "a.png");
+        assert_eq!(attachment.encrypted_metadata, None);
+    }
+}
+
+// Blob deduplication tests
+#[cfg(test)]
+mod dedup_tests {
+    use crate::state::{self, AttachmentRecord};
+    use crate::remove_attachments;
+    use candid::Principal;
+    use sha2::{Digest, Sha256};
+
+    fn hash(data: &[u8]) -> [u8; 32] {
+        Sha256::digest(data).into()
+    }
+
+    fn has_blob(data: &[u8]) -> bool {
+        state::with_blobs(|blobs| blobs.contains_key(&hash(data)))
+    }
+
+    fn store_record(id: u64, data: &[u8]) {
+        state::with_attachments_mut(|attachments| {
+            attachments.insert(id, AttachmentRecord {
+                id,
+                owner: Principal::anonymous(),
+                file_type: "text/plain".to_string(),
+                filename: "notes.txt".to_string(),
+                size: data.len() as u64,
+                sha256: hash(data).to_vec(),
+                created_at: 0,
+                message_id: None,
+                channel_id: None,
+                encrypted: false,
+                encrypted_metadata: None,
+                preview: None,
+            });
+        });
+    }
+
+    #[test]
+    fn test_identical_content_stored_once() {
+        state::store_blob(hash(b"same"), b"same".to_vec());
+        state::store_blob(hash(b"same"), b"same".to_vec());
+        state::retain_blob(hash(b"same"));
+        
+        assert_eq!(state::with_blobs(|blobs| blobs.len()), 1);
+    }
+
+    #[test]
+    fn test_blob_freed_with_last_reference() {
+        state::store_blob(hash(b"shared"), b"shared".to_vec());
+        store_record(1, b"shared");
+        state::retain_blob(hash(b"shared"));
+        store_record(2, b"shared");
+        
+        remove_attachments(&[1]);
+        assert!(has_blob(b"shared"));
+        
+        remove_attachments(&[2]);
+        assert!(!has_blob(b"shared"));
+    }
+}
+
+// Stored schema version tests
+#[cfg(test)]
+mod schema_tests {
+    use crate::schema::{self, DecodeError};
+    use crate::state::{self, Attachment, Channel, EncryptedMessage, Message, MessageType, User};
+    use candid::Principal;
+    use std::collections::HashMap;
+
+    // Bare Candid written before stored values were versioned, using the
+    // original shapes with inline attachment bytes
+    const MESSAGE_V0: &str = "4449444c066c08dbb70178979aec1c01b99adecb0171b091c4bf02018bd5ef9b0768d6a9bbae0a78f28eb69c0c02b0becfb90e036e786b038f96dcbb027fcdf1cbbe037fbb9eda84057f6d046c04aaac8d930405c1c1cee204789dfdd0f80671c7dda8bb07716d7b010007000000000000000101000000000000000568656c6c6f00010a0000000000000007010100002a36fe9c9717020103010203030000000000000009696d6167652f706e67076361742e706e67";
+    const ENCRYPTED_MESSAGE_V0: &str = "4449444c076c0adbb70178979aec1c01b091c4bf0201be898eb604718bd5ef9b0768d6a9bbae0a78f28eb69c0c02a0faafee0c03dea7f7da0d78b0becfb90e046e786b038f96dcbb027fcdf1cbbe037fbb9eda84057f6d686d056c04aaac8d930406c1c1cee204789dfdd0f80671c7dda8bb07716d7b0100080000000000000001020000000000000000086332566a636d5630010a0000000000000007010100002a36fe9c97170101010104000079c792eb97170102686902000000000000000a746578742f706c61696e05612e747874";
+    const USER_V0: &str = "4449444c046c08c8f1aa0201cfeadc6402fa90c17f68968cae87027189e295b60401cfbfdfe0067897a283bf0878e9e7fac109786e716d036c020071017101000102686901096d6573736167655f38036b6579010a0000000000000007010105616c6963650000002a36fe9c9717030000000000000000002a36fe9c9717";
+    const CHANNEL_V0: &str = "4449444c046c0adbb70178b28db43401d9d9c0d30102cbe4fdc70471fc91f4f80501b4c48e9a0603aaacd9d006788eaed9d0066897a283bf0878afb4d29a0a7e6e716d686e7801000200000000000000010361626301010a00000000000000070101067365637265740101640100002a36fe9c971700002a36fe9c9717010a00000000000000070101010000000000000001";
+
+    fn fixture(hex_bytes: &str) -> Vec<u8> {
+        hex::decode(hex_bytes).unwrap()
+    }
+
+    fn author() -> Principal {
+        Principal::from_text("rdmx6-jaaaa-aaaaa-aaadq-cai").unwrap()
+    }
+
+    #[test]
+    fn test_decode_message_v0_with_inline_attachments() {
+        let message: Message = schema::decode(&fixture(MESSAGE_V0)).unwrap();
+        
+        assert_eq!(message.id, 7);
+        assert_eq!(message.author, author());
+        assert_eq!(message.content, "hello");
+        assert_eq!(message.channel_id, Some(1));
+        assert_eq!(message.message_type, MessageType::Image);
+        // Inline attachments are dropped rather than left without an ID
+        assert!(message.attachments.is_empty());
+    }
+
+    #[test]
+    fn test_decode_encrypted_message_v0() {
+        let message: EncryptedMessage = schema::decode(&fixture(ENCRYPTED_MESSAGE_V0)).unwrap();
+        
+        assert_eq!(message.id, 8);
+        assert_eq!(message.encrypted_content, "c2VjcmV0");
+        assert_eq!(message.expires_at, Some(1_700_086_400_000_000_000));
+        assert_eq!(message.shared_with, vec![Principal::anonymous()]);
+        assert!(message.attachments.is_empty());
+        
+        // Re-encoding writes the current version
+        assert_eq!(schema::encode(&message)[0], 1);
+    }
+
+    #[test]
+    fn test_decode_user_and_channel_v0() {
+        let user: User = schema::decode(&fixture(USER_V0)).unwrap();
+        assert_eq!(user.username, "alice");
+        assert_eq!(user.bio.as_deref(), Some("hi"));
+        assert_eq!(user.encrypted_keys.get("message_8").map(String::as_str), Some("key"));
+        
+        let channel: Channel = schema::decode(&fixture(CHANNEL_V0)).unwrap();
+        assert_eq!(channel.name, "secret");
+        assert!(channel.is_encrypted);
+        assert_eq!(channel.members, vec![author()]);
+        assert_eq!(channel.password_hash.as_deref(), Some("abc"));
+        assert_eq!(channel.message_expiry, None);
+    }
+
+    #[test]
+    fn test_current_version_roundtrip() {
+        let message = Message {
+            id: 1,
+            author: author(),
+            content: "hi".to_string(),
+            timestamp: 5,
+            channel_id: None,
+            reply_to: None,
+            message_type: MessageType::Text,
+            attachments: vec![Attachment {
+                id: 3,
+                file_type: "text/plain".to_string(),
+                filename: "a.txt".to_string(),
+                size: 2,
+                data: vec![],
+                preview: None,
+                encrypted_metadata: None,
+            }],
+        };
+        
+        let bytes = schema::encode(&message);
+        assert_eq!(bytes[0], 1);
+        
+        let decoded: Message = schema::decode(&bytes).unwrap();
+        assert_eq!(decoded.content, "hi");
+        assert_eq!(decoded.attachments[0].id, 3);
+    }
+
+    #[test]
+    fn test_decode_errors_are_reported() {
+        assert_eq!(schema::decode::<User>(&[]).err(), Some(DecodeError::Empty));
+        
+        let mut future = fixture(USER_V0);
+        future.insert(0, 9);
+        assert_eq!(schema::decode::<User>(&future).err(), Some(DecodeError::UnknownVersion(9)));
+        
+        let mut truncated = fixture(USER_V0);
+        truncated.truncate(20);
+        assert!(matches!(schema::decode::<User>(&truncated), Err(DecodeError::Candid(_))));
+    }
+
+    #[test]
+    fn test_undecodable_records_are_skipped_on_read() {
+        let readable = User {
+            user_principal: Principal::from_slice(&[1]),
+            username: "reader".to_string(),
+            avatar_url: None,
+            bio: None,
+            joined_at: 0,
+            message_count: 0,
+            last_active: 0,
+            encrypted_keys: HashMap::new(),
+        };
+        let mut future = fixture(USER_V0);
+        future.insert(0, 9);
+        let broken = Principal::from_slice(&[9]);
+        state::with_users_mut(|users| users.insert(readable.user_principal, readable.clone()));
+        state::with_users_mut(|users| users.insert_encoded(broken, future));
+        
+        state::with_users(|users| {
+            assert_eq!(users.try_get(&broken).err(), Some(DecodeError::UnknownVersion(9)));
+            assert!(users.get(&broken).is_none());
+            assert!(users.contains_key(&broken));
+            
+            let listed: Vec<Principal> = users.iter().map(|(principal, _)| principal).collect();
+            assert_eq!(listed, vec![readable.user_principal]);
+        });
+        
+        // The upgrade check samples both ends of the map, so it sees the record
+        assert!(state::verify_stored_records(1).unwrap_err().contains("unknown schema version 9"));
+    }
+
+    #[test]
+    fn test_upgrade_check_reads_a_bounded_sample() {
+        for id in 1..=50 {
+            state::with_messages_mut(|messages| {
+                messages.insert(id, Message {
+                    id,
+                    author: Principal::anonymous(),
+                    content: format!("message {}", id),
+                    timestamp: id,
+                    channel_id: Some(1),
+                    reply_to: None,
+                    message_type: MessageType::Text,
+                    attachments: vec![],
+                });
+            });
+        }
+        
+        assert_eq!(state::verify_stored_records(5), Ok(10));
+        assert_eq!(state::verify_stored_records(100), Ok(50));
+    }
+}
+
+// Channel ID allocation tests
+#[cfg(test)]
+mod channel_id_tests {
+    use crate::state::{self, Channel};
+    use candid::Principal;
+
+    fn store_channel(id: u64) {
+        state::with_channels_mut(|channels| {
+            channels.insert(id, Channel {
+                id,
+                name: format!("channel-{}", id),
+                description: None,
+                created_by: Principal::anonymous(),
+                created_at: 0,
+                members: vec![],
+                message_count: 0,
+                last_message_at: None,
+                is_encrypted: false,
+                password_hash: None,
+                message_expiry: None,
+                retention: None,
+                key_epoch: None,
+            });
+        });
+    }
+
+    #[test]
+    fn test_channel_ids_skip_existing_channels() {
+        // As after init, which stores the General channel as 1
+        store_channel(1);
+        store_channel(3);
+
+        assert_eq!(state::next_channel_id(), 2);
+        assert_eq!(state::next_channel_id(), 4);
+    }
+}
+
+// Upgrade tests
+#[cfg(test)]
+mod upgrade_tests {
+    use crate::state::{self, EncryptedMessage, MessageType, UpgradeRecord};
+    use crate::{apply_canister_args, key_name_argument, run_migration_batch, CanisterArgs, MIGRATIONS};
+    use candid::Principal;
+    use ic_stable_structures::Storable;
+    use std::borrow::Cow;
+
+    #[test]
+    fn test_migrations_run_once() {
+        let mut record = UpgradeRecord::default();
+        while run_migration_batch(&mut record, 1) {}
+        assert_eq!(record.migration_version, MIGRATIONS.len() as u32);
+        assert_eq!(record.migration_cursor, None);
+        
+        // Nothing is left to run, and a newer recorded version is kept
+        assert!(!run_migration_batch(&mut record, 1));
+        record.migration_version += 5;
+        assert!(!run_migration_batch(&mut record, 1));
+        assert_eq!(record.migration_version, MIGRATIONS.len() as u32 + 5);
+    }
+
+    #[test]
+    fn test_migration_version_advances_when_a_migration_finishes() {
+        for id in 1..=3 {
+            state::with_encrypted_messages_mut(|messages| {
+                messages.insert(id, EncryptedMessage {
+                    id,
+                    encrypted_content: "secret".to_string(),
+                    author: Principal::anonymous(),
+                    timestamp: 0,
+                    expires_at: None,
+                    channel_id: None,
+                    reply_to: None,
+                    message_type: MessageType::Text,
+                    shared_with: vec![],
+                    attachments: vec![],
+                    key_epoch: None,
+                    ibe_recipient: None,
+                });
+            });
+        }
+
+        // The expiry index is rebuilt one message per batch
+        let mut record = UpgradeRecord::default();
+        assert!(run_migration_batch(&mut record, 1));
+        assert_eq!(record.migration_version, 0);
+        assert!(record.migration_cursor.is_some());
+        
+        while record.migration_version == 0 {
+            run_migration_batch(&mut record, 1);
+        }
+        assert_eq!(record.migration_cursor, None);
+    }
+
+    #[test]
+    fn test_upgrade_record_roundtrip() {
+        let record = UpgradeRecord {
+            migration_version: 1,
+            migration_cursor: Some(vec![1, 2]),
+            last_upgraded_at: Some(42),
+            upgrade_count: 3,
+        };
+        
+        state::set_upgrade_record(record.clone());
+        assert_eq!(state::upgrade_record(), record);
+        assert_eq!(UpgradeRecord::from_bytes(Cow::Owned(record.to_bytes().into_owned())), record);
+    }
+
+    #[test]
+    fn test_old_key_name_argument_is_recognised() {
+        let old = candid::encode_one(Some("old_key")).unwrap();
+        
+        // The typed argument cannot see it
+        let (args,): (Option<CanisterArgs>,) = candid::decode_args(&old).unwrap();
+        assert!(args.is_none());
+        
+        let args = key_name_argument(&old).unwrap();
+        assert_eq!(args.vetkd_key_name.as_deref(), Some("old_key"));
+        assert_eq!(args.cleanup_interval_secs, None);
+        
+        let new = candid::encode_one(Some(CanisterArgs { vetkd_key_name: None, cleanup_interval_secs: Some(600) })).unwrap();
+        assert!(key_name_argument(&new).is_none());
+        assert!(key_name_argument(&candid::encode_args(()).unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_canister_args_override_settings() {
+        assert_eq!(state::cleanup_interval_secs(), 3600);
+        
+        apply_canister_args(&CanisterArgs {
+            vetkd_key_name: Some(" test_key_1 ".to_string()),
+            cleanup_interval_secs: Some(600),
+        });
+        assert_eq!(state::cleanup_interval_secs(), 600);
+        assert_eq!(state::with_key_name(|key_name| key_name.get().clone()), "test_key_1");
+        
+        // Unset fields leave the current values alone
+        apply_canister_args(&CanisterArgs::default());
+        assert_eq!(state::cleanup_interval_secs(), 600);
+        assert_eq!(state::with_key_name(|key_name| key_name.get().clone()), "test_key_1");
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_invalid_cleanup_interval_rejected() {
+        apply_canister_args(&CanisterArgs {
+            vetkd_key_name: None,
+            cleanup_interval_secs: Some(1),
+        });
+    }
+}
+
+// Expiry cleanup tests
+#[cfg(test)]
+mod expiry_tests {
+    use super::fixtures;
+    use crate::certification;
+    use crate::state::{self, EncryptedMessage, MessageExpiry, MessageType};
+    use crate::{cleanup_expired_batch, message_expires_at, ChatError};
+    use candid::Principal;
+
+    const HOUR_SECS: u64 = 60 * 60;
+    const HOUR_NS: u64 = HOUR_SECS * 1_000_000_000;
+
+    fn store_message(id: u64, expires_at: u64, indexed: bool) {
+        state::with_encrypted_messages_mut(|messages| {
+            messages.insert(id, EncryptedMessage {
+                id,
+                encrypted_content: "secret".to_string(),
+                author: Principal::anonymous(),
+                timestamp: 0,
+                expires_at: Some(expires_at),
+                channel_id: None,
+                reply_to: None,
+                message_type: MessageType::Text,
+                shared_with: vec![],
+                attachments: vec![],
+                key_epoch: None,
+                ibe_recipient: None,
+            });
+        });
+        if indexed {
+            state::index_message_expiry(expires_at, id);
+        }
+    }
+
+    fn message_count() -> u64 {
+        state::with_encrypted_messages(|messages| messages.len())
+    }
+
+    #[test]
+    fn test_cleanup_uncertifies_expired_messages() {
+        store_message(1, 100, true);
+        let path = certification::encrypted_message_path(1);
+        certification::certify_encrypted_message(&state::with_encrypted_messages(|messages| messages.get(&1)).unwrap());
+        assert_eq!(state::next_expiry(), Some(100));
+        
+        assert_eq!(cleanup_expired_batch(1_000, 10), (1, true));
+        assert!(!certification::is_certified(&path));
+        assert_eq!(state::next_expiry(), None);
+        
+        certification::rebuild_tree();
+        assert!(!certification::is_certified(&path));
+    }
+
+    #[test]
+    fn test_cleanup_respects_budget() {
+        for id in 1..=5 {
+            store_message(id, 100 + id, true);
+        }
+        store_message(6, 10_000, true);
+        
+        assert_eq!(cleanup_expired_batch(1_000, 2), (2, false));
+        assert_eq!(cleanup_expired_batch(1_000, 2), (2, false));
+        assert_eq!(cleanup_expired_batch(1_000, 2), (1, true));
+        assert_eq!(cleanup_expired_batch(1_000, 2), (0, true));
+        
+        assert_eq!(message_count(), 1);
+        assert!(state::with_encrypted_messages(|messages| messages.contains_key(&6)));
+    }
+
+    #[test]
+    fn test_expiry_is_exclusive_and_oldest_first() {
+        store_message(1, 500, true);
+        store_message(2, 200, true);
+        store_message(3, 1_000, true);
+        
+        // A message expires only once the current time is past expires_at
+        assert_eq!(state::expired_messages(1_000, 10), vec![(200, 2), (500, 1)]);
+    }
+
+    #[test]
+    fn test_stale_index_entries_are_dropped() {
+        state::index_message_expiry(50, 99);
+        
+        assert_eq!(cleanup_expired_batch(1_000, 10), (0, true));
+        assert!(state::expired_messages(1_000, 10).is_empty());
+    }
+
+    #[test]
+    fn test_rebuild_indexes_existing_messages() {
+        store_message(1, 100, false);
+        store_message(2, 5_000, false);
+        assert_eq!(cleanup_expired_batch(1_000, 10), (0, true));
+        
+        assert_eq!(fixtures::migrate(state::rebuild_message_expiry_index, 1), 3);
+        assert_eq!(cleanup_expired_batch(1_000, 10), (1, true));
+        assert_eq!(message_count(), 1);
+    }
+
+    #[test]
+    fn test_expiry_defaults_to_channel_setting() {
+        assert_eq!(message_expires_at(MessageExpiry::OneHour, None, 10), Ok(Some(10 + HOUR_NS)));
+        assert_eq!(message_expires_at(MessageExpiry::SevenDays, None, 10), Ok(Some(10 + 7 * 24 * HOUR_NS)));
+        assert_eq!(message_expires_at(MessageExpiry::Never, None, 10), Ok(None));
+    }
+
+    #[test]
+    fn test_message_ttl_capped_by_channel() {
+        assert_eq!(message_expires_at(MessageExpiry::OneDay, Some(HOUR_SECS), 10), Ok(Some(10 + HOUR_NS)));
+        assert_eq!(message_expires_at(MessageExpiry::OneDay, Some(24 * HOUR_SECS), 10), Ok(Some(10 + 24 * HOUR_NS)));
+        assert_eq!(message_expires_at(MessageExpiry::OneDay, Some(24 * HOUR_SECS + 1), 10), Err(ChatError::InvalidInput));
+        assert_eq!(message_expires_at(MessageExpiry::OneDay, Some(0), 10), Err(ChatError::InvalidInput));
+        assert_eq!(message_expires_at(MessageExpiry::Never, Some(30 * 24 * HOUR_SECS), 10), Ok(Some(10 + 30 * 24 * HOUR_NS)));
+        assert_eq!(message_expires_at(MessageExpiry::Never, Some(u64::MAX), 10), Err(ChatError::InvalidInput));
+    }
+
+    #[test]
+    fn test_messages_without_expiry_are_kept() {
+        store_message(1, 100, false);
+        state::with_encrypted_messages_mut(|messages| {
+            let mut message = messages.get(&1).unwrap();
+            message.expires_at = None;
+            assert!(!message.is_expired(u64::MAX));
+            messages.insert(1, message);
+        });
+        
+        fixtures::migrate(state::rebuild_message_expiry_index, 10);
+        assert_eq!(cleanup_expired_batch(u64::MAX, 10), (0, true));
+        assert_eq!(message_count(), 1);
+    }
+}
+
+#[cfg(test)]
+mod retention_tests {
+    use super::fixtures;
+    use crate::state::{self, Channel, Message, MessageType, RetentionPolicy, User};
+    use crate::{enforce_channel_retention, update_channel_retention, ChatError};
+    use candid::Principal;
+    use std::collections::HashMap;
+
+    const DAY_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+    fn store_channel(id: u64, retention: Option<RetentionPolicy>) {
+        state::with_channels_mut(|channels| {
+            channels.insert(id, Channel {
+                id,
+                name: format!("channel-{}", id),
+                description: None,
+                created_by: Principal::anonymous(),
+                created_at: 0,
+                members: vec![],
+                message_count: 0,
+                last_message_at: None,
+                is_encrypted: false,
+                password_hash: None,
+                message_expiry: None,
+                retention,
+                key_epoch: None,
+            });
+        });
+    }
+
+    fn store_owned_channel(id: u64, owner: Principal, is_encrypted: bool) {
+        state::with_channels_mut(|channels| {
+            channels.insert(id, Channel {
+                id,
+                name: format!("channel-{}", id),
+                description: None,
+                created_by: owner,
+                created_at: 0,
+                members: vec![],
+                message_count: 0,
+                last_message_at: None,
+                is_encrypted,
+                password_hash: None,
+                message_expiry: None,
+                retention: None,
+                key_epoch: None,
+            });
+        });
+    }
+
+    fn store_user(principal: Principal, message_count: u64) {
+        state::with_users_mut(|users| {
+            users.insert(principal, User {
+                user_principal: principal,
+                username: principal.to_text(),
+                avatar_url: None,
+                bio: None,
+                joined_at: 0,
+                message_count,
+                last_active: 0,
+                encrypted_keys: HashMap::new(),
+            });
+        });
+    }
+
+    fn store_message(id: u64, channel_id: u64, timestamp: u64) {
+        state::with_messages_mut(|messages| {
+            messages.insert(id, Message {
+                id,
+                author: Principal::anonymous(),
+                content: format!("message {}", id),
+                timestamp,
+                channel_id: Some(channel_id),
+                reply_to: None,
+                message_type: MessageType::Text,
+                attachments: vec![],
+            });
+        });
+        state::index_channel_message(channel_id, id);
+        state::with_channels_mut(|channels| {
+            let mut channel = channels.get(&channel_id).unwrap();
+            channel.message_count += 1;
+            channels.insert(channel_id, channel);
+        });
+    }
+
+    fn remaining(channel_id: u64) -> Vec<u64> {
+        state::oldest_channel_messages(channel_id, usize::MAX)
+    }
+
+    fn message_count(channel_id: u64) -> u64 {
+        state::with_channels(|channels| channels.get(&channel_id).unwrap().message_count)
+    }
+
+    #[test]
+    fn test_max_messages_keeps_newest() {
+        store_channel(1, Some(RetentionPolicy::MaxMessages(3)));
+        store_channel(2, Some(RetentionPolicy::MaxMessages(3)));
+        for id in 1..=5 {
+            store_message(id, 1, id);
+        }
+        store_message(6, 2, 6);
+        
+        assert_eq!(enforce_channel_retention(1, 0, 100), 2);
+        assert_eq!(remaining(1), vec![3, 4, 5]);
+        assert_eq!(message_count(1), 3);
+        assert!(state::with_messages(|messages| messages.get(&1).is_none()));
+        
+        // Other channels are untouched
+        assert_eq!(enforce_channel_retention(2, 0, 100), 0);
+        assert_eq!(remaining(2), vec![6]);
+    }
+
+    #[test]
+    fn test_max_age_stops_at_first_recent_message() {
+        store_channel(1, Some(RetentionPolicy::MaxAgeDays(7)));
+        let now = 30 * DAY_NS;
+        store_message(1, 1, now - 10 * DAY_NS);
+        store_message(2, 1, now - 8 * DAY_NS);
+        store_message(3, 1, now - DAY_NS);
+        store_message(4, 1, now);
+        
+        assert_eq!(enforce_channel_retention(1, now, 100), 2);
+        assert_eq!(remaining(1), vec![3, 4]);
+        assert_eq!(message_count(1), 2);
+    }
+
+    #[test]
+    fn test_retention_respects_budget() {
+        store_channel(1, Some(RetentionPolicy::MaxMessages(1)));
+        for id in 1..=5 {
+            store_message(id, 1, id);
+        }
+        
+        assert_eq!(enforce_channel_retention(1, 0, 2), 2);
+        assert_eq!(enforce_channel_retention(1, 0, 2), 2);
+        assert_eq!(enforce_channel_retention(1, 0, 2), 0);
+        assert_eq!(remaining(1), vec![5]);
+        assert_eq!(message_count(1), 1);
+    }
+
+    #[test]
+    fn test_channels_without_policy_are_untouched() {
+        store_channel(1, None);
+        store_message(1, 1, 0);
+        
+        assert_eq!(enforce_channel_retention(1, u64::MAX, 100), 0);
+        assert_eq!(remaining(1), vec![1]);
+    }
+
+    #[test]
+    fn test_rebuild_indexes_messages_and_recounts_channels() {
+        store_channel(1, None);
+        store_channel(2, None);
+        for id in 1..=3 {
+            store_message(id, 1, id);
+        }
+        // Drop the index and corrupt the count, as for data stored before the index
+        for id in 1..=3 {
+            state::unindex_channel_message(1, id);
+        }
+        state::with_channels_mut(|channels| {
+            let mut channel = channels.get(&2).unwrap();
+            channel.message_count = 9;
+            channels.insert(2, channel);
+        });
+        
+        fixtures::migrate(state::rebuild_channel_message_index, 2);
+        assert_eq!(remaining(1), vec![1, 2, 3]);
+        assert_eq!(message_count(1), 3);
+        assert_eq!(message_count(2), 0);
+    }
+
+    #[test]
+    fn test_retention_updates_author_message_counts() {
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2]);
+        store_user(alice, 2);
+        store_user(bob, 1);
+        store_channel(1, Some(RetentionPolicy::MaxMessages(1)));
+        for (id, author) in [(1, alice), (2, bob), (3, alice)] {
+            store_message(id, 1, id);
+            state::with_messages_mut(|messages| {
+                let mut message = messages.get(&id).unwrap();
+                message.author = author;
+                messages.insert(id, message);
+            });
+        }
+        
+        assert_eq!(enforce_channel_retention(1, 0, 100), 2);
+        let count = |user| state::with_users(|users| users.get(&user).unwrap().message_count);
+        assert_eq!(count(alice), 1);
+        assert_eq!(count(bob), 0);
+    }
+
+    #[test]
+    fn test_encrypted_channels_reject_retention() {
+        let owner = Principal::from_slice(&[1]);
+        store_owned_channel(1, owner, false);
+        store_owned_channel(2, owner, true);
+        let policy = Some(RetentionPolicy::MaxMessages(10));
+        
+        assert!(update_channel_retention(&owner, 1, policy).is_ok());
+        assert!(matches!(update_channel_retention(&owner, 2, policy), Err(ChatError::InvalidInput)));
+        assert!(state::with_channels(|channels| channels.get(&2).unwrap().retention.is_none()));
+    }
+}
+
+#[cfg(test)]
+mod share_tests {
+    use super::fixtures;
+    use crate::state::{self, EncryptedMessage, MessageIds, MessageType, User};
+    use crate::{block, message_shares, own_and_shared_messages, share_message, unshare_message, ChatError};
+    use candid::Principal;
+    use std::collections::HashMap;
+
+    fn owner() -> Principal {
+        Principal::from_slice(&[1])
+    }
+
+    fn friend() -> Principal {
+        Principal::from_slice(&[2])
+    }
+
+    fn setup() {
+        for user in [owner(), friend()] {
+            state::with_users_mut(|users| {
+                users.insert(user, User {
+                    user_principal: user,
+                    username: user.to_text(),
+                    avatar_url: None,
+                    bio: None,
+                    joined_at: 0,
+                    message_count: 0,
+                    last_active: 0,
+                    encrypted_keys: HashMap::new(),
+                });
+            });
+        }
+        state::with_encrypted_messages_mut(|messages| {
+            messages.insert(1, EncryptedMessage {
+                id: 1,
+                encrypted_content: "secret".to_string(),
+                author: owner(),
+                timestamp: 0,
+                expires_at: None,
+                channel_id: None,
+                reply_to: None,
+                message_type: MessageType::Text,
+                shared_with: vec![],
+                attachments: vec![],
+                key_epoch: None,
+                ibe_recipient: None,
+            });
+        });
+        state::add_message_owner(owner(), 1);
+    }
+
+    fn visible_to(user: Principal) -> Vec<u64> {
+        own_and_shared_messages(&user, 0, None, None).messages.iter().map(|m| m.id).collect()
+    }
+
+    #[test]
+    fn test_share_and_unshare_endpoints() {
+        setup();
+        
+        assert_eq!(share_message(&owner(), 1, friend()), Ok(()));
+        assert_eq!(share_message(&owner(), 1, friend()), Ok(()));
+        assert_eq!(message_shares(&owner(), 1), Ok(vec![friend()]));
+        assert_eq!(visible_to(friend()), vec![1]);
+        
+        assert_eq!(unshare_message(&owner(), 1, friend()), Ok(()));
+        assert_eq!(message_shares(&owner(), 1), Ok(vec![]));
+        assert!(visible_to(friend()).is_empty());
+    }
+
+    #[test]
+    fn test_only_the_author_manages_shares() {
+        setup();
+        share_message(&owner(), 1, friend()).unwrap();
+        
+        assert_eq!(share_message(&friend(), 1, owner()), Err(ChatError::NotAuthorized));
+        assert_eq!(unshare_message(&friend(), 1, friend()), Err(ChatError::NotAuthorized));
+        assert_eq!(message_shares(&friend(), 1), Err(ChatError::NotAuthorized));
+        assert_eq!(share_message(&owner(), 2, friend()), Err(ChatError::NotFound));
+        assert_eq!(visible_to(friend()), vec![1]);
+    }
+
+    #[test]
+    fn test_share_recipients_are_checked() {
+        setup();
+        
+        assert_eq!(share_message(&owner(), 1, Principal::from_slice(&[9])), Err(ChatError::NotFound));
+        
+        block(friend(), owner()).unwrap();
+        assert_eq!(share_message(&owner(), 1, friend()), Err(ChatError::NotAuthorized));
+        assert_eq!(message_shares(&owner(), 1), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_share_index_is_scoped_to_user() {
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2]);
+        state::add_message_share(alice, 3);
+        state::add_message_share(alice, 1);
+        state::add_message_share(bob, 2);
+        state::add_message_share(alice, 1);
+        
+        assert_eq!(state::shared_message_ids(&alice, None, usize::MAX), vec![3, 1]);
+        assert_eq!(state::shared_message_ids(&bob, None, usize::MAX), vec![2]);
+        
+        // Removing unknown entries is a no-op
+        state::remove_message_share(alice, 7);
+        state::remove_message_share(Principal::anonymous(), 2);
+        state::remove_message_share(alice, 1);
+        assert_eq!(state::shared_message_ids(&alice, None, usize::MAX), vec![3]);
+        assert_eq!(state::shared_message_ids(&bob, None, usize::MAX), vec![2]);
+        assert!(state::shared_message_ids(&Principal::anonymous(), None, usize::MAX).is_empty());
+    }
+
+    #[test]
+    fn test_owner_index_range_covers_extreme_ids() {
+        let owner = Principal::from_slice(&[1]);
+        state::add_message_owner(owner, 0);
+        state::add_message_owner(owner, u64::MAX);
+        state::add_message_owner(Principal::from_slice(&[1, 0]), 5);
+        
+        assert_eq!(state::owned_message_ids(&owner, None, usize::MAX), vec![u64::MAX, 0]);
+        
+        state::remove_message_owner(owner, 0);
+        assert_eq!(state::owned_message_ids(&owner, None, usize::MAX), vec![u64::MAX]);
+    }
+
+    #[test]
+    fn test_migrate_legacy_message_id_lists() {
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2]);
+        state::with_legacy_encrypted_message_owners_mut(|owners| {
+            owners.insert(alice, MessageIds { ids: vec![4, 1] });
+            owners.insert(bob, MessageIds { ids: vec![2] });
+        });
+        state::with_legacy_encrypted_message_shares_mut(|shares| {
+            shares.insert(bob, MessageIds { ids: vec![1] });
+        });
+        
+        // Lists not yet migrated are still read and updated
+        assert!(state::migrate_encrypted_message_indexes(None, 1).is_some());
+        assert_eq!(state::owned_message_ids(&alice, None, usize::MAX), vec![4, 1]);
+        assert_eq!(state::shared_message_ids(&bob, None, usize::MAX), vec![1]);
+        state::remove_message_owner(bob, 2);
+        assert!(state::owned_message_ids(&bob, None, usize::MAX).is_empty());
+        state::add_message_owner(bob, 2);
+        
+        fixtures::migrate(state::migrate_encrypted_message_indexes, 1);
+        assert_eq!(state::owned_message_ids(&alice, None, usize::MAX), vec![4, 1]);
+        assert_eq!(state::owned_message_ids(&bob, None, usize::MAX), vec![2]);
+        assert_eq!(state::shared_message_ids(&bob, None, usize::MAX), vec![1]);
+        assert!(state::with_legacy_encrypted_message_owners_mut(|owners| owners.len() == 0));
+        assert!(state::with_legacy_encrypted_message_shares_mut(|shares| shares.len() == 0));
+        
+        // Running again finds nothing left to move
+        assert_eq!(state::migrate_encrypted_message_indexes(None, 1), None);
+        assert_eq!(state::owned_message_ids(&alice, None, usize::MAX), vec![4, 1]);
+    }
+}
+
+#[cfg(test)]
+mod ibe_message_tests {
+    use crate::state::{self, MessageType, User};
+    use crate::{block, decrypt_message, share_message, store_ibe_message, ChatError};
+    use candid::Principal;
+    use std::collections::HashMap;
+    
+    fn sender() -> Principal {
+        Principal::from_slice(&[1])
+    }
+
+    fn recipient() -> Principal {
+        Principal::from_slice(&[2])
+    }
+
+    fn register(user: Principal) {
+        state::with_users_mut(|users| {
+            users.insert(user, User {
+                user_principal: user,
+                username: user.to_text(),
+                avatar_url: None,
+                bio: None,
+                joined_at: 0,
+                message_count: 0,
+                last_active: 0,
+                encrypted_keys: HashMap::new(),
+            });
+        });
+    }
+
+    fn setup() {
+        for user in [sender(), recipient()] {
+            register(user);
+        }
+    }
+
+    fn send(from: Principal, to: Principal) -> Result<u64, ChatError> {
+        store_ibe_message(&from, to, "ciphertext".to_string(), None, MessageType::Text, None, 0)
+    }
+
+    #[test]
+    fn test_ibe_message_is_shared_with_recipient() {
+        setup();
+        
+        let message_id = send(sender(), recipient()).unwrap();
+        let message = state::with_encrypted_messages(|messages| messages.get(&message_id)).unwrap();
+        assert_eq!(message.ibe_recipient, Some(recipient()));
+        assert_eq!(message.shared_with, vec![recipient()]);
+        assert_eq!(state::shared_message_ids(&recipient(), None, usize::MAX), vec![message_id]);
+    }
+
+    #[test]
+    fn test_no_ibe_message_to_self() {
+        setup();
+        
+        assert_eq!(send(sender(), sender()), Err(ChatError::InvalidInput));
+    }
+
+    #[test]
+    fn test_blocked_sender_cannot_send_ibe_message() {
+        setup();
+        block(recipient(), sender()).unwrap();
+        
+        assert_eq!(send(sender(), recipient()), Err(ChatError::NotAuthorized));
+        assert_eq!(send(recipient(), sender()).map(|_| ()), Ok(()));
+    }
+
+    #[test]
+    fn test_ibe_message_needs_registered_recipient() {
+        setup();
+        
+        assert_eq!(send(sender(), Principal::from_slice(&[3])), Err(ChatError::NotFound));
+        assert_eq!(send(Principal::from_slice(&[3]), recipient()), Err(ChatError::NotAuthorized));
+    }
+
+    #[test]
+    fn test_ibe_message_cannot_be_shared() {
+        setup();
+        register(Principal::from_slice(&[3]));
+        let message_id = send(sender(), recipient()).unwrap();
+        
+        assert_eq!(share_message(&sender(), message_id, Principal::from_slice(&[3])), Err(ChatError::InvalidInput));
+    }
+
+    #[tokio::test]
+    async fn test_ibe_message_is_not_decrypted_by_canister() {
+        setup();
+        let message_id = send(sender(), recipient()).unwrap();
+        
+        for caller in [sender(), recipient()] {
+            assert_eq!(
+                decrypt_message(&caller, message_id, 0).await,
+                Err("Message is encrypted to its recipient's identity key".to_string())
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod encrypted_read_tests {
+    use super::fixtures;
+    use crate::state::{self, Channel, EncryptedMessage, MessageType};
+    use crate::{channel_encrypted_messages, own_and_shared_messages, ChatError, EncryptedMessagePage};
+    use candid::Principal;
+
+    fn user() -> Principal {
+        Principal::from_slice(&[1])
+    }
+
+    fn store_message(id: u64, author: Principal, channel_id: Option<u64>) {
+        state::with_encrypted_messages_mut(|messages| {
+            messages.insert(id, EncryptedMessage {
+                id,
+                encrypted_content: "secret".to_string(),
+                author,
+                timestamp: 0,
+                expires_at: None,
+                channel_id,
+                reply_to: None,
+                message_type: MessageType::Text,
+                shared_with: vec![],
+                attachments: vec![],
+                key_epoch: None,
+                ibe_recipient: None,
+            });
+        });
+    }
+
+    fn store_owned(id: u64) {
+        store_message(id, user(), None);
+        state::add_message_owner(user(), id);
+    }
+
+    fn store_shared(id: u64) {
+        store_message(id, Principal::anonymous(), None);
+        state::add_message_share(user(), id);
+    }
+
+    fn store_channel(id: u64, is_encrypted: bool, members: Vec<Principal>) {
+        state::with_channels_mut(|channels| {
+            channels.insert(id, Channel {
+                id,
+                name: format!("channel-{}", id),
+                description: None,
+                created_by: Principal::anonymous(),
+                created_at: 0,
+                members,
+                message_count: 0,
+                last_message_at: None,
+                is_encrypted,
+                password_hash: None,
+                message_expiry: None,
+                retention: None,
+                key_epoch: None,
+            });
+        });
+    }
+
+    fn ids(page: &EncryptedMessagePage) -> Vec<u64> {
+        page.messages.iter().map(|m| m.id).collect()
+    }
+
+    #[test]
+    fn test_pages_are_newest_first() {
+        for id in [1, 3, 5] {
+            store_owned(id);
+        }
+        for id in [2, 4] {
+            store_shared(id);
+        }
+        
+        let page = own_and_shared_messages(&user(), 0, None, Some(2));
+        assert_eq!(ids(&page), vec![5, 4]);
+        assert_eq!(page.next_before, Some(4));
+        
+        let page = own_and_shared_messages(&user(), 0, Some(4), Some(2));
+        assert_eq!(ids(&page), vec![3, 2]);
+        let page = own_and_shared_messages(&user(), 0, page.next_before, Some(2));
+        assert_eq!(ids(&page), vec![1]);
+        assert_eq!(page.next_before, None);
+    }
+
+    #[test]
+    fn test_page_size_is_capped() {
+        for id in 1..=150 {
+            store_owned(id);
+        }
+        
+        let page = own_and_shared_messages(&user(), 0, None, Some(1_000));
+        assert_eq!(page.messages.len(), 100);
+        assert_eq!(page.next_before, Some(51));
+        
+        let page = own_and_shared_messages(&user(), 0, Some(1), Some(1_000));
+        assert!(page.messages.is_empty());
+        assert_eq!(page.next_before, None);
+    }
+
+    #[test]
+    fn test_skipped_messages_do_not_shorten_pages() {
+        for id in 1..=6 {
+            store_owned(id);
+        }
+        // Messages 3 to 5 expired at 10
+        for id in 3..=5 {
+            state::with_encrypted_messages_mut(|messages| {
+                let mut message = messages.get(&id).unwrap();
+                message.expires_at = Some(10);
+                messages.insert(id, message);
+            });
+        }
+        
+        let page = own_and_shared_messages(&user(), 11, None, Some(2));
+        assert_eq!(ids(&page), vec![6, 2]);
+        assert_eq!(page.next_before, Some(2));
+        let page = own_and_shared_messages(&user(), 11, page.next_before, Some(2));
+        assert_eq!(ids(&page), vec![1]);
+        assert_eq!(page.next_before, None);
+    }
+
+    #[test]
+    fn test_channel_pages_are_for_members_only() {
+        store_channel(7, true, vec![user()]);
+        store_channel(8, false, vec![]);
+        for id in 1..=3 {
+            store_channel_message(id, Some(7));
+            state::index_encrypted_channel_message(7, id);
+        }
+        
+        let page = channel_encrypted_messages(&user(), 7, 0, None, Some(2)).unwrap();
+        assert_eq!(ids(&page), vec![3, 2]);
+        let page = channel_encrypted_messages(&user(), 7, 0, page.next_before, Some(2)).unwrap();
+        assert_eq!(ids(&page), vec![1]);
+        
+        let outsider = Principal::from_slice(&[2]);
+        assert!(matches!(channel_encrypted_messages(&outsider, 7, 0, None, None), Err(ChatError::NotAuthorized)));
+        assert!(matches!(channel_encrypted_messages(&user(), 8, 0, None, None), Err(ChatError::ChannelNotFound)));
+    }
+
+    fn store_channel_message(id: u64, channel_id: Option<u64>) {
+        store_message(id, Principal::anonymous(), channel_id);
+    }
+
+    fn channel_ids(channel_id: u64) -> Vec<u64> {
+        state::encrypted_channel_message_ids(channel_id, None, usize::MAX)
+    }
+
+    #[test]
+    fn test_channel_messages_are_read_from_the_index() {
+        store_channel_message(1, Some(7));
+        store_channel_message(2, Some(8));
+        store_channel_message(3, Some(7));
+        store_channel_message(4, None);
+        
+        // Messages the rebuild has not reached are still found
+        assert_eq!(channel_ids(7), vec![3, 1]);
+        assert_eq!(state::encrypted_channel_message_ids(7, Some(3), 1), vec![1]);
+        fixtures::migrate(state::rebuild_encrypted_channel_index, 1);
+        assert_eq!(channel_ids(7), vec![3, 1]);
+        assert_eq!(channel_ids(8), vec![2]);
+        
+        // Once indexed, other channels' messages are no longer scanned
+        store_channel_message(5, Some(7));
+        state::index_encrypted_channel_message(7, 6);
+        assert_eq!(channel_ids(7), vec![6, 3, 1]);
+    }
+
+    #[test]
+    fn test_channel_index_rebuild_interleaves_with_writes() {
+        for id in 1..=4 {
+            store_channel_message(id, Some(7));
+        }
+        let cursor = state::rebuild_encrypted_channel_index(None, 2);
+        
+        store_channel_message(5, Some(7));
+        state::index_encrypted_channel_message(7, 5);
+        assert_eq!(channel_ids(7), vec![5, 4, 3, 2, 1]);
+        
+        assert!(state::rebuild_encrypted_channel_index(cursor.as_deref(), 2).is_some());
+        assert_eq!(channel_ids(7), vec![5, 4, 3, 2, 1]);
+    }
+}
+
+#[cfg(test)]
+mod vetkd_tests {
+    use crate::state::{self, EncryptedMessage, MessageType};
+    use crate::vetkd::{ibe_identity, message_input, KeyContext};
+    use candid::Principal;
+
+    fn message(channel_id: Option<u64>, key_epoch: Option<u32>) -> EncryptedMessage {
+        EncryptedMessage {
+            id: 9,
+            encrypted_content: "secret".to_string(),
+            author: Principal::from_slice(&[1]),
+            timestamp: 0,
+            expires_at: None,
+            channel_id,
+            reply_to: None,
+            message_type: MessageType::Text,
+            shared_with: vec![],
+            attachments: vec![],
+            key_epoch,
+            ibe_recipient: None,
+        }
+    }
+
+    #[test]
+    fn test_message_context_follows_channel_epoch() {
+        assert_eq!(
+            KeyContext::for_message(&message(Some(4), Some(2))),
+            KeyContext::Channel { channel_id: 4, epoch: 2 }
+        );
+        assert_eq!(
+            KeyContext::for_message(&message(Some(4), Some(0))),
+            KeyContext::Channel { channel_id: 4, epoch: 0 }
+        );
+        assert_eq!(
+            KeyContext::for_message(&message(None, Some(0))),
+            KeyContext::User(Principal::from_slice(&[1]))
+        );
+    }
+
+    #[test]
+    fn test_messages_without_epoch_keep_legacy_context() {
+        for message in [message(Some(4), None), message(None, None)] {
+            let context = KeyContext::for_message(&message);
+            assert_eq!(context, KeyContext::LegacyMessage(9));
+            assert_eq!(context.to_bytes(), b"message_9");
+            assert!(context.message_input(9).is_empty());
+        }
+        assert_eq!(KeyContext::User(Principal::anonymous()).message_input(9), message_input(9));
+    }
+
+    #[test]
+    fn test_contexts_are_distinct() {
+        let contexts = [
+            KeyContext::Channel { channel_id: 1, epoch: 0 },
+            KeyContext::Channel { channel_id: 1, epoch: 1 },
+            KeyContext::Channel { channel_id: 2, epoch: 0 },
+            KeyContext::User(Principal::anonymous()),
+            KeyContext::User(Principal::from_slice(&[1])),
+            KeyContext::Ibe,
+            KeyContext::LegacyMessage(1),
+        ];
+        let bytes: std::collections::HashSet<Vec<u8>> = contexts.iter().map(KeyContext::to_bytes).collect();
+        
+        assert_eq!(bytes.len(), contexts.len());
+        assert!(contexts[0].to_bytes().starts_with(b"chat_z/channel/"));
+        assert!(contexts[3].to_bytes().starts_with(b"chat_z/user/"));
+        assert_eq!(message_input(1), vec![0, 0, 0, 0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_ibe_identity_is_the_principal() {
+        let user = Principal::from_text("rdmx6-jaaaa-aaaaa-aaadq-cai").unwrap();
+        
+        assert_eq!(ibe_identity(&user), user.as_slice());
+        assert_ne!(ibe_identity(&user), ibe_identity(&Principal::anonymous()));
+        // Identities live in a single context shared by all users
+        assert_eq!(KeyContext::Ibe.to_bytes(), b"chat_z/ibe");
+    }
+
+    #[test]
+    fn test_public_keys_cached_per_context() {
+        let channel = KeyContext::Channel { channel_id: 1, epoch: 0 }.to_bytes();
+        assert_eq!(state::cached_public_key(&channel), None);
+        
+        state::cache_public_key(channel.clone(), vec![1, 2, 3]);
+        assert_eq!(state::cached_public_key(&channel), Some(vec![1, 2, 3]));
+        assert_eq!(state::cached_public_key(&KeyContext::User(Principal::anonymous()).to_bytes()), None);
+    }
+}
+
+#[cfg(test)]
+mod vault_tests {
+    use crate::state::{self, User, VaultKey};
+    use crate::{delete_vault_key, put_vault_key, ChatError, MAX_VAULT_KEYS, MAX_WRAPPED_KEY_BYTES};
+    use candid::Principal;
+    use ic_stable_structures::Storable;
+    use std::collections::HashMap;
+    
+    fn owner() -> Principal {
+        Principal::from_slice(&[1])
+    }
+
+    fn put(context: &str, wrapped_key: &str) -> Result<(), ChatError> {
+        put_vault_key(owner(), context.to_string(), wrapped_key.to_string())
+    }
+
+    fn register(user: Principal) {
+        state::with_users_mut(|users| {
+            users.insert(user, User {
+                user_principal: user,
+                username: user.to_text(),
+                avatar_url: None,
+                bio: None,
+                joined_at: 0,
+                message_count: 0,
+                last_active: 0,
+                encrypted_keys: HashMap::new(),
+            });
+        });
+    }
+
+    fn setup() {
+        register(owner());
+    }
+
+    #[test]
+    fn test_put_replaces_existing_key() {
+        setup();
+        assert_eq!(put("channel_1", "a2V5"), Ok(()));
+        assert_eq!(put("channel_1", "bmV3"), Ok(()));
+        
+        assert_eq!(state::vault_contexts(owner()), vec!["channel_1"]);
+        assert_eq!(state::vault_key(owner(), "channel_1".to_string()), Some("bmV3".to_string()));
+    }
+
+    #[test]
+    fn test_put_validates_sizes() {
+        setup();
+        
+        assert_eq!(put(" ", "a2V5"), Err(ChatError::InvalidInput));
+        assert_eq!(put(&"x".repeat(129), "a2V5"), Err(ChatError::InvalidInput));
+        assert_eq!(put("ctx", ""), Err(ChatError::InvalidInput));
+        assert_eq!(put("ctx", &"k".repeat(MAX_WRAPPED_KEY_BYTES + 1)), Err(ChatError::InvalidInput));
+        assert!(state::vault_contexts(owner()).is_empty());
+    }
+
+    #[test]
+    fn test_put_enforces_key_count() {
+        setup();
+        for i in 0..MAX_VAULT_KEYS {
+            put(&format!("ctx_{}", i), "a2V5").unwrap();
+        }
+        
+        assert_eq!(put("one_more", "a2V5"), Err(ChatError::InvalidInput));
+        // Replacing a stored key is still allowed at the limit
+        assert_eq!(put("ctx_0", "bmV3"), Ok(()));
+    }
+
+    #[test]
+    fn test_vault_needs_registration() {
+        assert_eq!(put("ctx", "a2V5"), Err(ChatError::NotAuthorized));
+        assert_eq!(delete_vault_key(owner(), "ctx".to_string()), Err(ChatError::NotAuthorized));
+    }
+
+    #[test]
+    fn test_vaults_are_per_user() {
+        setup();
+        let other = Principal::from_slice(&[1, 0]);
+        register(other);
+        put("ctx", "a2V5").unwrap();
+        put_vault_key(other, "ctx".to_string(), "b3RoZXI=".to_string()).unwrap();
+        
+        assert_eq!(state::vault_key(owner(), "ctx".to_string()), Some("a2V5".to_string()));
+        assert_eq!(delete_vault_key(other, "ctx".to_string()), Ok(()));
+        assert_eq!(delete_vault_key(other, "ctx".to_string()), Err(ChatError::NotFound));
+        assert_eq!(state::vault_contexts(owner()), vec!["ctx"]);
+        assert!(state::vault_contexts(other).is_empty());
+    }
+
+    #[test]
+    fn test_vault_key_encoding_round_trips() {
+        let key = VaultKey { user: owner(), context: "x".repeat(state::MAX_KEY_CONTEXT_BYTES) };
+        assert_eq!(VaultKey::from_bytes(key.to_bytes()), key);
+    }
+}
+
+// Key derivation flows against the local management canister
+#[cfg(test)]
+mod key_flow_tests {
+    use crate::state::{self, AttachmentRecord, Channel, EncryptedMessage, MessageType, User};
+    use crate::vetkd::{self, KeyContext, LocalManagementCanister};
+    use crate::{attachment_key, channel_public_key, ibe_decryption_key, message_key};
+    use candid::Principal;
+    use std::collections::HashMap;
+
+    fn owner() -> Principal {
+        Principal::from_slice(&[1])
+    }
+
+    fn member() -> Principal {
+        Principal::from_slice(&[2])
+    }
+
+    fn outsider() -> Principal {
+        Principal::from_slice(&[3])
+    }
+
+    fn transport() -> Vec<u8> {
+        vec![7; 48]
+    }
+
+    fn store_user(principal: Principal) {
+        state::with_users_mut(|users| {
+            users.insert(principal, User {
+                user_principal: principal,
+                username: principal.to_text(),
+                avatar_url: None,
+                bio: None,
+                joined_at: 0,
+                message_count: 0,
+                last_active: 0,
+                encrypted_keys: HashMap::new(),
+            });
+        });
+    }
+
+    fn store_channel(id: u64, is_encrypted: bool, key_epoch: Option<u32>) {
+        state::with_channels_mut(|channels| {
+            channels.insert(id, Channel {
+                id,
+                name: "🔒 Keys".to_string(),
+                description: None,
+                created_by: owner(),
+                created_at: 0,
+                members: vec![owner(), member()],
+                message_count: 0,
+                last_message_at: None,
+                is_encrypted,
+                password_hash: None,
+                message_expiry: None,
+                retention: None,
+                key_epoch,
+            });
+        });
+    }
+
+    fn store_message(id: u64, channel_id: Option<u64>, key_epoch: Option<u32>, ibe_recipient: Option<Principal>) {
+        state::with_encrypted_messages_mut(|messages| {
+            messages.insert(id, EncryptedMessage {
+                id,
+                encrypted_content: "secret".to_string(),
+                author: owner(),
+                timestamp: 0,
+                expires_at: Some(1_000),
+                channel_id,
+                reply_to: None,
+                message_type: MessageType::Text,
+                shared_with: vec![],
+                attachments: vec![],
+                key_epoch,
+                ibe_recipient,
+            });
+        });
+    }
+
+    #[tokio::test]
+    async fn test_message_key_requires_access() {
+        let management = LocalManagementCanister::default();
+        store_channel(1, true, None);
+        store_message(10, Some(1), None, None);
+        
+        let key = message_key(&management, &owner(), 10, transport(), 0).await.unwrap();
+        assert_eq!(key.len(), 192);
+        assert_eq!(message_key(&management, &member(), 10, transport(), 0).await, Ok(key));
+        assert!(message_key(&management, &outsider(), 10, transport(), 0).await.is_err());
+        assert!(message_key(&management, &owner(), 11, transport(), 0).await.is_err());
+        
+        // Expired messages have no key
+        assert!(message_key(&management, &owner(), 10, transport(), 1_001).await.is_err());
+        assert_eq!(management.derive_key_calls.get(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_message_keys_are_encrypted_to_the_transport_key() {
+        let management = LocalManagementCanister::default();
+        store_channel(1, true, None);
+        store_message(10, Some(1), Some(0), None);
+        
+        let key = message_key(&management, &owner(), 10, transport(), 0).await.unwrap();
+        assert_ne!(message_key(&management, &owner(), 10, vec![8; 48], 0).await, Ok(key));
+        
+        for malformed in [vec![], vec![7; 47], vec![7; 96]] {
+            assert_eq!(
+                message_key(&management, &owner(), 10, malformed, 0).await,
+                Err("Invalid transport public key".to_string())
+            );
+        }
+        assert_eq!(management.derive_key_calls.get(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_local_management_canister_checks_transport_keys() {
+        let management = LocalManagementCanister::default();
+        
+        assert!(vetkd::derive_key(&management, &KeyContext::Ibe, vec![1], vec![]).await.is_err());
+        assert!(vetkd::derive_key(&management, &KeyContext::Ibe, vec![1], vec![7; 49]).await.is_err());
+        assert_eq!(vetkd::derive_key(&management, &KeyContext::Ibe, vec![1], transport()).await.map(|key| key.len()), Ok(192));
+    }
+
+    #[tokio::test]
+    async fn test_message_keys_follow_context() {
+        let management = LocalManagementCanister::default();
+        store_channel(1, true, Some(1));
+        store_message(10, Some(1), Some(0), None);
+        store_message(11, Some(1), Some(0), None);
+        store_message(12, Some(1), Some(1), None);
+        store_message(13, None, Some(0), None);
+        store_message(14, Some(1), None, None);
+        
+        let mut keys = Vec::new();
+        for id in 10..=14 {
+            keys.push(message_key(&management, &owner(), id, transport(), 0).await.unwrap());
+        }
+        keys.sort();
+        keys.dedup();
+        assert_eq!(keys.len(), 5);
+        
+        // Messages stored before key epochs keep their original context
+        assert_eq!(
+            message_key(&management, &owner(), 14, transport(), 0).await,
+            vetkd::derive_key(&management, &KeyContext::LegacyMessage(14), vec![], transport()).await
+        );
+        
+        // Derivation is deterministic
+        assert_eq!(
+            message_key(&management, &owner(), 12, transport(), 0).await,
+            vetkd::derive_key(&management, &KeyContext::Channel { channel_id: 1, epoch: 1 }, vetkd::message_input(12), transport()).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_no_message_key_for_ibe_messages() {
+        let management = LocalManagementCanister::default();
+        store_message(10, None, None, Some(member()));
+        
+        assert!(message_key(&management, &owner(), 10, transport(), 0).await.is_err());
+        assert_eq!(management.derive_key_calls.get(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_attachment_key_is_message_key() {
+        let management = LocalManagementCanister::default();
+        store_channel(1, true, None);
+        store_message(10, Some(1), None, None);
+        for (id, encrypted) in [(20, true), (21, false)] {
+            state::with_attachments_mut(|attachments| {
+                attachments.insert(id, AttachmentRecord {
+                    id,
+                    owner: owner(),
+                    file_type: "application/octet-stream".to_string(),
+                    filename: "notes.txt".to_string(),
+                    size: 4,
+                    sha256: vec![0; 32],
+                    created_at: 0,
+                    message_id: Some(10),
+                    channel_id: Some(1),
+                    encrypted,
+                    encrypted_metadata: None,
+                    preview: None,
+                });
+            });
+        }
+        
+        let message = message_key(&management, &member(), 10, transport(), 0).await.unwrap();
+        assert_eq!(attachment_key(&management, &member(), 20, transport(), 0).await, Ok(message));
+        assert!(attachment_key(&management, &outsider(), 20, transport(), 0).await.is_err());
+        assert_eq!(attachment_key(&management, &member(), 21, transport(), 0).await, Err("Attachment not found".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_channel_public_keys_are_cached_per_epoch() {
+        let management = LocalManagementCanister::default();
+        store_channel(1, true, Some(2));
+        store_channel(2, false, None);
+        
+        let current = channel_public_key(&management, 1, None).await.unwrap();
+        assert_eq!(current.len(), 96);
+        assert_eq!(channel_public_key(&management, 1, Some(2)).await, Ok(current.clone()));
+        assert_eq!(management.public_key_calls.get(), 1);
+        
+        let earlier = channel_public_key(&management, 1, Some(0)).await.unwrap();
+        assert_ne!(earlier, current);
+        assert_eq!(management.public_key_calls.get(), 2);
+        
+        assert!(channel_public_key(&management, 1, Some(3)).await.is_err());
+        assert!(channel_public_key(&management, 2, None).await.is_err());
+        assert!(channel_public_key(&management, 3, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ibe_keys_are_scoped_to_the_caller() {
+        let management = LocalManagementCanister::default();
+        store_user(owner());
+        store_user(member());
+        let transport = vec![7; 48];
+        
+        let own = ibe_decryption_key(&management, owner(), transport.clone()).await.unwrap();
+        let other = ibe_decryption_key(&management, member(), transport.clone()).await.unwrap();
+        assert_ne!(own, other);
+        
+        // Unregistered callers and malformed transport keys are rejected
+        assert!(ibe_decryption_key(&management, outsider(), transport.clone()).await.is_err());
+        assert!(ibe_decryption_key(&management, Principal::anonymous(), transport).await.is_err());
+        assert!(ibe_decryption_key(&management, owner(), vec![7; 47]).await.is_err());
+        assert_eq!(management.derive_key_calls.get(), 2);
+    }
+}
+
+#[cfg(test)]
+mod candid_tests {
+    use candid::types::subtype::subtype;
+    use candid_parser::bindings::{javascript, typescript};
+    use candid_parser::utils::CandidSource;
+    use std::collections::HashSet;
+    use std::path::Path;
+
+    /// The interface as checked in. Embedded at compile time, so it is the
+    /// previous version even while `UPDATE_CANDID` rewrites the file.
+    const CHECKED_IN: &str = include_str!("../chat_z_backend.did");
+    
+    /// The interface of the last release, which installed clients were built
+    /// against. Never written by `UPDATE_CANDID`; replace it with
+    /// `chat_z_backend.did` when a release ships.
+    const RELEASED: &str = include_str!("../chat_z_backend.prev.did");
+    
+    /// Methods changed incompatibly since the last release. Each is listed
+    /// under "Candid Interface" in the README; empty this when a release
+    /// ships.
+    const DOCUMENTED_BREAKS: &[&str] = &[];
+
+    fn did_path() -> std::path::PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("chat_z_backend.did")
+    }
+
+    /// The frontend's declarations: the interface and its JavaScript and
+    /// TypeScript bindings, as `dfx generate` writes them
+    fn declarations() -> Vec<(std::path::PathBuf, String)> {
+        let generated = crate::__export_service();
+        let (env, service) = CandidSource::Text(&generated).load().unwrap();
+        let service = service.unwrap();
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../declarations/chat_z_backend");
+        vec![
+            (dir.join("chat_z_backend.did.js"), javascript::compile(&env, &Some(service.clone()))),
+            (dir.join("chat_z_backend.did.d.ts"), typescript::compile(&env, &Some(service))),
+            (dir.join("chat_z_backend.did"), generated),
+        ]
+    }
+
+    #[test]
+    fn checked_in_interface_is_up_to_date() {
+        let generated = crate::__export_service();
+        if generated == CHECKED_IN {
+            return;
+        }
+
+        if std::env::var_os("UPDATE_CANDID").is_some() {
+            std::fs::write(did_path(), &generated).expect("Failed to write chat_z_backend.did");
+            return;
+        }
+        panic!(
+            "chat_z_backend.did differs from the interface exported by the code. \
+             Regenerate it with `UPDATE_CANDID=1 cargo test -p chat_z_backend candid_tests` \
+             and review the diff."
+        );
+    }
+
+    #[test]
+    fn declarations_are_up_to_date() {
+        let update = std::env::var_os("UPDATE_CANDID").is_some();
+        for (path, generated) in declarations() {
+            if std::fs::read_to_string(&path).ok().as_deref() == Some(generated.as_str()) {
+                continue;
+            }
+            if update {
+                std::fs::write(&path, &generated).expect("Failed to write declarations");
+                continue;
+            }
+            panic!(
+                "{} is out of date. Regenerate it with \
+                 `UPDATE_CANDID=1 cargo test -p chat_z_backend candid_tests`.",
+                path.display()
+            );
+        }
+    }
+
+    #[test]
+    fn interface_is_compatible_with_released_version() {
+        // Each method must be a subtype of the released one for installed
+        // clients to keep working after an upgrade
+        let (mut env, service) = CandidSource::Text(&crate::__export_service()).load().unwrap();
+        let (released_env, released) = CandidSource::Text(RELEASED).load().unwrap();
+        let released = env.merge_type(released_env, released.unwrap());
+        let methods = env.as_service(&service.unwrap()).unwrap().to_vec();
+        
+        for (name, released_method) in env.as_service(&released).unwrap() {
+            let method = &methods
+                .iter()
+                .find(|(method, _)| method == name)
+                .unwrap_or_else(|| panic!("Method {} of the released interface was removed", name))
+                .1;
+            let compatible = subtype(&mut HashSet::new(), &env, method, released_method);
+            if DOCUMENTED_BREAKS.contains(&name.as_str()) {
+                assert!(compatible.is_err(), "{} is compatible again; remove it from DOCUMENTED_BREAKS", name);
+            } else {
+                compatible.unwrap_or_else(|e| panic!("Method {} is not backward compatible: {}", name, e));
+            }
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use candid::Principal;
+use ic_cdk::call::Call;
+use ic_management_canister_types::{
+    VetKDCurve, VetKDDeriveKeyArgs, VetKDDeriveKeyResult, VetKDKeyId, VetKDPublicKeyArgs,
+    VetKDPublicKeyResult,
+};
+
+use crate::state::{self, EncryptedMessage};
+
+// VetKD derivation contexts. Every key the canister derives belongs to one of
+// these contexts, and the public key clients verify derived keys against is
+// the public key of the same context. Contexts are prefixed with a domain
+// separator so they can never collide with each other, or with the
+// undelimited per-message contexts of messages stored before contexts existed.
+//
+// Calls to the management canister go through `ManagementCanister`, so key
+// flows can run against `LocalManagementCanister` in unit tests.
+
+const CHANNEL_DOMAIN: &[u8] = b"chat_z/channel/";
+const USER_DOMAIN: &[u8] = b"chat_z/user/";
+const IBE_DOMAIN: &[u8] = b"chat_z/ibe";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum KeyContext {
+    /// Keys of messages sent to an encrypted channel during one key epoch
+    Channel { channel_id: u64, epoch: u32 },
+    /// Keys belonging to a single user, such as their direct messages
+    User(Principal),
+    /// Identity-based encryption. Senders encrypt to a principal under this
+    /// context's public key; the principal's identity key is derived with the
+    /// principal as input.
+    Ibe,
+    /// Context of its own (`message_{id}`) that keys of messages stored
+    /// before key epochs were recorded were derived in, with an empty input
+    LegacyMessage(u64),
+}
+
+impl KeyContext {
+    /// Context of the key a message is encrypted with
+    pub fn for_message(message: &EncryptedMessage) -> Self {
+        match (message.channel_id, message.key_epoch) {
+            (_, None) => KeyContext::LegacyMessage(message.id),
+            (Some(channel_id), Some(epoch)) => KeyContext::Channel { channel_id, epoch },
+            (None, Some(_)) => KeyContext::User(message.author),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            KeyContext::Channel { channel_id, epoch } => {
+                let mut bytes = CHANNEL_DOMAIN.to_vec();
+                bytes.extend_from_slice(&channel_id.to_be_bytes());
+                bytes.extend_from_slice(&epoch.to_be_bytes());
+                bytes
+            }
+            KeyContext::User(user) => {
+                let mut bytes = USER_DOMAIN.to_vec();
+                bytes.extend_from_slice(user.as_slice());
+                bytes
+            }
+            KeyContext::Ibe => IBE_DOMAIN.to_vec(),
+            KeyContext::LegacyMessage(message_id) => format!("message_{}", message_id).into_bytes(),
+        }
+    }
+
+    /// Derivation input of a message key within this context
+    pub fn message_input(&self, message_id: u64) -> Vec<u8> {
+        match self {
+            KeyContext::LegacyMessage(_) => vec![],
+            _ => message_input(message_id),
+        }
+    }
+}
+
+/// Compressed BLS12-381 G1 point
+pub const TRANSPORT_PUBLIC_KEY_BYTES: usize = 48;
+
+/// Derived keys are encrypted to a transport key the caller generates, which
+/// the management canister only accepts as a compressed G1 point
+pub fn check_transport_public_key(transport_public_key: &[u8]) -> Result<(), String> {
+    if transport_public_key.len() == TRANSPORT_PUBLIC_KEY_BYTES {
+        Ok(())
+    } else {
+        Err("Invalid transport public key".to_string())
+    }
+}
+
+/// Derivation input of a principal's identity key in the IBE context
+pub fn ibe_identity(user: &Principal) -> Vec<u8> {
+    user.as_slice().to_vec()
+}
+
+/// Derivation input of a message key within its context
+pub fn message_input(message_id: u64) -> Vec<u8> {
+    message_id.to_be_bytes().to_vec()
+}
+
+pub fn key_id() -> VetKDKeyId {
+    state::with_key_name(|key_name_cell| VetKDKeyId {
+        curve: VetKDCurve::Bls12_381_G2,
+        name: key_name_cell.get().clone(),
+    })
+}
+
+/// The VetKD methods of the management canister
+pub trait ManagementCanister {
+    async fn vetkd_public_key(&self, args: VetKDPublicKeyArgs) -> Result<VetKDPublicKeyResult, String>;
+    async fn vetkd_derive_key(&self, args: VetKDDeriveKeyArgs) -> Result<VetKDDeriveKeyResult, String>;
+}
+
+/// The real management canister, reached with inter-canister calls
+pub struct IcManagementCanister;
+
+impl ManagementCanister for IcManagementCanister {
+    async fn vetkd_public_key(&self, args: VetKDPublicKeyArgs) -> Result<VetKDPublicKeyResult, String> {
+        Call::unbounded_wait(Principal::management_canister(), "vetkd_public_key")
+            .with_arg(args)
+            .await
+            .map_err(|e| format!("Failed to get VetKD public key: {:?}", e))?
+            .candid()
+            .map_err(|e| format!("Failed to decode VetKD public key: {:?}", e))
+    }
+
+    async fn vetkd_derive_key(&self, args: VetKDDeriveKeyArgs) -> Result<VetKDDeriveKeyResult, String> {
+        Call::unbounded_wait(Principal::management_canister(), "vetkd_derive_key")
+            .with_arg(args)
+            .await
+            .map_err(|e| format!("Failed to derive VetKD key: {:?}", e))?
+            .candid()
+            .map_err(|e| format!("Failed to decode VetKD key: {:?}", e))
+    }
+}
+
+/// Deterministic stand-in for the management canister in unit tests. This is
+/// not BLS: public keys hash the key name and context, and derived keys hash
+/// the key name, context, input and transport key. Outputs have the sizes of
+/// real VetKD values (a 96-byte G2 public key, a 192-byte encrypted key), and
+/// differ whenever any of their inputs differ, which is what the tests check.
+/// Like the real one, it rejects transport keys that are not 48 bytes long.
+#[cfg(test)]
+#[derive(Default)]
+pub struct LocalManagementCanister {
+    pub public_key_calls: std::cell::Cell<u32>,
+    pub derive_key_calls: std::cell::Cell<u32>,
+}
+
+#[cfg(test)]
+impl LocalManagementCanister {
+    fn expand(label: &[u8], fields: &[&[u8]], len: usize) -> Vec<u8> {
+        use sha2::{Digest, Sha256};
+
+        let mut output = Vec::with_capacity(len);
+        let mut counter = 0u32;
+        while output.len() < len {
+            let mut hasher = Sha256::new();
+            hasher.update(label);
+            hasher.update(counter.to_be_bytes());
+            for field in fields {
+                hasher.update((field.len() as u64).to_be_bytes());
+                hasher.update(field);
+            }
+            output.extend_from_slice(&hasher.finalize());
+            counter += 1;
+        }
+        output.truncate(len);
+        output
+    }
+}
+
+#[cfg(test)]
+impl ManagementCanister for LocalManagementCanister {
+    async fn vetkd_public_key(&self, args: VetKDPublicKeyArgs) -> Result<VetKDPublicKeyResult, String> {
+        self.public_key_calls.set(self.public_key_calls.get() + 1);
+        let fields: [&[u8]; 2] = [args.key_id.name.as_bytes(), &args.context];
+        Ok(VetKDPublicKeyResult {
+            public_key: Self::expand(b"public_key", &fields, 96),
+        })
+    }
+
+    async fn vetkd_derive_key(&self, args: VetKDDeriveKeyArgs) -> Result<VetKDDeriveKeyResult, String> {
+        self.derive_key_calls.set(self.derive_key_calls.get() + 1);
+        check_transport_public_key(&args.transport_public_key)?;
+        let fields: [&[u8]; 4] = [
+            args.key_id.name.as_bytes(),
+            &args.context,
+            &args.input,
+            &args.transport_public_key,
+        ];
+        Ok(VetKDDeriveKeyResult {
+            encrypted_key: Self::expand(b"derive_key", &fields, 192),
+        })
+    }
+}
+
+/// Public key of a derivation context. The first request for a context calls
+/// the management canister; the result is kept in heap memory afterwards.
+pub async fn public_key(management: &impl ManagementCanister, context: &KeyContext) -> Result<Vec<u8>, String> {
+    let context = context.to_bytes();
+    if let Some(public_key) = state::cached_public_key(&context) {
+        return Ok(public_key);
+    }
+
+    let response = management
+        .vetkd_public_key(VetKDPublicKeyArgs {
+            canister_id: None,
+            context: context.clone(),
+            key_id: key_id(),
+        })
+        .await?;
+
+    state::cache_public_key(context, response.public_key.clone());
+    Ok(response.public_key)
+}
+
+/// Derive a key for `input` within `context`, encrypted to the transport key
+pub async fn derive_key(
+    management: &impl ManagementCanister,
+    context: &KeyContext,
+    input: Vec<u8>,
+    transport_public_key: Vec<u8>,
+) -> Result<Vec<u8>, String> {
+    let response = management
+        .vetkd_derive_key(VetKDDeriveKeyArgs {
+            context: context.to_bytes(),
+            input,
+            key_id: key_id(),
+            transport_public_key,
+        })
+        .await?;
+
+    Ok(response.encrypted_key)
+}
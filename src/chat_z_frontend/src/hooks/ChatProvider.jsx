@@ -194,7 +194,7 @@ export const useChatClient = () => {
         channel_id: currentChannel ? [currentChannel.id] : [],
         reply_to: replyTo ? replyTo : [],
         message_type: messageType,
-        attachments: attachments,
+        attachment_ids: attachments.length > 0 ? [attachments] : [],
       };
       console.log("idiot request",request)
       const result = await chatActor.send_message(request);
@@ -398,7 +398,8 @@ export const useChatClient = () => {
         channelId ? [BigInt(channelId)] : [],  // opt nat64
         replyTo ? [BigInt(replyTo)] : [],      // opt nat64 (THIS WAS MISSING/MISPLACED)
         messageType,                           // MessageType
-        attachments || []                       // vec Attachment
+        [],                                    // opt vec reserved (inline attachments, unused)
+        []                                     // opt nat64 (TTL in seconds, channel default)
       );
       
       console.log("result", result);
@@ -433,7 +434,7 @@ export const useChatClient = () => {
                 channelId ? [BigInt(channelId)] : [],
                 replyTo ? [BigInt(replyTo)] : [],
                 messageType,
-                attachments || []
+                []
               );
               
               if ('Ok' in retryResult) {
@@ -456,7 +457,7 @@ export const useChatClient = () => {
                 channelId ? [BigInt(channelId)] : [],
                 replyTo ? [BigInt(replyTo)] : [],
                 messageType,
-                attachments || []
+                []
               );
               
               if ('Ok' in retryResult) {
@@ -491,10 +492,14 @@ export const useChatClient = () => {
       const chatActor = createChatActor(CHAT_Z_CANISTER_ID, {
         agentOptions: identity ? { identity } : {},
       });
-      const encryptedMessageList = await chatActor.get_encrypted_messages_from_channel(channelId);
-      // Sort encrypted messages newest first
-      const sortedEncryptedMessages = encryptedMessageList.sort((a, b) => Number(b.timestamp) - Number(a.timestamp));
-      setEncryptedMessages(sortedEncryptedMessages);
+      const result = await chatActor.get_channel_encrypted_message_page(channelId, [], []);
+      if ('Err' in result) {
+        setEncryptedMessages([]);
+        return [];
+      }
+      // Messages arrive newest first
+      const encryptedMessageList = result.Ok.messages;
+      setEncryptedMessages(encryptedMessageList);
       return encryptedMessageList;
     } catch (error) {
       console.error('Error loading encrypted messages:', error);
@@ -513,7 +518,9 @@ export const useChatClient = () => {
       const chatActor = createChatActor(CHAT_Z_CANISTER_ID, {
         agentOptions: { identity },
       });
-      const encryptedMessageList = await chatActor.get_encrypted_messages();
+      // Messages arrive newest first
+      const result = await chatActor.get_encrypted_message_page([], []);
+      const encryptedMessageList = result.messages;
       setEncryptedMessages(encryptedMessageList);
       return encryptedMessageList;
     } catch (error) {
@@ -567,7 +574,7 @@ export const useChatClient = () => {
       const chatActor = createChatActor(CHAT_Z_CANISTER_ID, {
         agentOptions: { identity },
       });
-      const decryptedMessageList = await chatActor.decrypt_all_messages_from_channel(channelId);
+      const decryptedMessageList = await chatActor.decrypt_all_messages_from_channel(channelId, [], []);
       
       // Store decrypted messages in state
       const newDecryptedMessages = {};
@@ -0,0 +1,115 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use candid::utils::{ArgumentDecoder, ArgumentEncoder};
+use candid::{decode_args, encode_args, encode_one, Principal};
+use chat_z_backend::CanisterArgs;
+use pocket_ic::PocketIc;
+
+// Test harness for running the backend canister in PocketIC. The wasm is built
+// once per test binary, unless CHAT_Z_BACKEND_WASM names a prebuilt module.
+
+const INITIAL_CYCLES: u128 = 2_000_000_000_000;
+
+/// Principal allowed to call admin-only endpoints, as hard-coded in the backend
+pub const ADMIN: &str = "ouuvn-c7hpi-46km4-ywlnr-j2ten-wldfi-xu53v-vth6u-3qtqr-cmbxu-gqe";
+
+pub fn admin() -> Principal {
+    Principal::from_text(ADMIN).unwrap()
+}
+
+/// A distinct non-anonymous caller for each `n`
+pub fn user(n: u8) -> Principal {
+    Principal::self_authenticating([n])
+}
+
+fn workspace_root() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("../..")
+}
+
+fn build_backend_wasm() -> Vec<u8> {
+    if let Ok(path) = std::env::var("CHAT_Z_BACKEND_WASM") {
+        return std::fs::read(&path).unwrap_or_else(|e| panic!("Failed to read {}: {}", path, e));
+    }
+
+    let root = workspace_root();
+    let status = Command::new(std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_string()))
+        .current_dir(&root)
+        .args([
+            "build",
+            "--package",
+            "chat_z_backend",
+            "--target",
+            "wasm32-unknown-unknown",
+            "--release",
+        ])
+        .status()
+        .expect("Failed to run cargo build for the backend wasm");
+    assert!(status.success(), "Building the backend wasm failed");
+
+    let path = root.join("target/wasm32-unknown-unknown/release/chat_z_backend.wasm");
+    std::fs::read(&path).unwrap_or_else(|e| panic!("Failed to read {}: {}", path.display(), e))
+}
+
+pub fn backend_wasm() -> Vec<u8> {
+    static WASM: OnceLock<Vec<u8>> = OnceLock::new();
+    WASM.get_or_init(build_backend_wasm).clone()
+}
+
+/// A PocketIC instance with the backend installed
+pub struct ChatZ {
+    pub pic: PocketIc,
+    pub canister_id: Principal,
+}
+
+impl ChatZ {
+    pub fn install(args: Option<CanisterArgs>) -> Self {
+        let pic = PocketIc::new();
+        let canister_id = pic.create_canister();
+        pic.add_cycles(canister_id, INITIAL_CYCLES);
+        pic.install_canister(canister_id, backend_wasm(), encode_one(args).unwrap(), None);
+        ChatZ { pic, canister_id }
+    }
+
+    pub fn upgrade(&self, args: Option<CanisterArgs>) {
+        self.pic
+            .upgrade_canister(self.canister_id, backend_wasm(), encode_one(args).unwrap(), None)
+            .expect("Upgrade failed");
+    }
+
+    /// Call an update method and decode its reply; panics if the call is rejected
+    pub fn update<A, R>(&self, sender: Principal, method: &str, args: A) -> R
+    where
+        A: ArgumentEncoder,
+        R: for<'a> ArgumentDecoder<'a>,
+    {
+        let reply = self
+            .pic
+            .update_call(self.canister_id, sender, method, encode_args(args).unwrap())
+            .unwrap_or_else(|e| panic!("{} was rejected: {:?}", method, e));
+        decode_args(&reply).unwrap_or_else(|e| panic!("Failed to decode reply of {}: {}", method, e))
+    }
+
+    /// Call a query method and decode its reply; panics if the call is rejected
+    pub fn query<A, R>(&self, sender: Principal, method: &str, args: A) -> R
+    where
+        A: ArgumentEncoder,
+        R: for<'a> ArgumentDecoder<'a>,
+    {
+        let reply = self
+            .pic
+            .query_call(self.canister_id, sender, method, encode_args(args).unwrap())
+            .unwrap_or_else(|e| panic!("{} was rejected: {:?}", method, e));
+        decode_args(&reply).unwrap_or_else(|e| panic!("Failed to decode reply of {}: {}", method, e))
+    }
+
+    /// Move the canister clock forward and let timers that became due run
+    pub fn advance_time(&self, duration: Duration) {
+        self.pic.advance_time(duration);
+        for _ in 0..3 {
+            self.pic.tick();
+        }
+    }
+}
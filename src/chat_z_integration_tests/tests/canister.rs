@@ -0,0 +1,328 @@
+use std::time::Duration;
+
+use candid::{Principal, Reserved};
+use chat_z_backend::{
+    CanisterArgs, CanisterInfo, Channel, ChatError, CreateChannelRequest, CreateMessageRequest,
+    EncryptedMessagePage, Message, MessageType, PaginatedMessages, User,
+};
+use chat_z_integration_tests::{admin, user, ChatZ};
+
+fn register(chat: &ChatZ, principal: Principal, username: &str) -> User {
+    let (result,): (Result<User, ChatError>,) =
+        chat.update(principal, "register_user", (username.to_string(), None::<String>));
+    result.expect("registration failed")
+}
+
+fn create_channel(chat: &ChatZ, creator: Principal, name: &str) -> Channel {
+    let request = CreateChannelRequest {
+        name: name.to_string(),
+        description: None,
+    };
+    let (result,): (Result<Channel, ChatError>,) = chat.update(creator, "create_channel", (request,));
+    result.expect("creating the channel failed")
+}
+
+fn create_encrypted_channel(chat: &ChatZ, creator: Principal, name: &str, password: Option<&str>) -> Channel {
+    let (result,): (Result<Channel, ChatError>,) = chat.update(
+        creator,
+        "create_encrypted_channel",
+        (name.to_string(), None::<String>, password.map(str::to_string)),
+    );
+    result.expect("creating the encrypted channel failed")
+}
+
+fn join(chat: &ChatZ, principal: Principal, channel_id: u64, password: Option<&str>) -> Result<(), ChatError> {
+    let (result,): (Result<(), ChatError>,) =
+        chat.update(principal, "join_channel", (channel_id, password.map(str::to_string)));
+    result
+}
+
+fn send(chat: &ChatZ, author: Principal, channel_id: u64, content: &str) -> Result<Message, ChatError> {
+    let request = CreateMessageRequest {
+        content: content.to_string(),
+        channel_id: Some(channel_id),
+        reply_to: None,
+        message_type: MessageType::Text,
+        attachment_ids: None,
+    };
+    let (result,): (Result<Message, ChatError>,) = chat.update(author, "send_message", (request,));
+    result
+}
+
+fn create_encrypted_message(
+    chat: &ChatZ,
+    author: Principal,
+    content: &str,
+    channel_id: Option<u64>,
+    ttl_secs: Option<u64>,
+) -> Result<u64, ChatError> {
+    let (result,): (Result<u64, ChatError>,) = chat.update(
+        author,
+        "create_encrypted_message",
+        (content.to_string(), channel_id, None::<u64>, MessageType::Text, None::<Vec<Reserved>>, ttl_secs),
+    );
+    result
+}
+
+fn channel_messages(chat: &ChatZ, caller: Principal, channel_id: u64) -> PaginatedMessages {
+    let (page,): (PaginatedMessages,) =
+        chat.query(caller, "get_messages", (Some(channel_id), None::<u64>, None::<u64>));
+    page
+}
+
+fn encrypted_channel_messages(
+    chat: &ChatZ,
+    caller: Principal,
+    channel_id: u64,
+) -> Result<EncryptedMessagePage, ChatError> {
+    let (result,): (Result<EncryptedMessagePage, ChatError>,) = chat.query(
+        caller,
+        "get_channel_encrypted_message_page",
+        (channel_id, None::<u64>, None::<u64>),
+    );
+    result
+}
+
+fn get_channel(chat: &ChatZ, channel_id: u64) -> Option<Channel> {
+    let (channel,): (Option<Channel>,) = chat.query(user(0), "get_channel", (channel_id,));
+    channel
+}
+
+#[test]
+fn registration_and_profiles() {
+    let chat = ChatZ::install(None);
+    let alice = user(1);
+    let bob = user(2);
+
+    let registered = register(&chat, alice, "alice");
+    assert_eq!(registered.username, "alice");
+    assert_eq!(registered.user_principal, alice);
+
+    // A principal registers once
+    let (again,): (Result<User, ChatError>,) =
+        chat.update(alice, "register_user", ("alice2".to_string(), None::<String>));
+    assert_eq!(again.unwrap_err(), ChatError::UserAlreadyExists);
+
+    let (current,): (Option<User>,) = chat.query(alice, "get_current_user", ());
+    assert_eq!(current.unwrap().username, "alice");
+    let (unknown,): (Option<User>,) = chat.query(bob, "get_current_user", ());
+    assert!(unknown.is_none());
+
+    // Vault keys are only visible to their owner
+    let (stored,): (Result<(), ChatError>,) =
+        chat.update(alice, "store_encrypted_key", ("channel_1".to_string(), "a2V5".to_string()));
+    stored.unwrap();
+    let (own,): (Result<Vec<String>, ChatError>,) = chat.query(alice, "list_encrypted_keys", ());
+    assert_eq!(own.unwrap(), vec!["channel_1".to_string()]);
+    let (seen_by_bob,): (Option<User>,) = chat.query(bob, "get_user", (alice,));
+    assert!(seen_by_bob.unwrap().encrypted_keys.is_empty());
+}
+
+#[test]
+fn channel_lifecycle() {
+    let chat = ChatZ::install(None);
+    let alice = user(1);
+    let bob = user(2);
+    register(&chat, alice, "alice");
+    register(&chat, bob, "bob");
+
+    // The General channel created at install keeps ID 1
+    let channel = create_channel(&chat, alice, "rust");
+    assert_eq!(channel.id, 2);
+    assert_eq!(get_channel(&chat, 1).unwrap().name, "General");
+    assert_eq!(channel.members, vec![alice]);
+    assert_eq!(get_channel(&chat, channel.id).unwrap().name, "rust");
+
+    join(&chat, bob, channel.id, None).unwrap();
+    join(&chat, bob, channel.id, None).unwrap();
+    assert_eq!(get_channel(&chat, channel.id).unwrap().members, vec![alice, bob]);
+    assert_eq!(join(&chat, bob, 999, None), Err(ChatError::ChannelNotFound));
+
+    // Only the creator can delete a channel
+    let (denied,): (Result<(), ChatError>,) = chat.update(bob, "delete_channel", (channel.id,));
+    assert_eq!(denied, Err(ChatError::NotAuthorized));
+    let (deleted,): (Result<(), ChatError>,) = chat.update(alice, "delete_channel", (channel.id,));
+    deleted.unwrap();
+    assert!(get_channel(&chat, channel.id).is_none());
+}
+
+#[test]
+fn password_protected_join() {
+    let chat = ChatZ::install(None);
+    let alice = user(1);
+    let bob = user(2);
+    register(&chat, alice, "alice");
+    register(&chat, bob, "bob");
+
+    let channel = create_encrypted_channel(&chat, alice, "secret", Some("hunter2"));
+    assert!(channel.password_hash.is_some());
+
+    assert_eq!(join(&chat, bob, channel.id, None), Err(ChatError::InvalidPassword));
+    assert_eq!(join(&chat, bob, channel.id, Some("hunter3")), Err(ChatError::InvalidPassword));
+    assert!(!get_channel(&chat, channel.id).unwrap().members.contains(&bob));
+
+    join(&chat, bob, channel.id, Some("hunter2")).unwrap();
+    assert!(get_channel(&chat, channel.id).unwrap().members.contains(&bob));
+
+    // Unregistered callers cannot join even with the password
+    assert_eq!(join(&chat, user(3), channel.id, Some("hunter2")), Err(ChatError::NotAuthorized));
+}
+
+#[test]
+fn messaging() {
+    let chat = ChatZ::install(None);
+    let alice = user(1);
+    let bob = user(2);
+    register(&chat, alice, "alice");
+    register(&chat, bob, "bob");
+    let channel = create_channel(&chat, alice, "general-talk");
+
+    // Members only
+    assert_eq!(send(&chat, bob, channel.id, "hi").unwrap_err(), ChatError::NotAuthorized);
+    join(&chat, bob, channel.id, None).unwrap();
+
+    for i in 0..3 {
+        send(&chat, alice, channel.id, &format!("message {}", i)).unwrap();
+    }
+    let reply = send(&chat, bob, channel.id, "  hello alice  ").unwrap();
+    assert_eq!(reply.content, "hello alice");
+    assert_eq!(send(&chat, bob, channel.id, "   ").unwrap_err(), ChatError::InvalidInput);
+    assert_eq!(send(&chat, user(3), channel.id, "hi").unwrap_err(), ChatError::NotAuthorized);
+
+    let page = channel_messages(&chat, bob, channel.id);
+    assert_eq!(page.total_count, 4);
+    assert_eq!(page.messages[0].id, reply.id);
+    assert_eq!(page.messages[0].author_username, "bob");
+
+    let (first_page,): (PaginatedMessages,) =
+        chat.query(bob, "get_messages", (Some(channel.id), Some(2u64), None::<u64>));
+    assert_eq!(first_page.messages.len(), 2);
+    assert!(first_page.has_more);
+
+    assert_eq!(get_channel(&chat, channel.id).unwrap().message_count, 4);
+}
+
+#[test]
+fn encrypted_messages() {
+    let chat = ChatZ::install(None);
+    let alice = user(1);
+    let bob = user(2);
+    let carol = user(3);
+    register(&chat, alice, "alice");
+    register(&chat, bob, "bob");
+    register(&chat, carol, "carol");
+
+    let channel = create_encrypted_channel(&chat, alice, "vault", None);
+    join(&chat, bob, channel.id, None).unwrap();
+
+    // Only members can post to and read from an encrypted channel
+    assert_eq!(
+        create_encrypted_message(&chat, carol, "intruder", Some(channel.id), None),
+        Err(ChatError::NotAuthorized)
+    );
+    let message_id = create_encrypted_message(&chat, alice, "the plans", Some(channel.id), None).unwrap();
+
+    let page = encrypted_channel_messages(&chat, bob, channel.id).unwrap();
+    assert_eq!(page.messages.len(), 1);
+    assert_eq!(page.next_before, None);
+    assert_ne!(page.messages[0].encrypted_content, "the plans");
+    assert_eq!(encrypted_channel_messages(&chat, carol, channel.id).unwrap_err(), ChatError::NotAuthorized);
+
+    let (decrypted,): (Result<String, String>,) = chat.update(bob, "decrypt_encrypted_message", (message_id,));
+    assert_eq!(decrypted.unwrap(), "the plans");
+    let (denied,): (Result<String, String>,) = chat.update(carol, "decrypt_encrypted_message", (message_id,));
+    assert!(denied.is_err());
+
+    // Direct messages are shared explicitly, and shares can be revoked
+    let direct = create_encrypted_message(&chat, alice, "just for you", None, None).unwrap();
+    let (shared,): (Result<(), ChatError>,) = chat.update(alice, "share_encrypted_message", (direct, carol));
+    shared.unwrap();
+    let (inbox,): (EncryptedMessagePage,) = chat.query(carol, "get_encrypted_message_page", (None::<u64>, None::<u64>));
+    assert_eq!(inbox.messages.iter().map(|m| m.id).collect::<Vec<_>>(), vec![direct]);
+    let (shares,): (Result<Vec<Principal>, ChatError>,) = chat.query(alice, "get_encrypted_message_shares", (direct,));
+    assert_eq!(shares.unwrap(), vec![carol]);
+
+    let (unshared,): (Result<(), ChatError>,) = chat.update(alice, "unshare_encrypted_message", (direct, carol));
+    unshared.unwrap();
+    let (inbox,): (EncryptedMessagePage,) = chat.query(carol, "get_encrypted_message_page", (None::<u64>, None::<u64>));
+    assert!(inbox.messages.is_empty());
+
+    // Shares require a registered recipient
+    let (unknown,): (Result<(), ChatError>,) = chat.update(alice, "share_encrypted_message", (direct, user(9)));
+    assert_eq!(unknown, Err(ChatError::NotFound));
+}
+
+#[test]
+fn expiry_with_time_advancement() {
+    let chat = ChatZ::install(Some(CanisterArgs {
+        vetkd_key_name: None,
+        cleanup_interval_secs: Some(60),
+    }));
+    let alice = user(1);
+    let bob = user(2);
+    register(&chat, alice, "alice");
+    register(&chat, bob, "bob");
+    let channel = create_encrypted_channel(&chat, alice, "ephemeral", None);
+    join(&chat, bob, channel.id, None).unwrap();
+
+    let short_lived = create_encrypted_message(&chat, alice, "gone soon", Some(channel.id), Some(90)).unwrap();
+    let long_lived = create_encrypted_message(&chat, alice, "still here", Some(channel.id), None).unwrap();
+    assert_eq!(encrypted_channel_messages(&chat, bob, channel.id).unwrap().messages.len(), 2);
+
+    // Past its TTL the message is hidden, and the cleanup timer deletes it
+    chat.advance_time(Duration::from_secs(120));
+    let page = encrypted_channel_messages(&chat, bob, channel.id).unwrap();
+    assert_eq!(page.messages.iter().map(|m| m.id).collect::<Vec<_>>(), vec![long_lived]);
+
+    let (deleted,): (Result<Vec<Principal>, ChatError>,) =
+        chat.query(alice, "get_encrypted_message_shares", (short_lived,));
+    assert_eq!(deleted, Err(ChatError::NotFound));
+    let (cleaned,): (u64,) = chat.update(admin(), "cleanup_expired_messages", ());
+    assert_eq!(cleaned, 0);
+
+    // Channel defaults expire messages after a day
+    chat.advance_time(Duration::from_secs(24 * 60 * 60));
+    assert_eq!(encrypted_channel_messages(&chat, bob, channel.id).unwrap().messages.len(), 0);
+}
+
+#[test]
+fn upgrade_preserves_state() {
+    let chat = ChatZ::install(None);
+    let alice = user(1);
+    let bob = user(2);
+    register(&chat, alice, "alice");
+    register(&chat, bob, "bob");
+    let channel = create_channel(&chat, alice, "persistent");
+    join(&chat, bob, channel.id, None).unwrap();
+    send(&chat, alice, channel.id, "before the upgrade").unwrap();
+    let encrypted_channel = create_encrypted_channel(&chat, alice, "persistent-secrets", Some("pw"));
+    let secret = create_encrypted_message(&chat, alice, "kept", Some(encrypted_channel.id), None).unwrap();
+
+    chat.upgrade(Some(CanisterArgs {
+        vetkd_key_name: None,
+        cleanup_interval_secs: Some(600),
+    }));
+
+    let (info,): (Result<CanisterInfo, ChatError>,) = chat.query(admin(), "get_canister_info", ());
+    let info = info.unwrap();
+    assert_eq!(info.upgrade.upgrade_count, 1);
+    assert_eq!(info.cleanup_interval_secs, 600);
+    let (denied,): (Result<CanisterInfo, ChatError>,) = chat.query(alice, "get_canister_info", ());
+    assert!(denied.is_err());
+
+    let (current,): (Option<User>,) = chat.query(bob, "get_current_user", ());
+    assert_eq!(current.unwrap().username, "bob");
+    assert_eq!(get_channel(&chat, channel.id).unwrap().members, vec![alice, bob]);
+    let page = channel_messages(&chat, bob, channel.id);
+    assert_eq!(page.messages[0].content, "before the upgrade");
+
+    // Password hashes and encrypted messages survive too
+    assert_eq!(join(&chat, bob, encrypted_channel.id, Some("wrong")), Err(ChatError::InvalidPassword));
+    join(&chat, bob, encrypted_channel.id, Some("pw")).unwrap();
+    let (decrypted,): (Result<String, String>,) = chat.update(bob, "decrypt_encrypted_message", (secret,));
+    assert_eq!(decrypted.unwrap(), "kept");
+
+    // And the canister keeps working
+    send(&chat, bob, channel.id, "after the upgrade").unwrap();
+    assert_eq!(channel_messages(&chat, alice, channel.id).total_count, 2);
+}
@@ -3,22 +3,52 @@ import type { ActorMethod } from '@dfinity/agent';
 import type { IDL } from '@dfinity/candid';
 
 export interface Attachment {
+  'id' : bigint,
+  'preview' : [] | [ImagePreview],
   'data' : Uint8Array | number[],
   'size' : bigint,
   'file_type' : string,
   'filename' : string,
+  'encrypted_metadata' : [] | [Uint8Array | number[]],
+}
+export interface BeginEncryptedUploadRequest {
+  'total_size' : bigint,
+  'encrypted_metadata' : Uint8Array | number[],
+  'message_id' : bigint,
+}
+export interface BeginUploadRequest {
+  'file_type' : string,
+  'total_size' : bigint,
+  'filename' : string,
+}
+export interface CanisterArgs {
+  'cleanup_interval_secs' : [] | [bigint],
+  'vetkd_key_name' : [] | [string],
+}
+export interface CanisterInfo {
+  'upgrade' : UpgradeRecord,
+  'cleanup_interval_secs' : bigint,
+  'vetkd_key_name' : string,
+}
+export interface CertifiedEncryptedMessage {
+  'certificate' : Uint8Array | number[],
+  'encoded_message' : Uint8Array | number[],
+  'witness' : Uint8Array | number[],
 }
 export interface Channel {
   'id' : bigint,
+  'password_hash' : [] | [string],
   'members' : Array<Principal>,
   'name' : string,
+  'message_expiry' : [] | [MessageExpiry],
+  'retention' : [] | [RetentionPolicy],
   'description' : [] | [string],
   'last_message_at' : [] | [bigint],
   'created_at' : bigint,
   'created_by' : Principal,
   'message_count' : bigint,
   'is_encrypted' : boolean,
-  'password_hash' : [] | [string],
+  'key_epoch' : [] | [number],
 }
 export type ChatError = { 'UserAlreadyExists' : null } |
   { 'MessageTooLarge' : null } |
@@ -37,9 +67,46 @@ export interface CreateMessageRequest {
   'content' : string,
   'reply_to' : [] | [bigint],
   'message_type' : MessageType,
-  'attachments' : Array<Attachment>,
+  'attachment_ids' : [] | [BigUint64Array | bigint[]],
 }
 export interface EncryptedMessage {
+  'id' : bigint,
+  'channel_id' : [] | [bigint],
+  'reply_to' : [] | [bigint],
+  'encrypted_content' : string,
+  'ibe_recipient' : [] | [Principal],
+  'author' : Principal,
+  'timestamp' : bigint,
+  'message_type' : MessageType,
+  'shared_with' : Array<Principal>,
+  'key_epoch' : [] | [number],
+  'expires_at' : [] | [bigint],
+  'attachments' : Array<Attachment>,
+}
+export interface EncryptedMessagePage {
+  'next_before' : [] | [bigint],
+  'messages' : Array<EncryptedMessage>,
+}
+export interface HttpRequest {
+  'url' : string,
+  'method' : string,
+  'body' : Uint8Array | number[],
+  'headers' : Array<[string, string]>,
+  'certificate_version' : [] | [number],
+}
+export interface HttpResponse {
+  'body' : Uint8Array | number[],
+  'headers' : Array<[string, string]>,
+  'streaming_strategy' : [] | [StreamingStrategy],
+  'status_code' : number,
+}
+export interface ImagePreview {
+  'height' : number,
+  'thumbnail' : [] | [Uint8Array | number[]],
+  'blurhash' : [] | [string],
+  'width' : number,
+}
+export interface LegacyEncryptedMessage {
   'id' : bigint,
   'channel_id' : [] | [bigint],
   'reply_to' : [] | [bigint],
@@ -53,6 +120,7 @@ export interface EncryptedMessage {
 }
 export interface Message {
   'id' : bigint,
+  'channel_id' : [] | [bigint],
   'content' : string,
   'reply_to' : [] | [bigint],
   'author' : Principal,
@@ -60,6 +128,10 @@ export interface Message {
   'message_type' : MessageType,
   'attachments' : Array<Attachment>,
 }
+export type MessageExpiry = { 'SevenDays' : null } |
+  { 'OneHour' : null } |
+  { 'Never' : null } |
+  { 'OneDay' : null };
 export type MessageType = { 'System' : null } |
   { 'Text' : null } |
   { 'Image' : null };
@@ -78,16 +150,71 @@ export interface PaginatedMessages {
   'total_count' : bigint,
   'has_more' : boolean,
 }
-export interface Stats {
-  'messages' : bigint,
-  'channels' : bigint,
-  'users' : bigint,
+export type PresenceStatus = { 'Away' : null } |
+  { 'Online' : null } |
+  { 'Offline' : null };
+export type Result = { 'Ok' : bigint } |
+  { 'Err' : ChatError };
+export type Result_1 = { 'Ok' : null } |
+  { 'Err' : ChatError };
+export type Result_10 = { 'Ok' : EncryptedMessagePage } |
+  { 'Err' : ChatError };
+export type Result_11 = { 'Ok' : Array<Principal> } |
+  { 'Err' : ChatError };
+export type Result_12 = { 'Ok' : StorageUsage } |
+  { 'Err' : ChatError };
+export type Result_13 = { 'Ok' : Array<string> } |
+  { 'Err' : ChatError };
+export type Result_14 = { 'Ok' : User } |
+  { 'Err' : ChatError };
+export type Result_15 = { 'Ok' : Message } |
+  { 'Err' : ChatError };
+export type Result_2 = { 'Ok' : Uint8Array | number[] } |
+  { 'Err' : string };
+export type Result_3 = { 'Ok' : Attachment } |
+  { 'Err' : ChatError };
+export type Result_4 = { 'Ok' : Channel } |
+  { 'Err' : ChatError };
+export type Result_5 = { 'Ok' : string } |
+  { 'Err' : string };
+export type Result_6 = { 'Ok' : Uint8Array | number[] } |
+  { 'Err' : ChatError };
+export type Result_7 = { 'Ok' : string } |
+  { 'Err' : ChatError };
+export type Result_8 = { 'Ok' : CanisterInfo } |
+  { 'Err' : ChatError };
+export type Result_9 = { 'Ok' : CertifiedEncryptedMessage } |
+  { 'Err' : ChatError };
+export type RetentionPolicy = { 'MaxMessages' : bigint } |
+  { 'MaxAgeDays' : number };
+export interface StorageUsage { 'used_bytes' : bigint, 'quota_bytes' : bigint }
+export interface StreamingCallbackHttpResponse {
+  'token' : [] | [StreamingCallbackToken],
+  'body' : Uint8Array | number[],
+}
+export interface StreamingCallbackToken {
+  'attachment_id' : bigint,
+  'signature' : string,
+  'expires' : bigint,
+  'index' : number,
 }
+export type StreamingStrategy = {
+    'Callback' : {
+      'token' : StreamingCallbackToken,
+      'callback' : [Principal, string],
+    }
+  };
 export interface UpdateUserRequest {
   'bio' : [] | [string],
   'username' : [] | [string],
   'avatar_url' : [] | [string],
 }
+export interface UpgradeRecord {
+  'upgrade_count' : bigint,
+  'migration_cursor' : [] | [Uint8Array | number[]],
+  'last_upgraded_at' : [] | [bigint],
+  'migration_version' : number,
+}
 export interface User {
   'bio' : [] | [string],
   'encrypted_keys' : Array<[string, string]>,
@@ -98,93 +225,139 @@ export interface User {
   'message_count' : bigint,
   'joined_at' : bigint,
 }
+export interface UserPresence {
+  'status' : PresenceStatus,
+  'user_principal' : Principal,
+  'last_seen' : bigint,
+}
 export interface _SERVICE {
-  'cleanup_expired_messages' : ActorMethod<[], bigint>,
-  'create_channel' : ActorMethod<
-    [CreateChannelRequest],
-    { 'Ok' : Channel } |
-      { 'Err' : ChatError }
+  'begin_encrypted_upload' : ActorMethod<[BeginEncryptedUploadRequest], Result>,
+  'begin_upload' : ActorMethod<[BeginUploadRequest], Result>,
+  'block_user' : ActorMethod<[Principal], Result_1>,
+  'cancel_upload' : ActorMethod<[bigint], Result_1>,
+  'channel_key_verification_key' : ActorMethod<
+    [bigint, [] | [number]],
+    Result_2
   >,
+  'cleanup_expired_messages' : ActorMethod<[], bigint>,
+  'commit_upload' : ActorMethod<[bigint, Uint8Array | number[]], Result_3>,
+  'create_channel' : ActorMethod<[CreateChannelRequest], Result_4>,
   'create_encrypted_channel' : ActorMethod<
     [string, [] | [string], [] | [string]],
-    { 'Ok' : Channel } |
-      { 'Err' : ChatError }
+    Result_4
   >,
   'create_encrypted_message' : ActorMethod<
-    [string, [] | [bigint], [] | [bigint], MessageType, Array<Attachment>],
-    { 'Ok' : bigint } |
-      { 'Err' : ChatError }
+    [
+      string,
+      [] | [bigint],
+      [] | [bigint],
+      MessageType,
+      [] | [Array<any>],
+      [] | [bigint],
+    ],
+    Result
+  >,
+  'create_ibe_message' : ActorMethod<
+    [Principal, string, [] | [bigint], MessageType, [] | [bigint]],
+    Result
   >,
   'decrypt_all_messages_from_channel' : ActorMethod<
-    [bigint],
+    [bigint, [] | [bigint], [] | [bigint]],
     Array<MessageWithAuthor>
   >,
-  'decrypt_encrypted_message' : ActorMethod<
-    [bigint],
-    { 'Ok' : string } |
-      { 'Err' : string }
+  'decrypt_encrypted_message' : ActorMethod<[bigint], Result_5>,
+  'delete_channel' : ActorMethod<[bigint], Result_1>,
+  'delete_encrypted_key' : ActorMethod<[string], Result_1>,
+  'delete_encrypted_message' : ActorMethod<[bigint], Result_1>,
+  'encrypted_ibe_decryption_key' : ActorMethod<
+    [Uint8Array | number[]],
+    Result_2
   >,
-  'delete_encrypted_message' : ActorMethod<
-    [bigint],
-    { 'Ok' : null } |
-      { 'Err' : ChatError }
+  'encrypted_symmetric_key_for_attachment' : ActorMethod<
+    [bigint, [] | [Uint8Array | number[]]],
+    Result_2
   >,
   'encrypted_symmetric_key_for_message' : ActorMethod<
-    [bigint],
-    { 'Ok' : Uint8Array | number[] } |
-      { 'Err' : string }
-  >,
-  'fix_general_channel' : ActorMethod<
-    [],
-    { 'Ok' : Channel } |
-      { 'Err' : ChatError }
+    [bigint, [] | [Uint8Array | number[]]],
+    Result_2
   >,
+  'fix_general_channel' : ActorMethod<[], Result_4>,
+  'force_delete_channel' : ActorMethod<[bigint], Result_1>,
+  'forward_attachment' : ActorMethod<[bigint], Result_3>,
   'get_all_channels' : ActorMethod<[], Array<Channel>>,
   'get_all_users' : ActorMethod<[], Array<User>>,
+  'get_attachment' : ActorMethod<[bigint], [] | [Attachment]>,
+  'get_attachment_chunk' : ActorMethod<[bigint, number], Result_6>,
+  'get_attachment_url' : ActorMethod<[bigint], Result_7>,
+  'get_blocked_users' : ActorMethod<[], Array<Principal>>,
+  'get_canister_info' : ActorMethod<[], Result_8>,
+  'get_certified_encrypted_message' : ActorMethod<[bigint], Result_9>,
   'get_channel' : ActorMethod<[bigint], [] | [Channel]>,
+  'get_channel_encrypted_message_page' : ActorMethod<
+    [bigint, [] | [bigint], [] | [bigint]],
+    Result_10
+  >,
   'get_current_user' : ActorMethod<[], [] | [User]>,
-  'get_encrypted_messages' : ActorMethod<[], Array<EncryptedMessage>>,
+  'get_encrypted_key' : ActorMethod<[string], Result_7>,
+  'get_encrypted_message_page' : ActorMethod<
+    [[] | [bigint], [] | [bigint]],
+    EncryptedMessagePage
+  >,
+  'get_encrypted_message_shares' : ActorMethod<[bigint], Result_11>,
+  'get_encrypted_messages' : ActorMethod<[], Array<LegacyEncryptedMessage>>,
   'get_encrypted_messages_from_channel' : ActorMethod<
     [bigint],
-    Array<EncryptedMessage>
+    Array<LegacyEncryptedMessage>
   >,
   'get_message' : ActorMethod<[bigint], [] | [MessageWithAuthor]>,
   'get_messages' : ActorMethod<
     [[] | [bigint], [] | [bigint], [] | [bigint]],
     PaginatedMessages
   >,
+  'get_muted_users' : ActorMethod<[], Array<Principal>>,
+  'get_presence' : ActorMethod<[Array<Principal>], Array<UserPresence>>,
   'get_stats' : ActorMethod<[], Array<[string, bigint]>>,
+  'get_storage_usage' : ActorMethod<[[] | [Principal]], Result_12>,
+  'get_typing_users' : ActorMethod<[bigint], Result_11>,
   'get_user' : ActorMethod<[Principal], [] | [User]>,
-  'join_channel' : ActorMethod<
-    [bigint],
-    { 'Ok' : null } |
-      { 'Err' : ChatError }
+  'heartbeat' : ActorMethod<[], Result_1>,
+  'http_request' : ActorMethod<[HttpRequest], HttpResponse>,
+  'http_request_streaming_callback' : ActorMethod<
+    [StreamingCallbackToken],
+    StreamingCallbackHttpResponse
   >,
-  'register_user' : ActorMethod<
-    [string, [] | [string]],
-    { 'Ok' : User } |
-      { 'Err' : ChatError }
+  'ibe_encryption_key' : ActorMethod<[], Result_2>,
+  'join_channel' : ActorMethod<[bigint, [] | [string]], Result_1>,
+  'list_encrypted_keys' : ActorMethod<[], Result_13>,
+  'mute_user' : ActorMethod<[Principal], Result_1>,
+  'put_chunk' : ActorMethod<[bigint, number, Uint8Array | number[]], Result_1>,
+  'register_user' : ActorMethod<[string, [] | [string]], Result_14>,
+  'rotate_channel_key' : ActorMethod<[bigint], Result_4>,
+  'send_message' : ActorMethod<[CreateMessageRequest], Result_15>,
+  'set_attachment_preview' : ActorMethod<
+    [bigint, [] | [Uint8Array | number[]], [] | [string]],
+    Result_3
   >,
-  'send_message' : ActorMethod<
-    [CreateMessageRequest],
-    { 'Ok' : Message } |
-      { 'Err' : ChatError }
-  >,
-  'share_encrypted_message' : ActorMethod<
-    [bigint, Principal],
-    { 'Ok' : null } |
-      { 'Err' : ChatError }
+  'set_channel_message_expiry' : ActorMethod<[bigint, MessageExpiry], Result_4>,
+  'set_channel_retention' : ActorMethod<
+    [bigint, [] | [RetentionPolicy]],
+    Result_4
   >,
+  'set_default_storage_quota' : ActorMethod<[bigint], Result_1>,
+  'set_typing' : ActorMethod<[bigint, boolean], Result_1>,
+  'set_user_storage_quota' : ActorMethod<[Principal, [] | [bigint]], Result_1>,
+  'share_encrypted_message' : ActorMethod<[bigint, Principal], Result_1>,
+  'store_encrypted_key' : ActorMethod<[string, string], Result_1>,
   'symmetric_key_verification_key_for_encrypted_message' : ActorMethod<
     [],
-    { 'Ok' : Uint8Array | number[] } |
-      { 'Err' : string }
-  >,
-  'update_user' : ActorMethod<
-    [UpdateUserRequest],
-    { 'Ok' : User } |
-      { 'Err' : ChatError }
+    Result_2
   >,
+  'unblock_user' : ActorMethod<[Principal], Result_1>,
+  'unmute_user' : ActorMethod<[Principal], Result_1>,
+  'unshare_encrypted_message' : ActorMethod<[bigint, Principal], Result_1>,
+  'update_user' : ActorMethod<[UpdateUserRequest], Result_14>,
+  'upload_avatar' : ActorMethod<[Uint8Array | number[]], Result_14>,
+  'user_key_verification_key' : ActorMethod<[Principal], Result_2>,
 }
 export declare const idlFactory: IDL.InterfaceFactory;
-export declare const init: (args: { IDL: typeof IDL }) => IDL.Type[];
+export declare const init: (args: { IDL: typeof IDL }) => IDL.Type[];
\ No newline at end of file
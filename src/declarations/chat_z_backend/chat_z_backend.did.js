@@ -1,40 +1,82 @@
 export const idlFactory = ({ IDL }) => {
+  const CanisterArgs = IDL.Record({
+    'cleanup_interval_secs' : IDL.Opt(IDL.Nat64),
+    'vetkd_key_name' : IDL.Opt(IDL.Text),
+  });
+  const BeginEncryptedUploadRequest = IDL.Record({
+    'total_size' : IDL.Nat64,
+    'encrypted_metadata' : IDL.Vec(IDL.Nat8),
+    'message_id' : IDL.Nat64,
+  });
+  const ChatError = IDL.Variant({
+    'UserAlreadyExists' : IDL.Null,
+    'MessageTooLarge' : IDL.Null,
+    'InvalidInput' : IDL.Null,
+    'ChannelNotFound' : IDL.Null,
+    'NotFound' : IDL.Null,
+    'NotAuthorized' : IDL.Null,
+    'AttachmentTooLarge' : IDL.Null,
+    'InvalidPassword' : IDL.Null,
+  });
+  const Result = IDL.Variant({ 'Ok' : IDL.Nat64, 'Err' : ChatError });
+  const BeginUploadRequest = IDL.Record({
+    'file_type' : IDL.Text,
+    'total_size' : IDL.Nat64,
+    'filename' : IDL.Text,
+  });
+  const Result_1 = IDL.Variant({ 'Ok' : IDL.Null, 'Err' : ChatError });
+  const Result_2 = IDL.Variant({ 'Ok' : IDL.Vec(IDL.Nat8), 'Err' : IDL.Text });
+  const ImagePreview = IDL.Record({
+    'height' : IDL.Nat32,
+    'thumbnail' : IDL.Opt(IDL.Vec(IDL.Nat8)),
+    'blurhash' : IDL.Opt(IDL.Text),
+    'width' : IDL.Nat32,
+  });
+  const Attachment = IDL.Record({
+    'id' : IDL.Nat64,
+    'preview' : IDL.Opt(ImagePreview),
+    'data' : IDL.Vec(IDL.Nat8),
+    'size' : IDL.Nat64,
+    'file_type' : IDL.Text,
+    'filename' : IDL.Text,
+    'encrypted_metadata' : IDL.Opt(IDL.Vec(IDL.Nat8)),
+  });
+  const Result_3 = IDL.Variant({ 'Ok' : Attachment, 'Err' : ChatError });
   const CreateChannelRequest = IDL.Record({
     'name' : IDL.Text,
     'description' : IDL.Opt(IDL.Text),
   });
+  const MessageExpiry = IDL.Variant({
+    'SevenDays' : IDL.Null,
+    'OneHour' : IDL.Null,
+    'Never' : IDL.Null,
+    'OneDay' : IDL.Null,
+  });
+  const RetentionPolicy = IDL.Variant({
+    'MaxMessages' : IDL.Nat64,
+    'MaxAgeDays' : IDL.Nat32,
+  });
   const Channel = IDL.Record({
     'id' : IDL.Nat64,
+    'password_hash' : IDL.Opt(IDL.Text),
     'members' : IDL.Vec(IDL.Principal),
     'name' : IDL.Text,
+    'message_expiry' : IDL.Opt(MessageExpiry),
+    'retention' : IDL.Opt(RetentionPolicy),
     'description' : IDL.Opt(IDL.Text),
     'last_message_at' : IDL.Opt(IDL.Nat64),
     'created_at' : IDL.Nat64,
     'created_by' : IDL.Principal,
     'message_count' : IDL.Nat64,
     'is_encrypted' : IDL.Bool,
-    'password_hash' : IDL.Opt(IDL.Text),
-  });
-  const ChatError = IDL.Variant({
-    'UserAlreadyExists' : IDL.Null,
-    'MessageTooLarge' : IDL.Null,
-    'InvalidInput' : IDL.Null,
-    'ChannelNotFound' : IDL.Null,
-    'NotFound' : IDL.Null,
-    'NotAuthorized' : IDL.Null,
-    'AttachmentTooLarge' : IDL.Null,
+    'key_epoch' : IDL.Opt(IDL.Nat32),
   });
+  const Result_4 = IDL.Variant({ 'Ok' : Channel, 'Err' : ChatError });
   const MessageType = IDL.Variant({
     'System' : IDL.Null,
     'Text' : IDL.Null,
     'Image' : IDL.Null,
   });
-  const Attachment = IDL.Record({
-    'data' : IDL.Vec(IDL.Nat8),
-    'size' : IDL.Nat64,
-    'file_type' : IDL.Text,
-    'filename' : IDL.Text,
-  });
   const MessageWithAuthor = IDL.Record({
     'id' : IDL.Nat64,
     'content' : IDL.Text,
@@ -45,6 +87,7 @@ export const idlFactory = ({ IDL }) => {
     'message_type' : MessageType,
     'attachments' : IDL.Vec(Attachment),
   });
+  const Result_5 = IDL.Variant({ 'Ok' : IDL.Text, 'Err' : IDL.Text });
   const User = IDL.Record({
     'bio' : IDL.Opt(IDL.Text),
     'encrypted_keys' : IDL.Vec(IDL.Tuple(IDL.Text, IDL.Text)),
@@ -55,7 +98,56 @@ export const idlFactory = ({ IDL }) => {
     'message_count' : IDL.Nat64,
     'joined_at' : IDL.Nat64,
   });
+  const Result_6 = IDL.Variant({ 'Ok' : IDL.Vec(IDL.Nat8), 'Err' : ChatError });
+  const Result_7 = IDL.Variant({ 'Ok' : IDL.Text, 'Err' : ChatError });
+  const UpgradeRecord = IDL.Record({
+    'upgrade_count' : IDL.Nat64,
+    'migration_cursor' : IDL.Opt(IDL.Vec(IDL.Nat8)),
+    'last_upgraded_at' : IDL.Opt(IDL.Nat64),
+    'migration_version' : IDL.Nat32,
+  });
+  const CanisterInfo = IDL.Record({
+    'upgrade' : UpgradeRecord,
+    'cleanup_interval_secs' : IDL.Nat64,
+    'vetkd_key_name' : IDL.Text,
+  });
+  const Result_8 = IDL.Variant({ 'Ok' : CanisterInfo, 'Err' : ChatError });
+  const CertifiedEncryptedMessage = IDL.Record({
+    'certificate' : IDL.Vec(IDL.Nat8),
+    'encoded_message' : IDL.Vec(IDL.Nat8),
+    'witness' : IDL.Vec(IDL.Nat8),
+  });
+  const Result_9 = IDL.Variant({
+    'Ok' : CertifiedEncryptedMessage,
+    'Err' : ChatError,
+  });
   const EncryptedMessage = IDL.Record({
+    'id' : IDL.Nat64,
+    'channel_id' : IDL.Opt(IDL.Nat64),
+    'reply_to' : IDL.Opt(IDL.Nat64),
+    'encrypted_content' : IDL.Text,
+    'ibe_recipient' : IDL.Opt(IDL.Principal),
+    'author' : IDL.Principal,
+    'timestamp' : IDL.Nat64,
+    'message_type' : MessageType,
+    'shared_with' : IDL.Vec(IDL.Principal),
+    'key_epoch' : IDL.Opt(IDL.Nat32),
+    'expires_at' : IDL.Opt(IDL.Nat64),
+    'attachments' : IDL.Vec(Attachment),
+  });
+  const EncryptedMessagePage = IDL.Record({
+    'next_before' : IDL.Opt(IDL.Nat64),
+    'messages' : IDL.Vec(EncryptedMessage),
+  });
+  const Result_10 = IDL.Variant({
+    'Ok' : EncryptedMessagePage,
+    'Err' : ChatError,
+  });
+  const Result_11 = IDL.Variant({
+    'Ok' : IDL.Vec(IDL.Principal),
+    'Err' : ChatError,
+  });
+  const LegacyEncryptedMessage = IDL.Record({
     'id' : IDL.Nat64,
     'channel_id' : IDL.Opt(IDL.Nat64),
     'reply_to' : IDL.Opt(IDL.Nat64),
@@ -72,15 +164,69 @@ export const idlFactory = ({ IDL }) => {
     'total_count' : IDL.Nat64,
     'has_more' : IDL.Bool,
   });
+  const PresenceStatus = IDL.Variant({
+    'Away' : IDL.Null,
+    'Online' : IDL.Null,
+    'Offline' : IDL.Null,
+  });
+  const UserPresence = IDL.Record({
+    'status' : PresenceStatus,
+    'user_principal' : IDL.Principal,
+    'last_seen' : IDL.Nat64,
+  });
+  const StorageUsage = IDL.Record({
+    'used_bytes' : IDL.Nat64,
+    'quota_bytes' : IDL.Nat64,
+  });
+  const Result_12 = IDL.Variant({ 'Ok' : StorageUsage, 'Err' : ChatError });
+  const HttpRequest = IDL.Record({
+    'url' : IDL.Text,
+    'method' : IDL.Text,
+    'body' : IDL.Vec(IDL.Nat8),
+    'headers' : IDL.Vec(IDL.Tuple(IDL.Text, IDL.Text)),
+    'certificate_version' : IDL.Opt(IDL.Nat16),
+  });
+  const StreamingCallbackToken = IDL.Record({
+    'attachment_id' : IDL.Nat64,
+    'signature' : IDL.Text,
+    'expires' : IDL.Nat64,
+    'index' : IDL.Nat32,
+  });
+  const StreamingCallbackHttpResponse = IDL.Record({
+    'token' : IDL.Opt(StreamingCallbackToken),
+    'body' : IDL.Vec(IDL.Nat8),
+  });
+  const StreamingStrategy = IDL.Variant({
+    'Callback' : IDL.Record({
+      'token' : StreamingCallbackToken,
+      'callback' : IDL.Func(
+          [StreamingCallbackToken],
+          [StreamingCallbackHttpResponse],
+          ['query'],
+        ),
+    }),
+  });
+  const HttpResponse = IDL.Record({
+    'body' : IDL.Vec(IDL.Nat8),
+    'headers' : IDL.Vec(IDL.Tuple(IDL.Text, IDL.Text)),
+    'streaming_strategy' : IDL.Opt(StreamingStrategy),
+    'status_code' : IDL.Nat16,
+  });
+  const Result_13 = IDL.Variant({
+    'Ok' : IDL.Vec(IDL.Text),
+    'Err' : ChatError,
+  });
+  const Result_14 = IDL.Variant({ 'Ok' : User, 'Err' : ChatError });
   const CreateMessageRequest = IDL.Record({
     'channel_id' : IDL.Opt(IDL.Nat64),
     'content' : IDL.Text,
     'reply_to' : IDL.Opt(IDL.Nat64),
     'message_type' : MessageType,
-    'attachments' : IDL.Vec(Attachment),
+    'attachment_ids' : IDL.Opt(IDL.Vec(IDL.Nat64)),
   });
   const Message = IDL.Record({
     'id' : IDL.Nat64,
+    'channel_id' : IDL.Opt(IDL.Nat64),
     'content' : IDL.Text,
     'reply_to' : IDL.Opt(IDL.Nat64),
     'author' : IDL.Principal,
@@ -88,21 +234,32 @@ export const idlFactory = ({ IDL }) => {
     'message_type' : MessageType,
     'attachments' : IDL.Vec(Attachment),
   });
+  const Result_15 = IDL.Variant({ 'Ok' : Message, 'Err' : ChatError });
   const UpdateUserRequest = IDL.Record({
     'bio' : IDL.Opt(IDL.Text),
     'username' : IDL.Opt(IDL.Text),
     'avatar_url' : IDL.Opt(IDL.Text),
   });
   return IDL.Service({
-    'cleanup_expired_messages' : IDL.Func([], [IDL.Nat64], []),
-    'create_channel' : IDL.Func(
-        [CreateChannelRequest],
-        [IDL.Variant({ 'Ok' : Channel, 'Err' : ChatError })],
+    'begin_encrypted_upload' : IDL.Func(
+        [BeginEncryptedUploadRequest],
+        [Result],
         [],
       ),
+    'begin_upload' : IDL.Func([BeginUploadRequest], [Result], []),
+    'block_user' : IDL.Func([IDL.Principal], [Result_1], []),
+    'cancel_upload' : IDL.Func([IDL.Nat64], [Result_1], []),
+    'channel_key_verification_key' : IDL.Func(
+        [IDL.Nat64, IDL.Opt(IDL.Nat32)],
+        [Result_2],
+        [],
+      ),
+    'cleanup_expired_messages' : IDL.Func([], [IDL.Nat64], []),
+    'commit_upload' : IDL.Func([IDL.Nat64, IDL.Vec(IDL.Nat8)], [Result_3], []),
+    'create_channel' : IDL.Func([CreateChannelRequest], [Result_4], []),
     'create_encrypted_channel' : IDL.Func(
         [IDL.Text, IDL.Opt(IDL.Text), IDL.Opt(IDL.Text)],
-        [IDL.Variant({ 'Ok' : Channel, 'Err' : ChatError })],
+        [Result_4],
         [],
       ),
     'create_encrypted_message' : IDL.Func(
@@ -111,44 +268,92 @@ export const idlFactory = ({ IDL }) => {
           IDL.Opt(IDL.Nat64),
           IDL.Opt(IDL.Nat64),
           MessageType,
-          IDL.Vec(Attachment),
+          IDL.Opt(IDL.Vec(IDL.Reserved)),
+          IDL.Opt(IDL.Nat64),
+        ],
+        [Result],
+        [],
+      ),
+    'create_ibe_message' : IDL.Func(
+        [
+          IDL.Principal,
+          IDL.Text,
+          IDL.Opt(IDL.Nat64),
+          MessageType,
+          IDL.Opt(IDL.Nat64),
         ],
-        [IDL.Variant({ 'Ok' : IDL.Nat64, 'Err' : ChatError })],
+        [Result],
         [],
       ),
     'decrypt_all_messages_from_channel' : IDL.Func(
-        [IDL.Nat64],
+        [IDL.Nat64, IDL.Opt(IDL.Nat64), IDL.Opt(IDL.Nat64)],
         [IDL.Vec(MessageWithAuthor)],
         [],
       ),
-    'decrypt_encrypted_message' : IDL.Func(
-        [IDL.Nat64],
-        [IDL.Variant({ 'Ok' : IDL.Text, 'Err' : IDL.Text })],
+    'decrypt_encrypted_message' : IDL.Func([IDL.Nat64], [Result_5], []),
+    'delete_channel' : IDL.Func([IDL.Nat64], [Result_1], []),
+    'delete_encrypted_key' : IDL.Func([IDL.Text], [Result_1], []),
+    'delete_encrypted_message' : IDL.Func([IDL.Nat64], [Result_1], []),
+    'encrypted_ibe_decryption_key' : IDL.Func(
+        [IDL.Vec(IDL.Nat8)],
+        [Result_2],
         [],
       ),
-    'delete_encrypted_message' : IDL.Func(
-        [IDL.Nat64],
-        [IDL.Variant({ 'Ok' : IDL.Null, 'Err' : ChatError })],
+    'encrypted_symmetric_key_for_attachment' : IDL.Func(
+        [IDL.Nat64, IDL.Opt(IDL.Vec(IDL.Nat8))],
+        [Result_2],
         [],
       ),
     'encrypted_symmetric_key_for_message' : IDL.Func(
-        [IDL.Nat64],
-        [IDL.Variant({ 'Ok' : IDL.Vec(IDL.Nat8), 'Err' : IDL.Text })],
-        [],
-      ),
-    'fix_general_channel' : IDL.Func(
-        [],
-        [IDL.Variant({ 'Ok' : Channel, 'Err' : ChatError })],
+        [IDL.Nat64, IDL.Opt(IDL.Vec(IDL.Nat8))],
+        [Result_2],
         [],
       ),
+    'fix_general_channel' : IDL.Func([], [Result_4], []),
+    'force_delete_channel' : IDL.Func([IDL.Nat64], [Result_1], []),
+    'forward_attachment' : IDL.Func([IDL.Nat64], [Result_3], []),
     'get_all_channels' : IDL.Func([], [IDL.Vec(Channel)], ['query']),
     'get_all_users' : IDL.Func([], [IDL.Vec(User)], ['query']),
+    'get_attachment' : IDL.Func([IDL.Nat64], [IDL.Opt(Attachment)], ['query']),
+    'get_attachment_chunk' : IDL.Func(
+        [IDL.Nat64, IDL.Nat32],
+        [Result_6],
+        ['query'],
+      ),
+    'get_attachment_url' : IDL.Func([IDL.Nat64], [Result_7], ['query']),
+    'get_blocked_users' : IDL.Func([], [IDL.Vec(IDL.Principal)], ['query']),
+    'get_canister_info' : IDL.Func([], [Result_8], ['query']),
+    'get_certified_encrypted_message' : IDL.Func(
+        [IDL.Nat64],
+        [Result_9],
+        ['query'],
+      ),
     'get_channel' : IDL.Func([IDL.Nat64], [IDL.Opt(Channel)], ['query']),
+    'get_channel_encrypted_message_page' : IDL.Func(
+        [IDL.Nat64, IDL.Opt(IDL.Nat64), IDL.Opt(IDL.Nat64)],
+        [Result_10],
+        ['query'],
+      ),
     'get_current_user' : IDL.Func([], [IDL.Opt(User)], ['query']),
-    'get_encrypted_messages' : IDL.Func([], [IDL.Vec(EncryptedMessage)], []),
+    'get_encrypted_key' : IDL.Func([IDL.Text], [Result_7], ['query']),
+    'get_encrypted_message_page' : IDL.Func(
+        [IDL.Opt(IDL.Nat64), IDL.Opt(IDL.Nat64)],
+        [EncryptedMessagePage],
+        ['query'],
+      ),
+    'get_encrypted_message_shares' : IDL.Func(
+        [IDL.Nat64],
+        [Result_11],
+        ['query'],
+      ),
+    'get_encrypted_messages' : IDL.Func(
+        [],
+        [IDL.Vec(LegacyEncryptedMessage)],
+        [],
+      ),
     'get_encrypted_messages_from_channel' : IDL.Func(
         [IDL.Nat64],
-        [IDL.Vec(EncryptedMessage)],
+        [IDL.Vec(LegacyEncryptedMessage)],
         ['query'],
       ),
     'get_message' : IDL.Func(
@@ -161,42 +366,92 @@ export const idlFactory = ({ IDL }) => {
         [PaginatedMessages],
         ['query'],
       ),
+    'get_muted_users' : IDL.Func([], [IDL.Vec(IDL.Principal)], ['query']),
+    'get_presence' : IDL.Func(
+        [IDL.Vec(IDL.Principal)],
+        [IDL.Vec(UserPresence)],
+        ['query'],
+      ),
     'get_stats' : IDL.Func(
         [],
         [IDL.Vec(IDL.Tuple(IDL.Text, IDL.Nat64))],
         ['query'],
       ),
+    'get_storage_usage' : IDL.Func(
+        [IDL.Opt(IDL.Principal)],
+        [Result_12],
+        ['query'],
+      ),
+    'get_typing_users' : IDL.Func([IDL.Nat64], [Result_11], ['query']),
     'get_user' : IDL.Func([IDL.Principal], [IDL.Opt(User)], ['query']),
-    'join_channel' : IDL.Func(
-        [IDL.Nat64, IDL.Opt(IDL.Text)],
-        [IDL.Variant({ 'Ok' : IDL.Null, 'Err' : ChatError })],
+    'heartbeat' : IDL.Func([], [Result_1], []),
+    'http_request' : IDL.Func([HttpRequest], [HttpResponse], ['query']),
+    'http_request_streaming_callback' : IDL.Func(
+        [StreamingCallbackToken],
+        [StreamingCallbackHttpResponse],
+        ['query'],
+      ),
+    'ibe_encryption_key' : IDL.Func([], [Result_2], []),
+    'join_channel' : IDL.Func([IDL.Nat64, IDL.Opt(IDL.Text)], [Result_1], []),
+    'list_encrypted_keys' : IDL.Func([], [Result_13], ['query']),
+    'mute_user' : IDL.Func([IDL.Principal], [Result_1], []),
+    'put_chunk' : IDL.Func(
+        [IDL.Nat64, IDL.Nat32, IDL.Vec(IDL.Nat8)],
+        [Result_1],
+        [],
+      ),
+    'register_user' : IDL.Func([IDL.Text, IDL.Opt(IDL.Text)], [Result_14], []),
+    'rotate_channel_key' : IDL.Func([IDL.Nat64], [Result_4], []),
+    'send_message' : IDL.Func([CreateMessageRequest], [Result_15], []),
+    'set_attachment_preview' : IDL.Func(
+        [IDL.Nat64, IDL.Opt(IDL.Vec(IDL.Nat8)), IDL.Opt(IDL.Text)],
+        [Result_3],
         [],
       ),
-    'register_user' : IDL.Func(
-        [IDL.Text, IDL.Opt(IDL.Text)],
-        [IDL.Variant({ 'Ok' : User, 'Err' : ChatError })],
+    'set_channel_message_expiry' : IDL.Func(
+        [IDL.Nat64, MessageExpiry],
+        [Result_4],
         [],
       ),
-    'send_message' : IDL.Func(
-        [CreateMessageRequest],
-        [IDL.Variant({ 'Ok' : Message, 'Err' : ChatError })],
+    'set_channel_retention' : IDL.Func(
+        [IDL.Nat64, IDL.Opt(RetentionPolicy)],
+        [Result_4],
+        [],
+      ),
+    'set_default_storage_quota' : IDL.Func([IDL.Nat64], [Result_1], []),
+    'set_typing' : IDL.Func([IDL.Nat64, IDL.Bool], [Result_1], []),
+    'set_user_storage_quota' : IDL.Func(
+        [IDL.Principal, IDL.Opt(IDL.Nat64)],
+        [Result_1],
         [],
       ),
     'share_encrypted_message' : IDL.Func(
         [IDL.Nat64, IDL.Principal],
-        [IDL.Variant({ 'Ok' : IDL.Null, 'Err' : ChatError })],
+        [Result_1],
         [],
       ),
+    'store_encrypted_key' : IDL.Func([IDL.Text, IDL.Text], [Result_1], []),
     'symmetric_key_verification_key_for_encrypted_message' : IDL.Func(
         [],
-        [IDL.Variant({ 'Ok' : IDL.Vec(IDL.Nat8), 'Err' : IDL.Text })],
+        [Result_2],
         [],
       ),
-    'update_user' : IDL.Func(
-        [UpdateUserRequest],
-        [IDL.Variant({ 'Ok' : User, 'Err' : ChatError })],
+    'unblock_user' : IDL.Func([IDL.Principal], [Result_1], []),
+    'unmute_user' : IDL.Func([IDL.Principal], [Result_1], []),
+    'unshare_encrypted_message' : IDL.Func(
+        [IDL.Nat64, IDL.Principal],
+        [Result_1],
         [],
       ),
+    'update_user' : IDL.Func([UpdateUserRequest], [Result_14], []),
+    'upload_avatar' : IDL.Func([IDL.Vec(IDL.Nat8)], [Result_14], []),
+    'user_key_verification_key' : IDL.Func([IDL.Principal], [Result_2], []),
   });
 };
-export const init = ({ IDL }) => { return []; };
+export const init = ({ IDL }) => {
+  const CanisterArgs = IDL.Record({
+    'cleanup_interval_secs' : IDL.Opt(IDL.Nat64),
+    'vetkd_key_name' : IDL.Opt(IDL.Text),
+  });
+  return [IDL.Opt(CanisterArgs)];
+};
\ No newline at end of file